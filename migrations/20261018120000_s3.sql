@@ -0,0 +1,16 @@
+-- Access keys of S3-compatible API
+CREATE TABLE s3_key (
+    id INTEGER PRIMARY KEY,
+    access_key VARCHAR NOT NULL UNIQUE,
+    secret_key VARCHAR NOT NULL,
+    username VARCHAR(32) NOT NULL
+);
+
+-- Multipart uploads in progress, parts are stored in temporary folder
+CREATE TABLE s3_upload (
+    id INTEGER PRIMARY KEY,
+    upload_id VARCHAR NOT NULL UNIQUE,
+    bucket VARCHAR NOT NULL,
+    `key` VARCHAR NOT NULL,
+    username VARCHAR(32) NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Public keys used to login SFTP server
+CREATE TABLE ssh_key (
+    id INTEGER PRIMARY KEY,
+    username VARCHAR(32) NOT NULL,
+    public_key VARCHAR NOT NULL,
+    comment VARCHAR NOT NULL DEFAULT ''
+);
@@ -0,0 +1,2 @@
+-- Role decides which mounts the user can access
+ALTER TABLE user ADD COLUMN `role` VARCHAR(32) NOT NULL DEFAULT 'user';
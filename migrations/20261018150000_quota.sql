@@ -0,0 +1,14 @@
+-- Owner of files written by users, counted in user quota
+CREATE TABLE file_owner (
+    `path` VARCHAR NOT NULL PRIMARY KEY,
+    username VARCHAR(32) NOT NULL,
+    size INTEGER NOT NULL
+);
+
+CREATE INDEX file_owner_username ON file_owner (username);
+
+-- Used bytes of folders with quota
+CREATE TABLE folder_usage (
+    `path` VARCHAR NOT NULL PRIMARY KEY,
+    used INTEGER NOT NULL
+);
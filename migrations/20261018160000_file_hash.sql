@@ -0,0 +1,11 @@
+-- SHA-256 of files, valid while size and modified time are unchanged
+CREATE TABLE file_hash (
+    `path` VARCHAR NOT NULL PRIMARY KEY,
+    sha256 VARCHAR(64) NOT NULL,
+    size INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    -- Last time the content is verified by scrub
+    checked_at INTEGER NOT NULL,
+    -- Hash found by scrub if content changed without modified time
+    mismatch VARCHAR(64)
+);
@@ -0,0 +1,19 @@
+-- EXIF and container metadata of photos, audio and video,
+-- valid while size and modified time are unchanged
+CREATE TABLE media_metadata (
+    `path` VARCHAR NOT NULL PRIMARY KEY,
+    folder VARCHAR NOT NULL,
+    size INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    -- Capture time in unix timestamp, camera local time is taken as UTC
+    taken_at INTEGER,
+    camera VARCHAR,
+    width INTEGER,
+    height INTEGER,
+    -- Seconds
+    duration REAL,
+    codec VARCHAR
+);
+
+CREATE INDEX media_metadata_folder ON media_metadata (folder);
+CREATE INDEX media_metadata_taken_at ON media_metadata (taken_at);
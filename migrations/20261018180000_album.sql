@@ -0,0 +1,18 @@
+-- Virtual collections of files, owned by a user
+CREATE TABLE album (
+    id INTEGER PRIMARY KEY,
+    username VARCHAR(32) NOT NULL,
+    name VARCHAR NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE album_item (
+    album_id INTEGER NOT NULL REFERENCES album (id) ON DELETE CASCADE,
+    `path` VARCHAR NOT NULL,
+    -- Order of items in album
+    position INTEGER NOT NULL,
+    PRIMARY KEY (album_id, `path`)
+);
+
+-- Share of an album instead of a path
+ALTER TABLE share ADD COLUMN album_id INTEGER REFERENCES album (id) ON DELETE CASCADE;
@@ -0,0 +1,4 @@
+-- Unix timestamp after which the link stops working, NULL never expires
+ALTER TABLE share ADD COLUMN expires_at INTEGER;
+
+CREATE INDEX share_expires_at ON share (expires_at);
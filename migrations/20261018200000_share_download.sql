@@ -0,0 +1,4 @@
+-- Download limit and access counters of share links
+ALTER TABLE share ADD COLUMN max_downloads INTEGER;
+ALTER TABLE share ADD COLUMN download_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE share ADD COLUMN last_access_at INTEGER;
@@ -0,0 +1,27 @@
+-- Paths can be shared several times, shares are managed by id.
+-- SQLite can't drop the UNIQUE constraint of `path`, so the table is copied
+CREATE TABLE share_new (
+    id INTEGER PRIMARY KEY,
+    `path` VARCHAR,
+    `url` VARCHAR UNIQUE,
+    `password` VARCHAR,
+    album_id INTEGER REFERENCES album (id) ON DELETE CASCADE,
+    expires_at INTEGER,
+    max_downloads INTEGER,
+    download_count INTEGER NOT NULL DEFAULT 0,
+    last_access_at INTEGER,
+    -- Shown to the owner, e.g. name of the customer
+    label VARCHAR
+);
+
+INSERT INTO share_new (id, `path`, `url`, `password`, album_id, expires_at, max_downloads,
+    download_count, last_access_at)
+SELECT id, `path`, `url`, `password`, album_id, expires_at, max_downloads,
+    download_count, last_access_at FROM share;
+
+DROP TABLE share;
+
+ALTER TABLE share_new RENAME TO share;
+
+CREATE INDEX share_path ON share (`path`);
+CREATE INDEX share_expires_at ON share (expires_at);
@@ -0,0 +1,6 @@
+-- What visitors can do, "download" (list and download) or "drop" (upload only)
+ALTER TABLE share ADD COLUMN permission VARCHAR NOT NULL DEFAULT 'download';
+-- Limits of files uploaded to "drop" shares, NULL is unlimited
+ALTER TABLE share ADD COLUMN max_file_size INTEGER;
+ALTER TABLE share ADD COLUMN max_total_size INTEGER;
+ALTER TABLE share ADD COLUMN uploaded_size INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- Device and inode of shared file, so moves outside of file-station can be followed
+ALTER TABLE share ADD COLUMN file_id VARCHAR;
+-- Time when shared file was found removed or replaced, broken shares can't be opened
+ALTER TABLE share ADD COLUMN broken_at INTEGER;
+-- Paths are compared with paths of file events
+UPDATE share SET path = trim(path, '/') WHERE path IS NOT NULL;
@@ -0,0 +1,19 @@
+-- Accesses of shares, kept for FS_SHARE_LOG_DAYS
+CREATE TABLE share_access (
+    id INTEGER PRIMARY KEY,
+    share_id INTEGER NOT NULL REFERENCES share (id) ON DELETE CASCADE,
+    accessed_at INTEGER NOT NULL,
+    ip VARCHAR NOT NULL,
+    user_agent VARCHAR,
+    file_path VARCHAR NOT NULL,
+    -- File content is requested, not information or folder listing
+    download BOOLEAN NOT NULL,
+    status INTEGER NOT NULL,
+    -- Bytes of response body actually sent
+    bytes INTEGER NOT NULL,
+    -- Response is successful and sent completely
+    success BOOLEAN NOT NULL
+);
+
+CREATE INDEX share_access_share ON share_access (share_id, accessed_at);
+CREATE INDEX share_access_accessed_at ON share_access (accessed_at);
@@ -0,0 +1,4 @@
+-- Device and inode of file in album, so moves outside of file-station can be followed
+ALTER TABLE album_item ADD COLUMN file_id VARCHAR;
+-- Time when file in album was found removed or replaced, broken items aren't shown
+ALTER TABLE album_item ADD COLUMN broken_at INTEGER;
@@ -2,26 +2,93 @@ use std::collections::HashMap;
 use std::env::vars;
 use std::fs::{canonicalize, create_dir};
 use std::path::PathBuf;
+use std::thread::available_parallelism;
+
+/// Named storage root, shown as a top-level folder
+#[derive(Debug, Clone)]
+pub struct Mount {
+    pub name: String,
+    pub path: PathBuf,
+    pub read_only: bool,
+    /// Roles of users who can access it, empty means all users
+    pub roles: Vec<String>,
+}
+
+/// Max bytes stored in a folder, including sub folders
+#[derive(Debug, Clone)]
+pub struct FolderQuota {
+    /// Relative to the root of storage
+    pub path: PathBuf,
+    pub size: u64,
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     /// FS_FOLDER
     pub folder_path: PathBuf,
+    /// FS_MOUNTS, replaces FS_FOLDER as the root if set
+    pub mounts: Vec<Mount>,
     /// FS_DATABASE
     pub database_path: String,
     /// FS_LISTEN
     pub listen_addr: String,
     /// FS_REGISTER
     pub can_register: bool,
+    /// FS_WATCH
+    pub watch_folder: bool,
+    /// FS_S3_LISTEN, S3-compatible API is disabled if not set
+    pub s3_listen: Option<String>,
+    /// FS_SFTP_LISTEN, SFTP server is disabled if not set
+    pub sftp_listen: Option<String>,
+    /// FS_SFTP_HOST_KEY, generated if not exist
+    pub sftp_host_key: PathBuf,
+    /// FS_USER_QUOTA, max bytes written by each user
+    pub user_quota: Option<u64>,
+    /// FS_FOLDER_QUOTAS
+    pub folder_quotas: Vec<FolderQuota>,
+    /// FS_QUOTA_SCAN, seconds between rescans of used bytes
+    pub quota_scan_interval: u64,
+    /// FS_DU_INTERVAL, seconds between refreshes of disk usage
+    pub du_interval: u64,
+    /// FS_SCRUB_INTERVAL, seconds between verifying checksums of all files, 0 to disable
+    pub scrub_interval: u64,
+    /// FS_THUMBNAILS, cache folder of thumbnails
+    pub thumbnail_path: PathBuf,
+    /// FS_THUMBNAIL_WORKERS, max thumbnails generated at the same time
+    pub thumbnail_workers: usize,
+    /// FS_RENDER_MAX_SIZE, max bytes of files rendered to HTML
+    pub render_max_size: u64,
+    /// FS_RENDER_CACHE, max bytes of rendered HTML kept in memory
+    pub render_cache_size: u64,
+    /// FS_SHARE_LOG_DAYS, days share accesses are kept, 0 keeps them forever
+    pub share_log_days: u64,
+    /// FS_SHARE_EXPIRED_KEEP, seconds expired shares are kept before they're removed
+    pub share_expired_keep: u64,
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
             folder_path: "./files".into(),
+            mounts: vec![],
             database_path: "./database.db".into(),
             listen_addr: "127.0.0.1:5000".into(),
             can_register: true,
+            watch_folder: true,
+            s3_listen: None,
+            sftp_listen: None,
+            sftp_host_key: "./sftp_host_key".into(),
+            user_quota: None,
+            folder_quotas: vec![],
+            quota_scan_interval: 3600,
+            du_interval: 600,
+            scrub_interval: 7 * 24 * 3600,
+            thumbnail_path: "./thumbnails".into(),
+            thumbnail_workers: 2,
+            render_max_size: 1 << 20,
+            render_cache_size: 16 << 20,
+            share_log_days: 90,
+            share_expired_keep: 7 * 24 * 3600,
         }
     }
 
@@ -35,6 +102,10 @@ impl Config {
             create_dir(&folder_path).unwrap();
         }
         folder_path = canonicalize(folder_path).unwrap();
+        let mounts = match e.get("FS_MOUNTS") {
+            Some(m) => parse_mounts(m),
+            None => vec![],
+        };
         let database_path = e
             .get("FS_DATABASE")
             .unwrap_or(&"./database.db".into())
@@ -44,12 +115,172 @@ impl Config {
             .unwrap_or(&"127.0.0.1:5000".into())
             .clone();
         let can_register = e.get("FS_REGISTER").unwrap_or(&"TRUE".into()) == "TRUE";
+        let watch_folder = e.get("FS_WATCH").unwrap_or(&"TRUE".into()) == "TRUE";
+        let s3_listen = e.get("FS_S3_LISTEN").cloned();
+        let sftp_listen = e.get("FS_SFTP_LISTEN").cloned();
+        let sftp_host_key = PathBuf::from(
+            e.get("FS_SFTP_HOST_KEY")
+                .unwrap_or(&"./sftp_host_key".into()),
+        );
+        let user_quota = e
+            .get("FS_USER_QUOTA")
+            .map(|q| parse_size(q).expect("FS_USER_QUOTA should be size like 10G"));
+        let folder_quotas = match e.get("FS_FOLDER_QUOTAS") {
+            Some(q) => parse_folder_quotas(q),
+            None => vec![],
+        };
+        let quota_scan_interval = e
+            .get("FS_QUOTA_SCAN")
+            .unwrap_or(&"3600".into())
+            .parse()
+            .unwrap();
+        let du_interval = e
+            .get("FS_DU_INTERVAL")
+            .unwrap_or(&"600".into())
+            .parse()
+            .unwrap();
+        let scrub_interval = e
+            .get("FS_SCRUB_INTERVAL")
+            .unwrap_or(&"604800".into())
+            .parse()
+            .unwrap();
+        let thumbnail_path =
+            PathBuf::from(e.get("FS_THUMBNAILS").unwrap_or(&"./thumbnails".into()));
+        let thumbnail_workers = match e.get("FS_THUMBNAIL_WORKERS") {
+            Some(w) => w.parse().unwrap(),
+            None => available_parallelism().map_or(2, |n| n.get()),
+        };
+        let render_max_size = parse_size(e.get("FS_RENDER_MAX_SIZE").unwrap_or(&"1M".into()))
+            .expect("FS_RENDER_MAX_SIZE should be size like 1M");
+        let render_cache_size = parse_size(e.get("FS_RENDER_CACHE").unwrap_or(&"16M".into()))
+            .expect("FS_RENDER_CACHE should be size like 16M");
+        let share_log_days = e
+            .get("FS_SHARE_LOG_DAYS")
+            .unwrap_or(&"90".into())
+            .parse()
+            .unwrap();
+        let share_expired_keep = e
+            .get("FS_SHARE_EXPIRED_KEEP")
+            .unwrap_or(&"604800".into())
+            .parse()
+            .unwrap();
 
         Config {
             folder_path,
+            mounts,
             database_path,
             listen_addr,
             can_register,
+            watch_folder,
+            s3_listen,
+            sftp_listen,
+            sftp_host_key,
+            user_quota,
+            folder_quotas,
+            quota_scan_interval,
+            du_interval,
+            scrub_interval,
+            thumbnail_path,
+            thumbnail_workers,
+            render_max_size,
+            render_cache_size,
+            share_log_days,
+            share_expired_keep,
+        }
+    }
+}
+
+/// Parse mounts like "media=/data/media;archive=/mnt/archive:ro:admin,staff".
+/// Options after path are "ro" and allowed roles separated by ','
+fn parse_mounts(s: &str) -> Vec<Mount> {
+    let mut mounts: Vec<Mount> = vec![];
+    for m in s.split(';').filter(|m| !m.trim().is_empty()) {
+        let (name, spec) = m.split_once('=').expect("FS_MOUNTS should be name=path");
+        let name = name.trim().to_string();
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            panic!("invalid mount name {:?}", name);
+        }
+        if mounts.iter().any(|m| m.name == name) {
+            panic!("duplicate mount name {:?}", name);
         }
+        let mut options = spec.split(':');
+        let path = canonicalize(options.next().unwrap_or_default().trim()).unwrap();
+        let mut mount = Mount {
+            name,
+            path,
+            read_only: false,
+            roles: vec![],
+        };
+        for option in options.map(|o| o.trim()) {
+            match option {
+                "ro" => mount.read_only = true,
+                "rw" | "" => (),
+                roles => mount
+                    .roles
+                    .extend(roles.split(',').map(|r| r.trim().to_string())),
+            }
+        }
+        mounts.push(mount);
+    }
+    mounts
+}
+
+/// Parse size like "1024", "500M" or "10G"
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    num.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Parse folder quotas like "projects=100G;media/photos=50G"
+fn parse_folder_quotas(s: &str) -> Vec<FolderQuota> {
+    s.split(';')
+        .filter(|q| !q.trim().is_empty())
+        .map(|q| {
+            let (path, size) = q
+                .split_once('=')
+                .expect("FS_FOLDER_QUOTAS should be path=size");
+            FolderQuota {
+                path: PathBuf::from(path.trim().trim_matches('/')),
+                size: parse_size(size).expect("FS_FOLDER_QUOTAS should be path=size"),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_mounts() {
+        let mounts = parse_mounts("a=src;b=.:ro:admin, staff;");
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].name, "a");
+        assert!(!mounts[0].read_only && mounts[0].roles.is_empty());
+        assert!(mounts[1].read_only);
+        assert_eq!(mounts[1].roles, vec!["admin", "staff"]);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("500M"), Some(500 << 20));
+        assert_eq!(parse_size("10gb"), Some(10 << 30));
+        assert_eq!(parse_size("10X"), None);
+        let quotas = parse_folder_quotas("/projects/=1K;media/photos=2K");
+        assert_eq!(quotas[0].path, PathBuf::from("projects"));
+        assert_eq!(quotas[1].size, 2048);
     }
 }
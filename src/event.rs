@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension,
+    },
+    response::Response,
+};
+use lazy_static::lazy_static;
+use notify::{
+    event::{ModifyKind, RenameMode},
+    EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    file::{
+        check_path, relative_path,
+        storage::{is_server_temp, Storage, StorageBackend},
+    },
+    user::Claim,
+    CONFIG,
+};
+
+/// Same path changed within this window is only reported once,
+/// so handler events and watcher events don't show up twice
+const DEDUP_WINDOW: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    static ref SENDER: broadcast::Sender<FileEvent> = broadcast::channel(256).0;
+    static ref RECENT: Mutex<HashMap<PathBuf, Instant>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EventType {
+    Create,
+    Update,
+    Delete,
+    Rename,
+}
+
+/// Change of a file or folder, paths are relative to the storage folder
+#[derive(Clone, Debug, Serialize)]
+pub struct FileEvent {
+    #[serde(rename = "type")]
+    type_: EventType,
+    path: String,
+    /// New path of renamed file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+}
+
+/// Message sent by client to change its subscribed folders
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Subscription {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+impl FileEvent {
+    /// `path` is relative to the root of storage
+    pub fn new(type_: EventType, path: &Path) -> Option<FileEvent> {
+        Some(FileEvent {
+            type_,
+            path: path.to_str()?.to_string(),
+            to: None,
+        })
+    }
+
+    pub fn rename(from: &Path, to: &Path) -> Option<FileEvent> {
+        Some(FileEvent {
+            type_: EventType::Rename,
+            path: from.to_str()?.to_string(),
+            to: Some(to.to_str()?.to_string()),
+        })
+    }
+
+    pub fn event_type(&self) -> EventType {
+        self.type_
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// New path of renamed file
+    pub fn to(&self) -> Option<&str> {
+        self.to.as_deref()
+    }
+
+    /// Changed path, and new path if renamed
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        [Some(&self.path), self.to.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|p| p.as_str())
+    }
+
+    /// Check if the event happened in (or to) `folder`
+    fn is_in(&self, folder: &str) -> bool {
+        let paths = [Some(&self.path), self.to.as_ref()];
+        paths.into_iter().flatten().any(|p| {
+            let parent = Path::new(p).parent().unwrap_or(Path::new(""));
+            p == folder || parent == Path::new(folder)
+        })
+    }
+}
+
+/// Send event to all subscribers
+pub fn publish(event: Option<FileEvent>) {
+    let event = match event {
+        Some(e) => e,
+        None => return,
+    };
+    {
+        let mut recent = RECENT.lock().unwrap();
+        let now = Instant::now();
+        recent.retain(|_, t| now.duration_since(*t) < DEDUP_WINDOW);
+        for p in [Some(&event.path), event.to.as_ref()].into_iter().flatten() {
+            recent.insert(PathBuf::from(p), now);
+        }
+    }
+    // Error only means there is no subscriber
+    let _ = SENDER.send(event);
+}
+
+/// Receive events published from now on
+pub fn subscribe() -> broadcast::Receiver<FileEvent> {
+    SENDER.subscribe()
+}
+
+/// Check if `path` was published recently
+fn is_recent(path: &str) -> bool {
+    match RECENT.lock().unwrap().get(Path::new(path)) {
+        Some(t) => t.elapsed() < DEDUP_WINDOW,
+        None => false,
+    }
+}
+
+/// Upgrade to websocket, then push events of subscribed folders
+pub async fn events(
+    ws: WebSocketUpgrade,
+    Extension(storage): Extension<Storage>,
+    _: Claim,
+) -> Response {
+    ws.on_upgrade(|socket| handle_socket(socket, storage))
+}
+
+/// Subscribed folder normalized like "a/b", so it's unsubscribed with any spelling
+fn folder_key(storage: &dyn StorageBackend, folder: &str) -> Option<String> {
+    let path = check_path(storage, folder).ok()?;
+    Some(path.to_str()?.to_string())
+}
+
+async fn handle_socket(mut socket: WebSocket, storage: Storage) {
+    let mut receiver = SENDER.subscribe();
+    let mut folders: HashSet<String> = HashSet::new();
+    loop {
+        tokio::select! {
+            msg = socket.recv() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(t))) => t,
+                    Some(Ok(_)) => continue,
+                    _ => break,
+                };
+                match serde_json::from_str(&text) {
+                    // Folders the user can't see are ignored
+                    Ok(Subscription::Subscribe(f)) => {
+                        if let Some(f) = folder_key(storage.as_ref(), &f) {
+                            folders.insert(f);
+                        }
+                    }
+                    Ok(Subscription::Unsubscribe(f)) => {
+                        if let Some(f) = folder_key(storage.as_ref(), &f) {
+                            folders.remove(&f);
+                        }
+                    }
+                    Err(_) => continue,
+                }
+            }
+            event = receiver.recv() => {
+                let event = match event {
+                    Ok(e) => e,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if !folders.iter().any(|f| event.is_in(f)) {
+                    continue;
+                }
+                let text = match serde_json::to_string(&event) {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Watch changes made outside of file-station, e.g. copy by shell.
+/// The watcher stops when returned value is dropped
+pub fn watch_folder() -> notify::Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(|res: notify::Result<notify::Event>| {
+        let event = match res {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+        let paths: Option<Vec<PathBuf>> = event.paths.iter().map(|p| relative_path(p)).collect();
+        let paths = match paths {
+            Some(p) => p,
+            None => return,
+        };
+        let event = match (event.kind, paths.as_slice()) {
+            // Written by the server beside the file, then renamed to it
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) if is_server_temp(from) => {
+                FileEvent::new(EventType::Update, to)
+            }
+            _ if paths.iter().any(|p| is_server_temp(p)) => return,
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+                FileEvent::rename(from, to)
+            }
+            (EventKind::Modify(ModifyKind::Name(RenameMode::From)), [p]) => {
+                FileEvent::new(EventType::Delete, p)
+            }
+            (EventKind::Modify(ModifyKind::Name(RenameMode::To)), [p]) => {
+                FileEvent::new(EventType::Create, p)
+            }
+            (EventKind::Create(_), [p]) => FileEvent::new(EventType::Create, p),
+            (EventKind::Modify(_), [p]) => FileEvent::new(EventType::Update, p),
+            (EventKind::Remove(_), [p]) => FileEvent::new(EventType::Delete, p),
+            _ => return,
+        };
+        // Skip changes that handlers have already published
+        if let Some(e) = &event {
+            let mut paths = [Some(&e.path), e.to.as_ref()].into_iter().flatten();
+            if paths.all(|p| is_recent(p)) {
+                return;
+            }
+        }
+        // Not recorded as recent, so a rename is still seen after its halves
+        // are seen as delete and create
+        if let Some(e) = event {
+            let _ = SENDER.send(e);
+        }
+    })?;
+    if CONFIG.mounts.is_empty() {
+        watcher.watch(&CONFIG.folder_path, RecursiveMode::Recursive)?;
+    }
+    for mount in &CONFIG.mounts {
+        watcher.watch(&mount.path, RecursiveMode::Recursive)?;
+    }
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_in_folder() {
+        let event = FileEvent {
+            type_: EventType::Rename,
+            path: "a/b.txt".into(),
+            to: Some("c/b.txt".into()),
+        };
+        assert!(event.is_in("a"));
+        assert!(event.is_in("c"));
+        assert!(!event.is_in(""));
+        assert!(!event.is_in("a/b"));
+        let event = FileEvent {
+            type_: EventType::Delete,
+            path: "a".into(),
+            to: None,
+        };
+        assert!(event.is_in(""));
+        assert!(event.is_in("a"));
+        let storage = crate::file::storage::MemoryStorage::new();
+        for folder in ["a/b", "/a/b/", "./a/./b"] {
+            assert_eq!(folder_key(&storage, folder).as_deref(), Some("a/b"));
+        }
+    }
+}
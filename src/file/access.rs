@@ -0,0 +1,340 @@
+use std::mem::take;
+use std::net::IpAddr;
+
+use axum::{
+    body::{boxed, HttpBody, StreamBody},
+    extract::{Extension, Query},
+    http::{header, HeaderMap},
+    response::Response,
+    Json,
+};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::runtime::Handle;
+
+use crate::{
+    file::FileError,
+    user::{get_unix_timestamp, Claim},
+    CONFIG,
+};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+/// Longer user agents are cut
+const MAX_USER_AGENT: usize = 256;
+
+#[derive(Deserialize)]
+pub struct ShareAccessArgs {
+    id: i64,
+    /// Max records of history
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessRecord {
+    accessed_at: i64,
+    ip: String,
+    user_agent: Option<String>,
+    file_path: String,
+    download: bool,
+    status: i64,
+    bytes: i64,
+    success: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAccess {
+    file_path: String,
+    count: i64,
+    /// Successful and complete downloads
+    downloads: i64,
+    bytes: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareAccess {
+    total: i64,
+    successful: i64,
+    failed: i64,
+    downloads: i64,
+    unique_ips: i64,
+    bytes: i64,
+    first_access_at: Option<i64>,
+    last_access_at: Option<i64>,
+    /// Most accessed first
+    files: Vec<FileAccess>,
+    /// Newest first
+    history: Vec<AccessRecord>,
+}
+
+/// Access of share, recorded after response is sent or dropped
+pub struct Access {
+    db: SqlitePool,
+    url: String,
+    accessed_at: i64,
+    ip: String,
+    user_agent: Option<String>,
+    file_path: String,
+    download: bool,
+    status: u16,
+    bytes: u64,
+    /// `Content-Length` of response, server stops reading body after it
+    length: Option<u64>,
+    /// Body failed to be read
+    failed: bool,
+    recorded: bool,
+}
+
+impl Access {
+    pub fn new(
+        db: &SqlitePool,
+        url: &str,
+        ip: IpAddr,
+        headers: &HeaderMap,
+        file_path: &str,
+        download: bool,
+    ) -> Access {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|u| u.chars().take(MAX_USER_AGENT).collect());
+        Access {
+            db: db.clone(),
+            url: url.to_string(),
+            accessed_at: get_unix_timestamp() as i64,
+            ip: ip.to_string(),
+            user_agent,
+            file_path: file_path.to_string(),
+            download,
+            status: 0,
+            bytes: 0,
+            length: None,
+            failed: false,
+            recorded: false,
+        }
+    }
+
+    /// Record result of access. Bytes of response are counted while it's sent,
+    /// so downloads stopped by visitors aren't successful
+    pub async fn record(
+        mut self,
+        result: Result<Response, FileError>,
+    ) -> Result<Response, FileError> {
+        let res = match result {
+            Ok(res) => res,
+            Err(e) => {
+                self.status = e.status().as_u16();
+                self.save(true).await;
+                return Err(e);
+            }
+        };
+        self.status = res.status().as_u16();
+        self.length = res
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse().ok());
+        let (parts, body) = res.into_parts();
+        let body = stream::unfold((body, self), |(mut body, mut access)| async move {
+            match body.data().await {
+                Some(Ok(data)) => {
+                    access.bytes += data.len() as u64;
+                    Some((Ok(data), (body, access)))
+                }
+                Some(Err(e)) => {
+                    access.failed = true;
+                    Some((Err(e), (body, access)))
+                }
+                None => {
+                    access.save(true).await;
+                    None
+                }
+            }
+        });
+        Ok(Response::from_parts(parts, boxed(StreamBody::new(body))))
+    }
+
+    async fn save(mut self, complete: bool) {
+        self.recorded = true;
+        let complete = complete || self.length.is_some_and(|l| self.bytes >= l);
+        let success = complete && !self.failed && self.status < 400;
+        let (status, bytes) = (self.status as i64, self.bytes as i64);
+        // Accesses of unknown urls aren't recorded
+        let result = sqlx::query!(
+            "INSERT INTO share_access (share_id, accessed_at, ip, user_agent, file_path,
+            download, status, bytes, success)
+            SELECT id, ?, ?, ?, ?, ?, ?, ?, ? FROM share WHERE url = ?",
+            self.accessed_at,
+            self.ip,
+            self.user_agent,
+            self.file_path,
+            self.download,
+            status,
+            bytes,
+            success,
+            self.url
+        )
+        .execute(&self.db)
+        .await;
+        if let Err(e) = result {
+            tracing::warn!("failed to record share access: {}", e);
+        }
+    }
+}
+
+impl Drop for Access {
+    /// Response is dropped before it's sent completely, e.g. visitor disconnects
+    fn drop(&mut self) {
+        if self.recorded {
+            return;
+        }
+        self.recorded = true;
+        let access = Access {
+            db: self.db.clone(),
+            url: take(&mut self.url),
+            accessed_at: self.accessed_at,
+            ip: take(&mut self.ip),
+            user_agent: self.user_agent.take(),
+            file_path: take(&mut self.file_path),
+            download: self.download,
+            status: self.status,
+            bytes: self.bytes,
+            length: self.length,
+            failed: self.failed,
+            // Not spawned again if it's dropped by runtime shutdown
+            recorded: true,
+        };
+        if let Ok(handle) = Handle::try_current() {
+            handle.spawn(access.save(false));
+        }
+    }
+}
+
+/// Get access history of share `?id=` with counts of all recorded accesses
+pub async fn get_share_access(
+    Query(args): Query<ShareAccessArgs>,
+    Extension(db): Extension<SqlitePool>,
+    _: Claim,
+) -> Result<Json<ShareAccess>, FileError> {
+    sqlx::query!("SELECT id FROM share WHERE id = ?", args.id)
+        .fetch_one(&db)
+        .await?;
+    let limit = args.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let summary = sqlx::query!(
+        r#"SELECT COUNT(*) AS "total!: i64", COALESCE(SUM(success), 0) AS "successful!: i64",
+        COALESCE(SUM(download AND success), 0) AS "downloads!: i64",
+        COUNT(DISTINCT ip) AS "unique_ips!: i64", COALESCE(SUM(bytes), 0) AS "bytes!: i64",
+        MIN(accessed_at) AS "first: i64", MAX(accessed_at) AS "last: i64"
+        FROM share_access WHERE share_id = ?"#,
+        args.id
+    )
+    .fetch_one(&db)
+    .await?;
+    let files = sqlx::query_as!(
+        FileAccess,
+        r#"SELECT file_path, COUNT(*) AS "count!: i64",
+        COALESCE(SUM(download AND success), 0) AS "downloads!: i64",
+        COALESCE(SUM(bytes), 0) AS "bytes!: i64"
+        FROM share_access WHERE share_id = ? GROUP BY file_path ORDER BY 2 DESC, file_path"#,
+        args.id
+    )
+    .fetch_all(&db)
+    .await?;
+    let history = sqlx::query_as!(
+        AccessRecord,
+        r#"SELECT accessed_at, ip, user_agent, file_path, download AS "download: bool",
+        status, bytes, success AS "success: bool"
+        FROM share_access WHERE share_id = ? ORDER BY accessed_at DESC, id DESC LIMIT ?"#,
+        args.id,
+        limit
+    )
+    .fetch_all(&db)
+    .await?;
+    Ok(Json(ShareAccess {
+        total: summary.total,
+        successful: summary.successful,
+        failed: summary.total - summary.successful,
+        downloads: summary.downloads,
+        unique_ips: summary.unique_ips,
+        bytes: summary.bytes,
+        first_access_at: summary.first,
+        last_access_at: summary.last,
+        files,
+        history,
+    }))
+}
+
+/// Remove accesses older than `FS_SHARE_LOG_DAYS`, return removed count
+pub async fn purge_access(db: &SqlitePool) -> Result<u64, FileError> {
+    if CONFIG.share_log_days == 0 {
+        return Ok(0);
+    }
+    let before = get_unix_timestamp() as i64 - (CONFIG.share_log_days * 24 * 60 * 60) as i64;
+    let result = sqlx::query!("DELETE FROM share_access WHERE accessed_at < ?", before)
+        .execute(db)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::file::{
+        fixture::{claim, pool},
+        share::purge_expired,
+    };
+
+    #[tokio::test]
+    async fn test_share_access() {
+        let db = pool().await;
+        sqlx::query!("INSERT INTO share (id, path, url) VALUES (1, 'a', 'u')")
+            .execute(&db)
+            .await
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, "curl/8".parse().unwrap());
+        let access = |file_path| {
+            let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+            Access::new(&db, "u", ip, &headers, file_path, true)
+        };
+        let res = access("b.txt")
+            .record(Ok("hello".into_response()))
+            .await
+            .unwrap();
+        let mut body = res.into_body();
+        while body.data().await.is_some() {}
+        let res = access("c.txt").record(Err(FileError::Forbidden)).await;
+        assert!(matches!(res, Err(FileError::Forbidden)));
+        let args = ShareAccessArgs { id: 1, limit: None };
+        let Json(stats) = get_share_access(Query(args), Extension(db.clone()), claim())
+            .await
+            .unwrap();
+        assert_eq!((stats.total, stats.successful, stats.failed), (2, 1, 1));
+        assert_eq!((stats.downloads, stats.bytes, stats.unique_ips), (1, 5, 1));
+        assert_eq!(stats.files[0].file_path, "b.txt");
+        assert_eq!(stats.history[0].status, 403);
+        assert_eq!(stats.history[1].user_agent.as_deref(), Some("curl/8"));
+
+        // Expired share is kept with its accesses until they're too old
+        let expired = get_unix_timestamp() as i64 - CONFIG.share_expired_keep as i64;
+        sqlx::query!("UPDATE share SET expires_at = ?", expired)
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(purge_expired(&db).await.unwrap(), 0);
+        let old = get_unix_timestamp() as i64 - (CONFIG.share_log_days * 24 * 60 * 60) as i64 - 1;
+        sqlx::query!("UPDATE share_access SET accessed_at = ?", old)
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(purge_access(&db).await.unwrap(), 2);
+        assert_eq!(purge_expired(&db).await.unwrap(), 1);
+    }
+}
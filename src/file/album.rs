@@ -0,0 +1,306 @@
+use std::path::{Path, PathBuf};
+
+use axum::{
+    extract::{Extension, Query},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+
+use crate::{
+    file::{
+        check_path,
+        gallery::GalleryItem,
+        storage::{Storage, StorageBackend},
+        tracking::file_identity,
+        File, FileError,
+    },
+    user::{get_unix_timestamp, Claim},
+};
+
+#[derive(Deserialize)]
+pub struct AlbumArgs {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct AddAlbumArgs {
+    name: String,
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateAlbumArgs {
+    /// New name
+    name: Option<String>,
+    /// Files appended to album
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumInfo {
+    id: i64,
+    name: String,
+    created_at: i64,
+    count: i64,
+    /// Path of the first item
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cover: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Album {
+    id: i64,
+    name: String,
+    created_at: i64,
+    /// Removed files are skipped
+    items: Vec<GalleryItem>,
+}
+
+/// Get albums of current user
+pub async fn get_albums(
+    Extension(db): Extension<SqlitePool>,
+    claim: Claim,
+) -> Result<Json<Vec<AlbumInfo>>, FileError> {
+    let rows = sqlx::query!(
+        r#"SELECT id AS "id!", name, created_at,
+        (SELECT COUNT(*) FROM album_item WHERE album_id = album.id AND broken_at IS NULL)
+        AS "count!: i64",
+        (SELECT path FROM album_item WHERE album_id = album.id AND broken_at IS NULL
+        ORDER BY position LIMIT 1) AS "cover?: String"
+        FROM album WHERE username = ? ORDER BY created_at DESC, id DESC"#,
+        claim.username
+    )
+    .fetch_all(&db)
+    .await?;
+    Ok(Json(
+        rows.into_iter()
+            .map(|r| AlbumInfo {
+                id: r.id,
+                name: r.name,
+                created_at: r.created_at,
+                count: r.count,
+                cover: r.cover,
+            })
+            .collect(),
+    ))
+}
+
+/// Create album with files, return id
+pub async fn add_album(
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    claim: Claim,
+    Json(args): Json<AddAlbumArgs>,
+) -> Result<Json<Value>, FileError> {
+    let name = args.name.trim();
+    if name.is_empty() {
+        return Err(FileError::ContentError);
+    }
+    let paths = check_files(storage.as_ref(), &args.paths).await?;
+    let created_at = get_unix_timestamp() as i64;
+    let id = sqlx::query!(
+        "INSERT INTO album (username, name, created_at) VALUES (?, ?, ?)",
+        claim.username,
+        name,
+        created_at
+    )
+    .execute(&db)
+    .await?
+    .last_insert_rowid();
+    add_items(&db, storage.as_ref(), id, &paths).await?;
+    Ok(Json(json!({ "id": id })))
+}
+
+/// Get files of album
+pub async fn get_album(
+    Query(args): Query<AlbumArgs>,
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    claim: Claim,
+) -> Result<Json<Album>, FileError> {
+    let album = sqlx::query!(
+        "SELECT name, created_at FROM album WHERE id = ? AND username = ?",
+        args.id,
+        claim.username
+    )
+    .fetch_one(&db)
+    .await?;
+    let rows = sqlx::query!(
+        r#"SELECT i.path, m.width, m.height, COALESCE(m.taken_at, m.modified) AS "time: i64"
+        FROM album_item i LEFT JOIN media_metadata m ON m.path = i.path
+        WHERE i.album_id = ? AND i.broken_at IS NULL ORDER BY i.position"#,
+        args.id
+    )
+    .fetch_all(&db)
+    .await?;
+    let mut items = vec![];
+    for row in rows {
+        let path = Path::new(&row.path);
+        if !storage.contains(path) {
+            continue;
+        }
+        // Files without metadata use modified time
+        let time = match row.time {
+            Some(time) => time,
+            None => match storage.stat(path).await {
+                Ok(stat) => stat.modified as i64,
+                Err(_) => continue,
+            },
+        };
+        items.push(GalleryItem::new(row.path, time, row.width, row.height));
+    }
+    Ok(Json(Album {
+        id: args.id,
+        name: album.name,
+        created_at: album.created_at,
+        items,
+    }))
+}
+
+/// Rename album, add or remove files
+pub async fn update_album(
+    Query(args): Query<AlbumArgs>,
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    claim: Claim,
+    Json(update): Json<UpdateAlbumArgs>,
+) -> Result<StatusCode, FileError> {
+    check_album(&db, args.id, &claim.username).await?;
+    if let Some(name) = update.name {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(FileError::ContentError);
+        }
+        sqlx::query!("UPDATE album SET name = ? WHERE id = ?", name, args.id)
+            .execute(&db)
+            .await?;
+    }
+    for path in &update.remove {
+        let path = check_path(storage.as_ref(), path)?;
+        let p = path.to_str().ok_or(FileError::PathError)?;
+        sqlx::query!(
+            "DELETE FROM album_item WHERE album_id = ? AND path = ?",
+            args.id,
+            p
+        )
+        .execute(&db)
+        .await?;
+    }
+    let paths = check_files(storage.as_ref(), &update.add).await?;
+    add_items(&db, storage.as_ref(), args.id, &paths).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Delete album, its shares are deleted too
+pub async fn delete_album(
+    Query(args): Query<AlbumArgs>,
+    Extension(db): Extension<SqlitePool>,
+    claim: Claim,
+) -> Result<StatusCode, FileError> {
+    sqlx::query!(
+        "DELETE FROM album WHERE id = ? AND username = ?",
+        args.id,
+        claim.username
+    )
+    .execute(&db)
+    .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Check album of user exists
+pub async fn check_album(db: &SqlitePool, id: i64, username: &str) -> Result<(), FileError> {
+    sqlx::query!(
+        "SELECT id FROM album WHERE id = ? AND username = ?",
+        id,
+        username
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(())
+}
+
+/// Paths of files in album
+pub async fn album_paths(db: &SqlitePool, id: i64) -> Result<Vec<PathBuf>, FileError> {
+    let rows = sqlx::query!(
+        "SELECT path FROM album_item WHERE album_id = ? AND broken_at IS NULL ORDER BY position",
+        id
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(|r| PathBuf::from(r.path)).collect())
+}
+
+/// Information of files in album with their absolute paths, removed files are skipped
+pub async fn album_files(storage: &dyn StorageBackend, paths: &[PathBuf]) -> Vec<File> {
+    let mut files = vec![];
+    for path in paths.iter().filter(|p| storage.contains(p)) {
+        let file = match File::new(storage, path).await {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        if let Some(file) = file.absolute_path(path.parent().unwrap_or(Path::new(""))) {
+            files.push(file);
+        }
+    }
+    files
+}
+
+/// Album only contains files, not folders
+async fn check_files(
+    storage: &dyn StorageBackend,
+    paths: &[String],
+) -> Result<Vec<PathBuf>, FileError> {
+    let mut checked = vec![];
+    for path in paths {
+        let path = check_path(storage, path)?;
+        if !storage.exists(&path).await || storage.is_dir(&path).await {
+            return Err(FileError::PathError);
+        }
+        checked.push(path);
+    }
+    Ok(checked)
+}
+
+/// Append files to album, files already in it are skipped.
+/// Broken items are linked to the files added at their paths
+async fn add_items(
+    db: &SqlitePool,
+    storage: &dyn StorageBackend,
+    id: i64,
+    paths: &[PathBuf],
+) -> Result<(), FileError> {
+    let next = sqlx::query!(
+        r#"SELECT COALESCE(MAX(position) + 1, 0) AS "next!: i64" FROM album_item
+        WHERE album_id = ?"#,
+        id
+    )
+    .fetch_one(db)
+    .await?
+    .next;
+    for (i, path) in paths.iter().enumerate() {
+        let p = path.to_str().ok_or(FileError::PathError)?;
+        let position = next + i as i64;
+        let file_id = file_identity(storage, path);
+        sqlx::query!(
+            "INSERT INTO album_item (album_id, path, position, file_id) VALUES (?, ?, ?, ?)
+            ON CONFLICT(album_id, path) DO UPDATE SET file_id = excluded.file_id, broken_at = NULL
+            WHERE broken_at IS NOT NULL",
+            id,
+            p,
+            position,
+            file_id
+        )
+        .execute(db)
+        .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,509 @@
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use axum::{async_trait, extract::Extension, Json};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use crate::{
+    file::{
+        storage::{Entry, Reader, Stat, Storage, StorageBackend},
+        FileError,
+    },
+    user::{get_unix_timestamp, Claim},
+    CONFIG,
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Mismatch {
+    path: String,
+    /// Hash recorded when file is written
+    sha256: String,
+    /// Hash of current content, empty if file can't be read
+    actual: String,
+    checked_at: i64,
+}
+
+/// Hash data while it is read
+struct HashReader<'a> {
+    inner: &'a mut (dyn AsyncRead + Send + Unpin),
+    hasher: Sha256,
+}
+
+impl AsyncRead for HashReader<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut *self.inner).poll_read(cx, buf))?;
+        self.hasher.update(&buf.filled()[start..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Storage that records SHA-256 of files written through it
+pub struct HashStorage {
+    inner: Storage,
+    pool: SqlitePool,
+}
+
+impl HashStorage {
+    pub fn new(inner: Storage, pool: SqlitePool) -> HashStorage {
+        HashStorage { inner, pool }
+    }
+
+    async fn record(&self, path: &Path, sha256: &str) -> io::Result<()> {
+        let stat = self.inner.stat(path).await?;
+        record(&self.pool, path, sha256, &stat)
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for HashStorage {
+    fn contains(&self, path: &Path) -> bool {
+        self.inner.contains(path)
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        self.inner.local_path(path)
+    }
+
+    fn writable(&self, path: &Path) -> bool {
+        self.inner.writable(path)
+    }
+
+    fn for_role(&self, role: &str) -> Option<Storage> {
+        let inner = self.inner.for_role(role)?;
+        Some(Arc::new(HashStorage::new(inner, self.pool.clone())))
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        self.inner.list(path).await
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<Stat> {
+        self.inner.stat(path).await
+    }
+
+    async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<Reader> {
+        self.inner.read(path, range).await
+    }
+
+    async fn write(
+        &self,
+        path: &Path,
+        data: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<u64> {
+        let mut reader = HashReader {
+            inner: data,
+            hasher: Sha256::new(),
+        };
+        let size = self.inner.write(path, &mut reader).await?;
+        let sha256 = hex::encode(reader.hasher.finalize());
+        self.record(path, &sha256).await?;
+        Ok(size)
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to).await?;
+        // Hashes of files inside renamed folder are moved too
+        let (from, to) = (path_str(from)?, path_str(to)?);
+        let start = from.chars().count() as i64 + 1;
+        let prefix = format!("{}/", from);
+        let prefix_len = prefix.chars().count() as i64;
+        sqlx::query!(
+            "DELETE FROM file_hash WHERE path = ? OR substr(path, 1, ?) = ?",
+            to,
+            prefix_len,
+            prefix
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(io::Error::other)?;
+        sqlx::query!(
+            "UPDATE file_hash SET path = ? || substr(path, ?)
+            WHERE path = ? OR substr(path, 1, ?) = ?",
+            to,
+            start,
+            from,
+            prefix_len,
+            prefix
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let sha256 = self.sha256(from).await;
+        let size = self.inner.copy(from, to).await?;
+        match sha256 {
+            Some(sha256) => self.record(to, &sha256).await?,
+            None => forget(&self.pool, to).await.map_err(io::Error::other)?,
+        }
+        Ok(size)
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        self.inner.delete(path).await?;
+        forget(&self.pool, path).await.map_err(io::Error::other)
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn max_size(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.inner.max_size(path).await
+    }
+
+    /// File written at random offset is hashed again
+    async fn written(&self, path: &Path, old_size: u64) -> io::Result<()> {
+        self.inner.written(path, old_size).await?;
+        let sha256 = hash_file(self.inner.as_ref(), path).await?;
+        self.record(path, &sha256).await
+    }
+
+    async fn sha256(&self, path: &Path) -> Option<String> {
+        let stat = self.inner.stat(path).await.ok()?;
+        let p = path.to_str()?;
+        let row = sqlx::query!(
+            "SELECT sha256, size, modified FROM file_hash WHERE path = ?",
+            p
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .ok()??;
+        // File is changed outside since hashed
+        if row.size as u64 != stat.size || row.modified as u64 != stat.modified {
+            return None;
+        }
+        Some(row.sha256)
+    }
+}
+
+/// Get files whose content changed while size and modified time are unchanged
+pub async fn get_mismatches(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    _: Claim,
+) -> Result<Json<Vec<Mismatch>>, FileError> {
+    let rows = sqlx::query!(
+        r#"SELECT path, sha256, mismatch AS "mismatch!", checked_at FROM file_hash
+        WHERE mismatch IS NOT NULL"#
+    )
+    .fetch_all(&pool)
+    .await?;
+    let mismatches = rows
+        .into_iter()
+        // Files in hidden mounts are skipped
+        .filter(|r| storage.contains(Path::new(&r.path)))
+        .map(|r| Mismatch {
+            path: r.path,
+            sha256: r.sha256,
+            actual: r.mismatch,
+            checked_at: r.checked_at,
+        })
+        .collect();
+    Ok(Json(mismatches))
+}
+
+/// Hash all files in storage.
+/// Unchanged files are verified, changed or new files are recorded
+pub async fn scrub(pool: &SqlitePool, storage: &dyn StorageBackend) -> Result<(), FileError> {
+    let checked_at = get_unix_timestamp() as i64;
+    let mut folders = vec![PathBuf::new()];
+    while let Some(folder) = folders.pop() {
+        let entries = match storage.list(&folder).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            // Files in the folder can't be verified
+            Err(e) => {
+                tracing::warn!("failed to scrub folder {}: {}", folder.display(), e);
+                let prefix = match folder.to_str() {
+                    Some("") => String::new(),
+                    _ => format!("{}/", path_str(&folder)?),
+                };
+                let prefix_len = prefix.chars().count() as i64;
+                sqlx::query!(
+                    "UPDATE file_hash SET checked_at = ?, mismatch = ''
+                    WHERE substr(path, 1, ?) = ?",
+                    checked_at,
+                    prefix_len,
+                    prefix
+                )
+                .execute(pool)
+                .await?;
+                continue;
+            }
+        };
+        for entry in entries {
+            let path = folder.join(&entry.name);
+            if entry.stat.is_dir {
+                folders.push(path);
+                continue;
+            }
+            let p = path_str(&path)?;
+            let sha256 = match hash_file(storage, &path).await {
+                Ok(sha256) => sha256,
+                // File removed during scrub
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                // Recorded file can't be read, e.g. disk is broken
+                Err(e) => {
+                    tracing::warn!("failed to scrub file {}: {}", p, e);
+                    sqlx::query!(
+                        "UPDATE file_hash SET checked_at = ?, mismatch = '' WHERE path = ?",
+                        checked_at,
+                        p
+                    )
+                    .execute(pool)
+                    .await?;
+                    continue;
+                }
+            };
+            let row = sqlx::query!(
+                "SELECT sha256, size, modified FROM file_hash WHERE path = ?",
+                p
+            )
+            .fetch_optional(pool)
+            .await?;
+            match row {
+                Some(r)
+                    if r.size as u64 == entry.stat.size
+                        && r.modified as u64 == entry.stat.modified =>
+                {
+                    let mismatch = (r.sha256 != sha256).then_some(sha256);
+                    if mismatch.is_some() {
+                        tracing::warn!("checksum mismatch: {}", p);
+                    }
+                    sqlx::query!(
+                        "UPDATE file_hash SET checked_at = ?, mismatch = ? WHERE path = ?",
+                        checked_at,
+                        mismatch,
+                        p
+                    )
+                    .execute(pool)
+                    .await?;
+                }
+                _ => record(pool, &path, &sha256, &entry.stat).await?,
+            }
+        }
+    }
+    // Files not found in this scrub are removed if they don't exist anymore
+    let unchecked = sqlx::query!(
+        "SELECT path FROM file_hash WHERE checked_at < ?",
+        checked_at
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in unchecked {
+        match storage.stat(Path::new(&row.path)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Ok(s) if s.is_dir => (),
+            _ => continue,
+        }
+        sqlx::query!("DELETE FROM file_hash WHERE path = ?", row.path)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Scrub every `FS_SCRUB_INTERVAL` seconds, the first one starts after an interval
+pub async fn scrub_loop(pool: SqlitePool, storage: Storage) {
+    let mut interval = tokio::time::interval(Duration::from_secs(CONFIG.scrub_interval.max(1)));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = scrub(&pool, storage.as_ref()).await {
+            tracing::warn!("failed to scrub files: {}", e);
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of file
+pub async fn hash_file(storage: &dyn StorageBackend, path: &Path) -> io::Result<String> {
+    let mut reader = storage.read(path, None).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Value of `Digest` header, e.g. "sha-256=base64"
+pub fn digest_header(sha256: &str) -> Option<String> {
+    let bytes = hex::decode(sha256).ok()?;
+    Some(format!("sha-256={}", base64::encode(bytes)))
+}
+
+async fn record(
+    pool: &SqlitePool,
+    path: &Path,
+    sha256: &str,
+    stat: &Stat,
+) -> Result<(), sqlx::Error> {
+    let p = path.to_str().unwrap_or_default();
+    let (size, modified) = (stat.size as i64, stat.modified as i64);
+    let checked_at = get_unix_timestamp() as i64;
+    sqlx::query!(
+        "INSERT INTO file_hash (path, sha256, size, modified, checked_at) VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT(path) DO UPDATE SET sha256 = excluded.sha256, size = excluded.size,
+        modified = excluded.modified, checked_at = excluded.checked_at, mismatch = NULL",
+        p,
+        sha256,
+        size,
+        modified,
+        checked_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn forget(pool: &SqlitePool, path: &Path) -> Result<(), sqlx::Error> {
+    let p = path.to_str().unwrap_or_default();
+    sqlx::query!("DELETE FROM file_hash WHERE path = ?", p)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+fn path_str(path: &Path) -> io::Result<&str> {
+    path.to_str().ok_or(io::ErrorKind::InvalidInput.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::{fixture::pool, storage::MemoryStorage};
+
+    #[tokio::test]
+    async fn test_hash_storage() {
+        let pool = pool().await;
+        let inner: Storage = Arc::new(MemoryStorage::new());
+        let storage = HashStorage::new(inner.clone(), pool.clone());
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        storage
+            .write(Path::new("a"), &mut &b"hello"[..])
+            .await
+            .unwrap();
+        assert_eq!(storage.sha256(Path::new("a")).await.unwrap(), hello);
+        storage.copy(Path::new("a"), Path::new("b")).await.unwrap();
+        storage
+            .rename(Path::new("b"), Path::new("c"))
+            .await
+            .unwrap();
+        assert_eq!(storage.sha256(Path::new("c")).await.unwrap(), hello);
+        assert!(storage.sha256(Path::new("b")).await.is_none());
+        assert_eq!(
+            digest_header(hello).unwrap(),
+            "sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+        // Recorded hash differs from content, like the file is corrupted
+        sqlx::query!("UPDATE file_hash SET sha256 = '' WHERE path = 'c'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        scrub(&pool, inner.as_ref()).await.unwrap();
+        let mismatches = sqlx::query!("SELECT path FROM file_hash WHERE mismatch IS NOT NULL")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].path, "c");
+
+        // Files which can't be read are reported, not forgotten
+        storage.mkdir(Path::new("d")).await.unwrap();
+        storage
+            .write(Path::new("d/e"), &mut &b"hello"[..])
+            .await
+            .unwrap();
+        let broken = Broken(inner.clone());
+        scrub(&pool, &broken).await.unwrap();
+        let mismatches = sqlx::query!(
+            r#"SELECT path, mismatch AS "mismatch!" FROM file_hash
+            WHERE mismatch IS NOT NULL ORDER BY path"#
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let mismatches: Vec<_> = mismatches
+            .iter()
+            .map(|r| (&*r.path, &*r.mismatch))
+            .collect();
+        assert_eq!(mismatches, [("a", ""), ("c", ""), ("d/e", "")]);
+        // Only removed files are forgotten
+        inner.delete(Path::new("a")).await.unwrap();
+        scrub(&pool, inner.as_ref()).await.unwrap();
+        assert!(storage.sha256(Path::new("a")).await.is_none());
+        assert_eq!(storage.sha256(Path::new("d/e")).await.unwrap(), hello);
+    }
+
+    /// Storage of which no file can be read, and folder "d" can't be listed
+    struct Broken(Storage);
+
+    #[async_trait]
+    impl StorageBackend for Broken {
+        fn contains(&self, path: &Path) -> bool {
+            self.0.contains(path)
+        }
+
+        async fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+            match path == Path::new("d") {
+                true => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+                false => self.0.list(path).await,
+            }
+        }
+
+        async fn stat(&self, path: &Path) -> io::Result<Stat> {
+            self.0.stat(path).await
+        }
+
+        async fn read(&self, _path: &Path, _range: Option<Range<u64>>) -> io::Result<Reader> {
+            Err(io::Error::other("input/output error"))
+        }
+
+        async fn write(
+            &self,
+            path: &Path,
+            data: &mut (dyn AsyncRead + Send + Unpin),
+        ) -> io::Result<u64> {
+            self.0.write(path, data).await
+        }
+
+        async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.0.rename(from, to).await
+        }
+
+        async fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+            self.0.copy(from, to).await
+        }
+
+        async fn delete(&self, path: &Path) -> io::Result<()> {
+            self.0.delete(path).await
+        }
+
+        async fn mkdir(&self, path: &Path) -> io::Result<()> {
+            self.0.mkdir(path).await
+        }
+    }
+}
@@ -0,0 +1,780 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{ConnectInfo, Extension, FromRequestParts, TypedHeader},
+    headers::{authorization::Basic, Authorization},
+    http::{header, request::Parts, HeaderMap, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::TryStreamExt;
+use lazy_static::lazy_static;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::{
+    escape::{escape, unescape},
+    events::Event,
+    name::{Namespace, ResolveResult},
+    NsReader, Reader,
+};
+use sqlx::SqlitePool;
+use tokio_util::io::StreamReader;
+
+use crate::{
+    event::{publish, EventType, FileEvent},
+    file::{
+        check_path, create_path,
+        file::{file_etag, file_response},
+        quota::QuotaExceeded,
+        rename_path,
+        storage::{Stat, Storage, StorageBackend},
+        storage_for_user, write_path, CheckedPath, File, FileError,
+    },
+    user::verify_login,
+};
+
+/// Where the WebDAV service is nested
+pub const DAV_PREFIX: &str = "/dav";
+/// Characters escaped in href, keep '/' to separate path
+const HREF_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+/// Max and default lock timeout in seconds
+const LOCK_TIMEOUT: u64 = 60 * 60;
+/// Max size of XML request body
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+lazy_static! {
+    /// Active locks, keyed by lock token
+    static ref LOCKS: Mutex<HashMap<String, Lock>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone)]
+struct Lock {
+    path: PathBuf,
+    shared: bool,
+    /// `Depth: infinity`, lock all members of collection
+    infinite: bool,
+    owner: Option<String>,
+    timeout: u64,
+    expire: Instant,
+}
+
+impl Lock {
+    /// Check if `path` is protected by this lock
+    fn covers(&self, path: &Path) -> bool {
+        self.path == path || (self.infinite && path.starts_with(&self.path))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DavError {
+    #[error("Io Error")]
+    IoError(#[from] io::Error),
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Status")]
+    Status(StatusCode),
+}
+
+impl IntoResponse for DavError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            DavError::IoError(e) if QuotaExceeded::remaining(&e).is_some() => {
+                StatusCode::INSUFFICIENT_STORAGE
+            }
+            DavError::IoError(e) => match e.kind() {
+                io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                io::ErrorKind::AlreadyExists => StatusCode::METHOD_NOT_ALLOWED,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            DavError::Unauthorized => {
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, "Basic realm=\"file-station\"")],
+                )
+                    .into_response()
+            }
+            DavError::Status(s) => s,
+        };
+        status.into_response()
+    }
+}
+
+impl From<FileError> for DavError {
+    fn from(e: FileError) -> Self {
+        match e {
+            FileError::IoError(e) => DavError::IoError(e),
+            FileError::PathError => DavError::Status(StatusCode::NOT_FOUND),
+            FileError::QuotaExceeded(_) => DavError::Status(StatusCode::INSUFFICIENT_STORAGE),
+            _ => DavError::Status(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+/// Entry of WebDAV requests, dispatch by method
+pub async fn dav(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+) -> Result<Response, DavError> {
+    let (mut parts, body) = req.into_parts();
+    let (username, role) = authenticate(&pool, addr.ip(), &mut parts).await?;
+    // Paths are checked with the mounts visible to the user
+    let storage = storage_for_user(&storage, &pool, &username, &role);
+    parts.extensions.insert(storage.clone());
+    let CheckedPath(path) = CheckedPath::from_request_parts(&mut parts, &())
+        .await
+        .map_err(|_| DavError::Status(StatusCode::FORBIDDEN))?;
+    let (storage, headers) = (storage.as_ref(), &parts.headers);
+    match parts.method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => propfind(storage, &path, headers).await,
+        "PROPPATCH" => proppatch(storage, &path, body).await,
+        "GET" | "HEAD" => get(storage, &path, headers).await,
+        "PUT" => put(storage, &path, headers, body).await,
+        "MKCOL" => mkcol(storage, &path, headers, body).await,
+        "DELETE" => delete(storage, &path, headers).await,
+        "COPY" => copy_or_move(storage, &path, headers, false).await,
+        "MOVE" => copy_or_move(storage, &path, headers, true).await,
+        "LOCK" => lock(storage, &path, headers, body).await,
+        "UNLOCK" => unlock(&path, headers),
+        _ => Err(DavError::Status(StatusCode::METHOD_NOT_ALLOWED)),
+    }
+}
+
+/// Check Basic auth with user table, return username and role of the user
+async fn authenticate(
+    pool: &SqlitePool,
+    ip: IpAddr,
+    parts: &mut Parts,
+) -> Result<(String, String), DavError> {
+    let TypedHeader(Authorization(basic)) =
+        TypedHeader::<Authorization<Basic>>::from_request_parts(parts, &())
+            .await
+            .map_err(|_| DavError::Unauthorized)?;
+    let role = verify_login(pool, ip, basic.username(), basic.password())
+        .await
+        .map_err(|_| DavError::Unauthorized)?;
+    Ok((basic.username().to_string(), role))
+}
+
+fn options() -> Response {
+    (
+        [
+            ("DAV", "1, 2"),
+            ("MS-Author-Via", "DAV"),
+            (
+                "Allow",
+                "OPTIONS, PROPFIND, PROPPATCH, GET, HEAD, PUT, MKCOL, DELETE, COPY, MOVE, LOCK, UNLOCK",
+            ),
+        ],
+        StatusCode::OK,
+    )
+        .into_response()
+}
+
+async fn propfind(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+) -> Result<Response, DavError> {
+    let file = File::new(storage, path)
+        .await
+        .map_err(|_| DavError::Status(StatusCode::NOT_FOUND))?;
+    let mut responses = prop_response(path, &file);
+    // "infinity" is treated as "1", clients walk the tree by themselves
+    if file.type_ == "folder" && get_header(headers, "Depth") != Some("0") {
+        let files = File::read_dir(storage, path)
+            .await
+            .map_err(|_| DavError::Status(StatusCode::NOT_FOUND))?;
+        for f in files {
+            responses.push_str(&prop_response(&path.join(&f.name), &f));
+        }
+    }
+    Ok(multistatus(responses))
+}
+
+/// Build `<D:response>` with all properties of `file`, ETag is the same as of download
+fn prop_response(path: &Path, file: &File) -> String {
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(file.last_modified_time);
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname><D:getlastmodified>{}</D:getlastmodified>",
+        escape(file.name.as_str()),
+        httpdate::fmt_http_date(modified),
+    );
+    let is_dir = file.type_ == "folder";
+    if is_dir {
+        props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let mime = mime_guess::from_path(path).first_or_octet_stream();
+        let stat = Stat {
+            is_dir,
+            size: file.size,
+            modified: file.last_modified_time,
+        };
+        props.push_str(&format!(
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+            <D:getcontenttype>{}</D:getcontenttype><D:getetag>{}</D:getetag>",
+            file.size,
+            escape(mime.as_ref()),
+            escape(file_etag(file.sha256.as_deref(), &stat).as_str())
+        ));
+    }
+    props.push_str(
+        "<D:supportedlock>\
+        <D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+        <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+        </D:supportedlock>",
+    );
+    props.push_str(&lock_discovery(path));
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+        <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        href(path, is_dir),
+        props
+    )
+}
+
+/// Dead properties are not stored, so every property update is forbidden
+async fn proppatch(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    body: Body,
+) -> Result<Response, DavError> {
+    let stat = storage
+        .stat(path)
+        .await
+        .map_err(|_| DavError::Status(StatusCode::NOT_FOUND))?;
+    let body = read_body(body).await?;
+    let props: String = parse_proppatch(&body)?
+        .iter()
+        .map(|(ns, name)| format!("<x:{} xmlns:x=\"{}\"/>", name, escape(ns.as_str())))
+        .collect();
+    Ok(multistatus(format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+        <D:status>HTTP/1.1 403 Forbidden</D:status></D:propstat></D:response>",
+        href(path, stat.is_dir),
+        props
+    )))
+}
+
+async fn get(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+) -> Result<Response, DavError> {
+    if storage.is_dir(path).await {
+        return Err(DavError::Status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    Ok(file_response(storage, path, headers).await?)
+}
+
+async fn put(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, DavError> {
+    if storage.is_dir(path).await {
+        return Err(DavError::Status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    check_parent(storage, path).await?;
+    check_lock(path, headers, false)?;
+    let existed = storage.exists(path).await;
+    let mut data = StreamReader::new(TryStreamExt::map_err(body, io::Error::other));
+    write_path(storage, path, &mut data).await?;
+    if existed {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok(StatusCode::CREATED.into_response())
+    }
+}
+
+async fn mkcol(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, DavError> {
+    if !read_body(body).await?.is_empty() {
+        return Err(DavError::Status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    if storage.exists(path).await {
+        return Err(DavError::Status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    check_parent(storage, path).await?;
+    check_lock(path, headers, false)?;
+    create_path(storage, path).await?;
+    Ok(StatusCode::CREATED.into_response())
+}
+
+async fn delete(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+) -> Result<Response, DavError> {
+    if is_root(path) {
+        return Err(DavError::Status(StatusCode::FORBIDDEN));
+    }
+    if !storage.exists(path).await {
+        return Err(DavError::Status(StatusCode::NOT_FOUND));
+    }
+    check_lock(path, headers, true)?;
+    remove_all(storage, path).await?;
+    remove_locks(path);
+    publish(FileEvent::new(EventType::Delete, path));
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn copy_or_move(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+    is_move: bool,
+) -> Result<Response, DavError> {
+    let dest = destination(storage, headers)?;
+    if is_root(path) || is_root(&dest) || dest.starts_with(path) {
+        return Err(DavError::Status(StatusCode::FORBIDDEN));
+    }
+    if !storage.exists(path).await {
+        return Err(DavError::Status(StatusCode::NOT_FOUND));
+    }
+    check_parent(storage, &dest).await?;
+    if is_move {
+        check_lock(path, headers, true)?;
+    }
+    check_lock(&dest, headers, true)?;
+    let existed = storage.exists(&dest).await;
+    if existed {
+        if get_header(headers, "Overwrite") == Some("F") {
+            return Err(DavError::Status(StatusCode::PRECONDITION_FAILED));
+        }
+        remove_all(storage, &dest).await?;
+        remove_locks(&dest);
+    }
+    if is_move {
+        rename_path(storage, path, &dest).await?;
+        remove_locks(path);
+    } else {
+        let infinite = get_header(headers, "Depth") != Some("0");
+        copy_all(storage, path, &dest, infinite).await?;
+        publish(FileEvent::new(EventType::Create, &dest));
+    }
+    match existed {
+        true => Ok(StatusCode::NO_CONTENT.into_response()),
+        false => Ok(StatusCode::CREATED.into_response()),
+    }
+}
+
+async fn lock(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, DavError> {
+    let body = read_body(body).await?;
+    let timeout = match get_header(headers, "Timeout").and_then(|t| t.strip_prefix("Second-")) {
+        Some(t) => t.parse().unwrap_or(LOCK_TIMEOUT).min(LOCK_TIMEOUT),
+        None => LOCK_TIMEOUT,
+    };
+    // Empty body means refreshing the lock in `If` header
+    if body.is_empty() {
+        return refresh_lock(path, headers, timeout);
+    }
+    let (shared, owner) = parse_lockinfo(&body)?;
+    let infinite = get_header(headers, "Depth") != Some("0");
+    {
+        let mut locks = LOCKS.lock().unwrap();
+        locks.retain(|_, l| l.expire > Instant::now());
+        let conflict = locks.values().any(|l| {
+            (l.covers(path) || (infinite && l.path.starts_with(path))) && !(shared && l.shared)
+        });
+        if conflict {
+            return Err(DavError::Status(StatusCode::LOCKED));
+        }
+    }
+    // Lock unmapped URL creates an empty file
+    let mut status = StatusCode::OK;
+    if !storage.exists(path).await {
+        check_parent(storage, path).await?;
+        write_path(storage, path, &mut tokio::io::empty()).await?;
+        status = StatusCode::CREATED;
+    }
+    let lock = Lock {
+        path: path.to_path_buf(),
+        shared,
+        infinite,
+        owner,
+        timeout,
+        expire: Instant::now() + Duration::from_secs(timeout),
+    };
+    let token = format!(
+        "opaquelocktoken:{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        rand::random::<u32>(),
+        rand::random::<u16>(),
+        rand::random::<u16>(),
+        rand::random::<u16>(),
+        rand::random::<u64>() & 0xffff_ffff_ffff
+    );
+    let body = format!(
+        "<D:lockdiscovery>{}</D:lockdiscovery>",
+        active_lock(&token, &lock)
+    );
+    LOCKS.lock().unwrap().insert(token.clone(), lock);
+    let mut response = prop_body(status, body);
+    let lock_token = format!("<{}>", token).parse().unwrap();
+    response.headers_mut().insert("Lock-Token", lock_token);
+    Ok(response)
+}
+
+/// Extend timeout of the lock in `If` header
+fn refresh_lock(path: &Path, headers: &HeaderMap, timeout: u64) -> Result<Response, DavError> {
+    let mut locks = LOCKS.lock().unwrap();
+    locks.retain(|_, l| l.expire > Instant::now());
+    let if_header = get_header(headers, "If").unwrap_or("");
+    let (token, lock) = locks
+        .iter_mut()
+        .find(|(t, l)| if_header.contains(t.as_str()) && l.covers(path))
+        .ok_or(DavError::Status(StatusCode::PRECONDITION_FAILED))?;
+    lock.timeout = timeout;
+    lock.expire = Instant::now() + Duration::from_secs(timeout);
+    let body = format!(
+        "<D:lockdiscovery>{}</D:lockdiscovery>",
+        active_lock(token, lock)
+    );
+    Ok(prop_body(StatusCode::OK, body))
+}
+
+fn unlock(path: &Path, headers: &HeaderMap) -> Result<Response, DavError> {
+    let token = get_header(headers, "Lock-Token")
+        .map(|t| t.trim_matches(|c| c == '<' || c == '>'))
+        .ok_or(DavError::Status(StatusCode::BAD_REQUEST))?;
+    let mut locks = LOCKS.lock().unwrap();
+    match locks.get(token) {
+        Some(l) if l.covers(path) => {
+            locks.remove(token);
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        _ => Err(DavError::Status(StatusCode::CONFLICT)),
+    }
+}
+
+/// Refuse to modify `path` if it is locked by others.
+/// With `recursive`, locks of the members are also checked
+fn check_lock(path: &Path, headers: &HeaderMap, recursive: bool) -> Result<(), DavError> {
+    let if_header = get_header(headers, "If").unwrap_or("");
+    let mut locks = LOCKS.lock().unwrap();
+    locks.retain(|_, l| l.expire > Instant::now());
+    let locked = locks.iter().any(|(token, l)| {
+        (l.covers(path) || (recursive && l.path.starts_with(path)))
+            && !if_header.contains(token.as_str())
+    });
+    match locked {
+        true => Err(DavError::Status(StatusCode::LOCKED)),
+        false => Ok(()),
+    }
+}
+
+/// Drop locks of `path` and its members after it is gone
+fn remove_locks(path: &Path) {
+    LOCKS
+        .lock()
+        .unwrap()
+        .retain(|_, l| !l.path.starts_with(path));
+}
+
+/// Active locks covering `path`
+fn lock_discovery(path: &Path) -> String {
+    let locks = LOCKS.lock().unwrap();
+    let active: String = locks
+        .iter()
+        .filter(|(_, l)| l.covers(path) && l.expire > Instant::now())
+        .map(|(t, l)| active_lock(t, l))
+        .collect();
+    format!("<D:lockdiscovery>{}</D:lockdiscovery>", active)
+}
+
+fn active_lock(token: &str, lock: &Lock) -> String {
+    format!(
+        "<D:activelock><D:locktype><D:write/></D:locktype>\
+        <D:lockscope><D:{}/></D:lockscope><D:depth>{}</D:depth>{}\
+        <D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken>\
+        <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        if lock.shared { "shared" } else { "exclusive" },
+        if lock.infinite { "infinity" } else { "0" },
+        match &lock.owner {
+            Some(o) => format!("<D:owner>{}</D:owner>", escape(o.as_str())),
+            None => String::new(),
+        },
+        lock.timeout,
+        token,
+        href(&lock.path, false)
+    )
+}
+
+/// Get lock scope and owner from `<D:lockinfo>`
+fn parse_lockinfo(body: &str) -> Result<(bool, Option<String>), DavError> {
+    let mut reader = Reader::from_str(body);
+    let (mut shared, mut in_owner) = (false, false);
+    // Escaped text of owner, entity references are kept until the end
+    let mut owner: Option<String> = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) if e.local_name().as_ref() == "owner" => in_owner = true,
+            Ok(Event::End(e)) if e.local_name().as_ref() == "owner" => in_owner = false,
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == "shared" => {
+                shared = true
+            }
+            Ok(Event::Text(t)) if in_owner => {
+                owner
+                    .get_or_insert_with(String::new)
+                    .push_str(&t.into_inner());
+            }
+            Ok(Event::GeneralRef(r)) if in_owner => {
+                let text = format!("&{};", r.into_inner());
+                owner.get_or_insert_with(String::new).push_str(&text);
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(_) => return Err(DavError::Status(StatusCode::BAD_REQUEST)),
+        }
+    }
+    let owner = match owner {
+        Some(o) => Some(
+            unescape(o.trim())
+                .map_err(|_| DavError::Status(StatusCode::BAD_REQUEST))?
+                .to_string(),
+        ),
+        None => None,
+    };
+    Ok((shared, owner))
+}
+
+/// Get namespace and name of the properties in `<D:propertyupdate>`
+fn parse_proppatch(body: &str) -> Result<Vec<(String, String)>, DavError> {
+    let mut reader = NsReader::from_str(body);
+    let mut props = vec![];
+    let mut depth = 0;
+    let mut prop_depth = None;
+    loop {
+        let (ns, event) = reader
+            .read_resolved_event()
+            .map_err(|_| DavError::Status(StatusCode::BAD_REQUEST))?;
+        let ns = match ns {
+            ResolveResult::Bound(Namespace(n)) => n.to_string(),
+            _ => String::new(),
+        };
+        match event {
+            Event::Start(e) => {
+                depth += 1;
+                if prop_depth.is_some() && prop_depth == Some(depth - 1) {
+                    props.push((ns, e.local_name().as_ref().to_string()));
+                } else if e.local_name().as_ref() == "prop" {
+                    prop_depth = Some(depth);
+                }
+            }
+            Event::Empty(e) if prop_depth.is_some() && prop_depth == Some(depth) => {
+                props.push((ns, e.local_name().as_ref().to_string()));
+            }
+            Event::End(_) => {
+                if prop_depth == Some(depth) {
+                    prop_depth = None;
+                }
+                depth -= 1;
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    Ok(props)
+}
+
+/// Get checked path from `Destination` header
+fn destination(storage: &dyn StorageBackend, headers: &HeaderMap) -> Result<PathBuf, DavError> {
+    let dest =
+        get_header(headers, "Destination").ok_or(DavError::Status(StatusCode::BAD_REQUEST))?;
+    // Strip "scheme://host" of absolute URL
+    let dest = match dest.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or("/"),
+        None => dest,
+    };
+    let dest = dest
+        .strip_prefix(DAV_PREFIX)
+        .ok_or(DavError::Status(StatusCode::BAD_GATEWAY))?;
+    let dest = percent_decode_str(dest)
+        .decode_utf8()
+        .map_err(|_| DavError::Status(StatusCode::BAD_REQUEST))?;
+    check_path(storage, &dest).map_err(|_| DavError::Status(StatusCode::FORBIDDEN))
+}
+
+fn is_root(path: &Path) -> bool {
+    path.as_os_str().is_empty()
+}
+
+/// Parent collection must exist before creating members
+async fn check_parent(storage: &dyn StorageBackend, path: &Path) -> Result<(), DavError> {
+    match path.parent() {
+        Some(p) if storage.is_dir(p).await => Ok(()),
+        _ => Err(DavError::Status(StatusCode::CONFLICT)),
+    }
+}
+
+/// Delete file, or folder with all members
+async fn remove_all(storage: &dyn StorageBackend, path: &Path) -> io::Result<()> {
+    let mut paths = vec![];
+    let mut stack = vec![path.to_path_buf()];
+    while let Some(p) = stack.pop() {
+        if storage.is_dir(&p).await {
+            for entry in storage.list(&p).await? {
+                stack.push(p.join(entry.name));
+            }
+        }
+        paths.push(p);
+    }
+    // Members are deleted before their folder
+    for p in paths.iter().rev() {
+        storage.delete(p).await?;
+    }
+    Ok(())
+}
+
+/// Copy file or folder, members are copied only if `infinite`
+async fn copy_all(
+    storage: &dyn StorageBackend,
+    from: &Path,
+    to: &Path,
+    infinite: bool,
+) -> io::Result<()> {
+    let mut stack = vec![(from.to_path_buf(), to.to_path_buf())];
+    while let Some((from, to)) = stack.pop() {
+        if !storage.is_dir(&from).await {
+            storage.copy(&from, &to).await?;
+            continue;
+        }
+        storage.mkdir(&to).await?;
+        if infinite {
+            for entry in storage.list(&from).await? {
+                stack.push((from.join(&entry.name), to.join(&entry.name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn read_body(mut body: Body) -> Result<String, DavError> {
+    let mut buf = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| DavError::Status(StatusCode::BAD_REQUEST))?;
+        if buf.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(DavError::Status(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    String::from_utf8(buf).map_err(|_| DavError::Status(StatusCode::BAD_REQUEST))
+}
+
+fn get_header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// URL of `path` in WebDAV service, collections end with '/'
+fn href(path: &Path, is_dir: bool) -> String {
+    let mut href = format!(
+        "{}/{}",
+        DAV_PREFIX,
+        utf8_percent_encode(path.to_str().unwrap_or_default(), HREF_SET)
+    );
+    if is_dir && !href.ends_with('/') {
+        href.push('/');
+    }
+    href
+}
+
+fn multistatus(responses: String) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+        responses
+    );
+    xml_response(StatusCode::MULTI_STATUS, body)
+}
+
+fn prop_body(status: StatusCode, prop: String) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:prop xmlns:D=\"DAV:\">{}</D:prop>",
+        prop
+    );
+    xml_response(status, body)
+}
+
+fn xml_response(status: StatusCode, body: String) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_lockinfo() {
+        let body = r#"<?xml version="1.0" encoding="utf-8" ?>
+            <D:lockinfo xmlns:D='DAV:'>
+                <D:lockscope><D:shared/></D:lockscope>
+                <D:locktype><D:write/></D:locktype>
+                <D:owner><D:href>mailto:a&amp;b@example.com</D:href></D:owner>
+            </D:lockinfo>"#;
+        let (shared, owner) = parse_lockinfo(body).unwrap();
+        assert!(shared);
+        assert_eq!(owner, Some("mailto:a&b@example.com".to_string()));
+    }
+
+    #[test]
+    fn test_prop_etag() {
+        let stat = Stat {
+            is_dir: false,
+            size: 5,
+            modified: 1000,
+        };
+        let mut file = File::from_stat("a.txt".into(), &stat);
+        let res = prop_response(Path::new("a.txt"), &file);
+        assert!(res.contains("<D:getetag>&quot;1000-5&quot;</D:getetag>"));
+        file.sha256 = Some("abc".into());
+        let res = prop_response(Path::new("a.txt"), &file);
+        assert!(res.contains("<D:getetag>&quot;abc&quot;</D:getetag>"));
+    }
+
+    #[test]
+    fn test_parse_proppatch() {
+        let body = r#"<?xml version="1.0" encoding="utf-8" ?>
+            <D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:schemas-microsoft-com:">
+                <D:set><D:prop><Z:Win32LastModifiedTime>Wed</Z:Win32LastModifiedTime></D:prop></D:set>
+                <D:remove><D:prop><D:getcontentlanguage/></D:prop></D:remove>
+            </D:propertyupdate>"#;
+        let props = parse_proppatch(body).unwrap();
+        assert_eq!(
+            props,
+            vec![
+                (
+                    "urn:schemas-microsoft-com:".to_string(),
+                    "Win32LastModifiedTime".to_string()
+                ),
+                ("DAV:".to_string(), "getcontentlanguage".to_string()),
+            ]
+        );
+    }
+}
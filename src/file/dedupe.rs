@@ -0,0 +1,381 @@
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use axum::{extract::Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{
+    file::{
+        storage::{Storage, StorageBackend},
+        tracking::refresh_identity,
+        FileError,
+    },
+    user::Claim,
+};
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// Duplicates become links of the same file, it must be on the same filesystem
+    Hardlink,
+    /// Copy-on-write clone, supported by filesystems like Btrfs and XFS
+    Reflink,
+}
+
+#[derive(Deserialize)]
+pub struct DedupeArgs {
+    /// Only dedupe the group of this hash if set
+    sha256: Option<String>,
+    mode: LinkMode,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateGroup {
+    sha256: String,
+    size: u64,
+    paths: Vec<String>,
+    /// Bytes that can be saved, files already hard linked are counted once
+    wasted_bytes: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateReport {
+    groups: Vec<DuplicateGroup>,
+    wasted_bytes: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DedupeResult {
+    linked: Vec<String>,
+    /// Files that can't be linked, e.g. in read-only mount or on other filesystem
+    failed: Vec<String>,
+    /// Files left alone, as their content is changed since hashed,
+    /// or hard link would change their modified time or permissions
+    skipped: Vec<String>,
+    saved_bytes: u64,
+}
+
+/// Get groups of identical files, based on SHA-256 recorded before
+pub async fn get_duplicates(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    _: Claim,
+) -> Result<Json<DuplicateReport>, FileError> {
+    let mut groups = vec![];
+    for (sha256, size, paths) in find_duplicates(&pool, storage.as_ref()).await? {
+        let wasted_bytes = size * (distinct_files(storage.as_ref(), &paths) - 1);
+        groups.push(DuplicateGroup {
+            sha256,
+            size,
+            paths: paths
+                .iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect(),
+            wasted_bytes,
+        });
+    }
+    let wasted_bytes = groups.iter().map(|g| g.wasted_bytes).sum();
+    Ok(Json(DuplicateReport {
+        groups,
+        wasted_bytes,
+    }))
+}
+
+/// Replace duplicates with links to the first file of group.
+/// Paths and content are unchanged, so the file list looks the same
+pub async fn dedupe(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    _: Claim,
+    Json(args): Json<DedupeArgs>,
+) -> Result<Json<DedupeResult>, FileError> {
+    let mut result = DedupeResult {
+        linked: vec![],
+        failed: vec![],
+        skipped: vec![],
+        saved_bytes: 0,
+    };
+    for (sha256, size, paths) in find_duplicates(&pool, storage.as_ref()).await? {
+        if args.sha256.as_ref().is_some_and(|s| *s != sha256) {
+            continue;
+        }
+        let source = match storage.local_path(&paths[0]) {
+            Some(s) => s,
+            None => continue,
+        };
+        let source_id = file_id_of(&source);
+        for path in &paths[1..] {
+            // Already linked
+            if source_id.is_some() && file_id(storage.as_ref(), path) == source_id {
+                continue;
+            }
+            let name = path.to_string_lossy().to_string();
+            let local = match storage.local_path(path) {
+                Some(l) if storage.writable(path) => l,
+                _ => {
+                    result.failed.push(name);
+                    continue;
+                }
+            };
+            let (source, mode) = (source.clone(), args.mode);
+            let linked = tokio::task::spawn_blocking(move || dedupe_file(&source, &local, mode))
+                .await
+                .map_err(|_| FileError::ServerError)?;
+            match linked {
+                Ok(true) => (),
+                Ok(false) => {
+                    result.skipped.push(name);
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!("failed to dedupe {}: {}", name, e);
+                    result.failed.push(name);
+                    continue;
+                }
+            }
+            // Shares of the file follow its new inode
+            refresh_identity(&pool, storage.as_ref(), path).await?;
+            result.linked.push(name);
+            result.saved_bytes += size;
+        }
+    }
+    Ok(Json(result))
+}
+
+/// Groups of (SHA-256, size, paths) with more than one file.
+/// Empty files and files changed since hashed are skipped
+async fn find_duplicates(
+    pool: &SqlitePool,
+    storage: &dyn StorageBackend,
+) -> Result<Vec<(String, u64, Vec<PathBuf>)>, FileError> {
+    let rows = sqlx::query!(
+        "SELECT path, sha256, size FROM file_hash WHERE size > 0 AND sha256 IN
+        (SELECT sha256 FROM file_hash GROUP BY sha256 HAVING COUNT(*) > 1)
+        ORDER BY size DESC, sha256, path"
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut groups: Vec<(String, u64, Vec<PathBuf>)> = vec![];
+    for row in rows {
+        let path = PathBuf::from(&row.path);
+        // Files in hidden mounts are skipped
+        if !storage.contains(&path) {
+            continue;
+        }
+        if storage.sha256(&path).await.as_ref() != Some(&row.sha256) {
+            continue;
+        }
+        match groups.last_mut() {
+            Some((sha256, _, paths)) if *sha256 == row.sha256 => paths.push(path),
+            _ => groups.push((row.sha256, row.size as u64, vec![path])),
+        }
+    }
+    groups.retain(|(_, _, paths)| paths.len() > 1);
+    Ok(groups)
+}
+
+/// Number of files with different data, hard links of the same file are counted once
+fn distinct_files(storage: &dyn StorageBackend, paths: &[PathBuf]) -> u64 {
+    let mut ids = HashSet::new();
+    let mut count = 0;
+    for path in paths {
+        match file_id(storage, path) {
+            Some(id) if !ids.insert(id) => (),
+            _ => count += 1,
+        }
+    }
+    count
+}
+
+/// Device and inode of local file
+fn file_id(storage: &dyn StorageBackend, path: &Path) -> Option<(u64, u64)> {
+    file_id_of(&storage.local_path(path)?)
+}
+
+#[cfg(unix)]
+fn file_id_of(local: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::metadata(local).ok()?;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id_of(_local: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Link `path` to `source` if they have the same content, and in hard link mode
+/// the same modified time and permissions, which the link takes from `source`.
+/// Return if it's linked
+fn dedupe_file(source: &Path, path: &Path, mode: LinkMode) -> io::Result<bool> {
+    if let LinkMode::Hardlink = mode {
+        let (a, b) = (std::fs::metadata(source)?, std::fs::metadata(path)?);
+        if a.permissions() != b.permissions() || modified_secs(&a) != modified_secs(&b) {
+            return Ok(false);
+        }
+    }
+    // Hash may be recorded before the file is changed
+    if !same_content(source, path)? {
+        return Ok(false);
+    }
+    link_file(source, path, mode)?;
+    Ok(true)
+}
+
+/// Modified time in seconds, the precision shown to users
+fn modified_secs(meta: &Metadata) -> Option<u64> {
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(modified.as_secs())
+}
+
+/// Compare data of two files byte by byte
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::new(std::fs::File::open(a)?);
+    let mut b = BufReader::new(std::fs::File::open(b)?);
+    loop {
+        let (x, y) = (a.fill_buf()?, b.fill_buf()?);
+        if x.is_empty() || y.is_empty() {
+            return Ok(x.is_empty() && y.is_empty());
+        }
+        let n = x.len().min(y.len());
+        if x[..n] != y[..n] {
+            return Ok(false);
+        }
+        a.consume(n);
+        b.consume(n);
+    }
+}
+
+/// Make `path` share data with `source`. The link is made beside `path`,
+/// then renamed to it, so `path` is never missing
+fn link_file(source: &Path, path: &Path, mode: LinkMode) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".dedupe");
+    let temp = PathBuf::from(temp);
+    let linked = match mode {
+        LinkMode::Hardlink => std::fs::hard_link(source, &temp),
+        LinkMode::Reflink => reflink(source, path, &temp),
+    };
+    if let Err(e) = linked.and_then(|_| std::fs::rename(&temp, path)) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Clone `source` into `temp` with the permissions and modified time of `path`
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, path: &Path, temp: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let meta = std::fs::metadata(path)?;
+    let src = std::fs::File::open(source)?;
+    let dst = std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(temp)?;
+    // SAFETY: both descriptors are owned by the files above and stay open during the call
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    dst.set_permissions(meta.permissions())?;
+    dst.set_modified(meta.modified()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _path: &Path, _temp: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::file::{
+        checksum::HashStorage,
+        fixture::{claim, pool, temp_folder},
+        storage::LocalStorage,
+    };
+
+    #[tokio::test]
+    async fn test_dedupe() {
+        let pool = pool().await;
+        let root = temp_folder();
+        let storage: Storage = Arc::new(HashStorage::new(
+            Arc::new(LocalStorage::new(root.clone())),
+            pool.clone(),
+        ));
+        let set_modified = |name: &str, secs: i64| {
+            let file = std::fs::File::options()
+                .write(true)
+                .open(root.join(name))
+                .unwrap();
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(secs as u64))
+                .unwrap();
+            let pool = pool.clone();
+            let name = name.to_string();
+            async move {
+                sqlx::query!(
+                    "UPDATE file_hash SET modified = ? WHERE path = ?",
+                    secs,
+                    name
+                )
+                .execute(&pool)
+                .await
+                .unwrap();
+            }
+        };
+        for (name, modified) in [("a", 1000), ("b", 1000), ("c", 1000), ("d", 2000)] {
+            storage
+                .write(Path::new(name), &mut &b"same"[..])
+                .await
+                .unwrap();
+            set_modified(name, modified).await;
+        }
+        let Json(report) =
+            get_duplicates(Extension(pool.clone()), Extension(storage.clone()), claim())
+                .await
+                .unwrap();
+        assert_eq!(report.groups[0].paths, ["a", "b", "c", "d"]);
+        assert_eq!(report.wasted_bytes, 12);
+        // Changed without changing size and modified time after hashed
+        std::fs::write(root.join("c"), "SAME").unwrap();
+        set_modified("c", 1000).await;
+        let args = DedupeArgs {
+            sha256: None,
+            mode: LinkMode::Hardlink,
+        };
+        let Json(result) = dedupe(
+            Extension(pool.clone()),
+            Extension(storage.clone()),
+            claim(),
+            Json(args),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.linked, ["b"]);
+        // Hard link would take modified time of "a"
+        assert_eq!(result.skipped, ["c", "d"]);
+        assert_eq!(storage.stat(Path::new("d")).await.unwrap().modified, 2000);
+        assert_eq!(std::fs::read(root.join("c")).unwrap(), b"SAME");
+        let Json(report) = get_duplicates(Extension(pool), Extension(storage.clone()), claim())
+            .await
+            .unwrap();
+        assert_eq!(report.wasted_bytes, 8);
+        // Writing a linked file doesn't change the others
+        storage
+            .write(Path::new("b"), &mut &b"new"[..])
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(root.join("a")).unwrap(), b"same");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::{Extension, Query},
+    Json,
+};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    file::{
+        check_path,
+        storage::{Storage, StorageBackend},
+        FileError,
+    },
+    user::{get_unix_timestamp, Claim},
+    CONFIG,
+};
+
+/// Largest files kept for each folder, also the max top-N of files
+const MAX_FILES: usize = 100;
+const DEFAULT_TOP: usize = 10;
+
+lazy_static! {
+    /// Last scan of storage, keyed by role when mounts are hidden by role
+    static ref SCANS: Mutex<HashMap<String, (Storage, Arc<DiskUsage>)>> =
+        Mutex::new(HashMap::new());
+    /// Keys of scans running in background
+    static ref RUNNING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+#[derive(Deserialize)]
+pub struct DiskUsageArgs {
+    #[serde(default)]
+    path: String,
+    /// Number of largest folders and files, files are at most `MAX_FILES`
+    top: Option<usize>,
+    /// Breakdown by "extension" (default) or "mime"
+    by: Option<String>,
+    /// Scan again in background, cached result is returned until it completes
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[derive(Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeUsage {
+    size: u64,
+    file_count: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeEntry {
+    #[serde(rename = "type")]
+    type_: String,
+    #[serde(flatten)]
+    usage: TypeUsage,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageEntry {
+    path: String,
+    size: u64,
+    /// Files in folder, including sub folders
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_count: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskUsageReport {
+    scanned_at: u64,
+    path: String,
+    size: u64,
+    file_count: u64,
+    /// Sub folders of `path`, for drawing treemap
+    children: Vec<UsageEntry>,
+    largest_folders: Vec<UsageEntry>,
+    largest_files: Vec<UsageEntry>,
+    types: Vec<TypeEntry>,
+}
+
+/// Recursive size of a folder
+#[derive(Default)]
+struct FolderUsage {
+    size: u64,
+    file_count: u64,
+    /// Keyed by lowercase extension
+    types: HashMap<String, TypeUsage>,
+    /// Largest files, including files in sub folders, sorted by size
+    files: Vec<(Arc<PathBuf>, u64)>,
+}
+
+impl FolderUsage {
+    fn keep_largest(&mut self) {
+        self.files.sort_by_key(|f| Reverse(f.1));
+        self.files.truncate(MAX_FILES);
+    }
+}
+
+/// Sizes of all folders in storage
+pub struct DiskUsage {
+    scanned_at: u64,
+    folders: HashMap<PathBuf, FolderUsage>,
+}
+
+impl DiskUsage {
+    /// Walk through the whole storage
+    pub async fn scan(storage: &dyn StorageBackend) -> io::Result<DiskUsage> {
+        let mut usage = DiskUsage {
+            scanned_at: get_unix_timestamp(),
+            folders: HashMap::new(),
+        };
+        let mut pending = vec![PathBuf::new()];
+        while let Some(folder) = pending.pop() {
+            usage.folders.entry(folder.clone()).or_default();
+            // Folder removed during scan is skipped
+            let entries = match storage.list(&folder).await {
+                Ok(entries) => entries,
+                Err(e) if folder.as_os_str().is_empty() => return Err(e),
+                Err(_) => continue,
+            };
+            for entry in entries {
+                let path = folder.join(&entry.name);
+                if entry.stat.is_dir {
+                    pending.push(path);
+                    continue;
+                }
+                let (size, ext) = (entry.stat.size, extension(&path));
+                let path = Arc::new(path);
+                for ancestor in folder.ancestors() {
+                    let f = usage.folders.entry(ancestor.to_path_buf()).or_default();
+                    f.size += size;
+                    f.file_count += 1;
+                    let t = f.types.entry(ext.clone()).or_default();
+                    t.size += size;
+                    t.file_count += 1;
+                    f.files.push((path.clone(), size));
+                    if f.files.len() >= MAX_FILES * 2 {
+                        f.keep_largest();
+                    }
+                }
+            }
+        }
+        usage.folders.values_mut().for_each(FolderUsage::keep_largest);
+        Ok(usage)
+    }
+
+    /// Usage of folder `path`, `None` if it isn't scanned
+    fn report(&self, path: &Path, top: usize, by_mime: bool) -> Option<DiskUsageReport> {
+        let folder = self.folders.get(path)?;
+        let entry = |(p, size, count): (&PathBuf, u64, Option<u64>)| UsageEntry {
+            path: p.to_string_lossy().to_string(),
+            size,
+            file_count: count,
+        };
+        let mut children: Vec<_> = self
+            .folders
+            .iter()
+            .filter(|(p, _)| p.parent() == Some(path))
+            .map(|(p, f)| (p, f.size, Some(f.file_count)))
+            .collect();
+        children.sort_by_key(|f| Reverse(f.1));
+        let mut folders: Vec<_> = self
+            .folders
+            .iter()
+            .filter(|(p, _)| p.starts_with(path) && p.as_path() != path)
+            .map(|(p, f)| (p, f.size, Some(f.file_count)))
+            .collect();
+        folders.sort_by_key(|f| Reverse(f.1));
+        let files = folder
+            .files
+            .iter()
+            .take(top)
+            .map(|(p, size)| entry((p, *size, None)));
+        let mut types: HashMap<String, TypeUsage> = HashMap::new();
+        for (ext, usage) in &folder.types {
+            let key = match by_mime {
+                true => mime_guess::from_ext(ext)
+                    .first_or_octet_stream()
+                    .essence_str()
+                    .to_string(),
+                false => ext.clone(),
+            };
+            let t = types.entry(key).or_default();
+            t.size += usage.size;
+            t.file_count += usage.file_count;
+        }
+        let mut types: Vec<_> = types
+            .into_iter()
+            .map(|(type_, usage)| TypeEntry { type_, usage })
+            .collect();
+        types.sort_by_key(|t| Reverse(t.usage.size));
+        Some(DiskUsageReport {
+            scanned_at: self.scanned_at,
+            path: path.to_string_lossy().to_string(),
+            size: folder.size,
+            file_count: folder.file_count,
+            children: children.into_iter().map(entry).collect(),
+            largest_folders: folders.into_iter().take(top).map(entry).collect(),
+            largest_files: files.collect(),
+            types,
+        })
+    }
+}
+
+/// Get recursive sizes under `path`, largest folders and files,
+/// and sizes by file type. Result may be `FS_DU_INTERVAL` seconds old
+pub async fn get_disk_usage(
+    Query(args): Query<DiskUsageArgs>,
+    Extension(storage): Extension<Storage>,
+    claim: Claim,
+) -> Result<Json<DiskUsageReport>, FileError> {
+    let path = check_path(storage.as_ref(), &args.path)?;
+    let by_mime = match args.by.as_deref() {
+        None | Some("extension") => false,
+        Some("mime") => true,
+        Some(_) => return Err(FileError::ContentError),
+    };
+    let top = args.top.unwrap_or(DEFAULT_TOP);
+    let usage = disk_usage(&storage, &claim.role, args.refresh).await?;
+    let mut report = usage.report(&path, top, by_mime);
+    // Folder created after the last scan
+    if report.is_none() && storage.is_dir(&path).await {
+        let usage = scan(scan_key(&claim.role), storage.clone()).await?;
+        report = usage.report(&path, top, by_mime);
+    }
+    Ok(Json(report.ok_or(FileError::PathError)?))
+}
+
+/// Users see the same storage unless mounts are hidden by role
+fn scan_key(role: &str) -> String {
+    match CONFIG.mounts.is_empty() {
+        true => String::new(),
+        false => role.to_string(),
+    }
+}
+
+/// Get cached usage of `storage`, scan it if it isn't scanned yet.
+/// With `refresh` it's scanned again in background, unless a scan is running
+async fn disk_usage(storage: &Storage, role: &str, refresh: bool) -> io::Result<Arc<DiskUsage>> {
+    let key = scan_key(role);
+    let cached = SCANS.lock().unwrap().get(&key).map(|(_, u)| u.clone());
+    let usage = match cached {
+        Some(usage) => usage,
+        None => return scan(key, storage.clone()).await,
+    };
+    if refresh && RUNNING.lock().unwrap().insert(key.clone()) {
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            if let Err(e) = scan(key.clone(), storage).await {
+                tracing::warn!("failed to scan disk usage: {}", e);
+            }
+            RUNNING.lock().unwrap().remove(&key);
+        });
+    }
+    Ok(usage)
+}
+
+/// Scan `storage` and cache the result with `key`
+async fn scan(key: String, storage: Storage) -> io::Result<Arc<DiskUsage>> {
+    let usage = Arc::new(DiskUsage::scan(storage.as_ref()).await?);
+    SCANS.lock().unwrap().insert(key, (storage, usage.clone()));
+    Ok(usage)
+}
+
+/// Scan `storage` at start, then refresh every scanned storage
+/// every `FS_DU_INTERVAL` seconds
+pub async fn refresh_loop(storage: Storage) {
+    if CONFIG.mounts.is_empty() {
+        if let Err(e) = disk_usage(&storage, "", false).await {
+            tracing::warn!("failed to scan disk usage: {}", e);
+        }
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(CONFIG.du_interval.max(1)));
+    // First tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let scanned: Vec<_> = SCANS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, (storage, _))| (key.clone(), storage.clone()))
+            .collect();
+        for (key, storage) in scanned {
+            if let Err(e) = scan(key, storage).await {
+                tracing::warn!("failed to scan disk usage: {}", e);
+            }
+        }
+    }
+}
+
+/// Lowercase extension of file, empty if it has none
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_disk_usage() {
+        let storage = MemoryStorage::new();
+        storage.mkdir(Path::new("a")).await.unwrap();
+        storage.mkdir(Path::new("a/b")).await.unwrap();
+        for (path, data) in [("a/x.TXT", "12"), ("a/b/y.txt", "123"), ("z.png", "1")] {
+            storage
+                .write(Path::new(path), &mut data.as_bytes())
+                .await
+                .unwrap();
+        }
+        let usage = DiskUsage::scan(&storage).await.unwrap();
+        let report = usage.report(Path::new(""), 1, false).unwrap();
+        assert_eq!((report.size, report.file_count), (6, 3));
+        assert_eq!(report.children[0].path, "a");
+        assert_eq!(report.largest_files.len(), 1);
+        assert_eq!(report.largest_files[0].path, "a/b/y.txt");
+        assert_eq!(report.types[0].type_, "txt");
+        assert_eq!(report.types[0].usage.size, 5);
+        let report = usage.report(Path::new("a"), 10, true).unwrap();
+        assert_eq!(report.largest_folders[0].path, "a/b");
+        assert_eq!(report.types[0].type_, "text/plain");
+        assert!(usage.report(Path::new("c"), 10, false).is_none());
+        // Largest files of a folder aren't pushed out by larger files elsewhere
+        storage.mkdir(Path::new("big")).await.unwrap();
+        for i in 0..MAX_FILES {
+            storage
+                .write(&Path::new("big").join(i.to_string()), &mut &[0; 10][..])
+                .await
+                .unwrap();
+        }
+        let usage = DiskUsage::scan(&storage).await.unwrap();
+        let report = usage.report(Path::new(""), MAX_FILES, false).unwrap();
+        assert!(report.largest_files.iter().all(|f| f.size == 10));
+        let report = usage.report(Path::new("a"), 10, false).unwrap();
+        assert_eq!(report.largest_files.len(), 2);
+    }
+}
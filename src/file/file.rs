@@ -1,29 +1,90 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use axum::{
-    body::Bytes,
-    extract::{Multipart, Query},
-    http::StatusCode,
+    body::{Bytes, StreamBody},
+    extract::{multipart::Field, BodyStream, Extension, Multipart, Query},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
-use tokio::fs::{remove_dir, remove_file, rename, write};
+use futures_util::{future, stream, StreamExt, TryStreamExt};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::{ReaderStream, StreamReader};
 
 use crate::{
-    file::{concat_path_str, is_traversal, CheckedPath, File, FileError, QueryArgs, RenameArgs},
+    file::{
+        check_path,
+        checksum::digest_header,
+        metadata::{load_metadata, MediaFilter},
+        remove_path, rename_path,
+        storage::{Stat, Storage, StorageBackend},
+        write_path, CheckedPath, File, FileError, QueryArgs, RenameArgs,
+    },
     user::Claim,
-    CONFIG,
 };
 
+/// Max ranges in a request, whole file is sent for more ranges
+const MAX_RANGES: usize = 16;
+/// Max size of text file after appending
+const MAX_APPEND_SIZE: u64 = 1024 * 1024;
+
+lazy_static! {
+    /// Files being written by `write_file`
+    static ref WRITING: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// Mark of file being written, removed when dropped
+pub struct WriteGuard(PathBuf);
+
+impl WriteGuard {
+    /// `None` if the file is being written by another request
+    pub fn new(path: &Path) -> Option<WriteGuard> {
+        let mut writing = WRITING.lock().unwrap();
+        writing
+            .insert(path.to_path_buf())
+            .then(|| WriteGuard(path.to_path_buf()))
+    }
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        WRITING.lock().unwrap().remove(&self.0);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WriteArgs {
+    /// Append body to the end of a text file
+    #[serde(default)]
+    append: bool,
+}
+
+/// Download file
+pub async fn download_file(
+    CheckedPath(path): CheckedPath,
+    Extension(storage): Extension<Storage>,
+    headers: HeaderMap,
+    _: Claim,
+) -> Result<Response, FileError> {
+    file_response(storage.as_ref(), &path, &headers).await
+}
+
 /// Delete file
 pub async fn delete_file(
     CheckedPath(path): CheckedPath,
+    Extension(storage): Extension<Storage>,
     _: Claim,
 ) -> Result<StatusCode, FileError> {
-    if path.is_dir() {
-        remove_dir(path).await?;
-    } else {
-        remove_file(path).await?;
-    }
+    remove_path(storage.as_ref(), &path).await?;
     Ok(StatusCode::OK)
 }
 
@@ -31,76 +92,498 @@ pub async fn delete_file(
 pub async fn rename_file(
     Query(args): Query<RenameArgs>,
     CheckedPath(from): CheckedPath,
+    Extension(storage): Extension<Storage>,
     _: Claim,
 ) -> Result<StatusCode, FileError> {
-    let to = concat_path_str(&args.to);
-    if is_traversal(&to) {
-        return Err(FileError::PathError);
-    }
-    rename(from, to).await?;
+    let to = check_path(storage.as_ref(), &args.to)?;
+    rename_path(storage.as_ref(), &from, &to).await?;
     Ok(StatusCode::OK)
 }
 
 /// Using multipart to accept upload file
 pub async fn upload_file(
-    CheckedPath(mut path): CheckedPath,
+    CheckedPath(path): CheckedPath,
+    Extension(storage): Extension<Storage>,
     _: Claim,
     mut multipart: Multipart,
 ) -> Result<StatusCode, FileError> {
-    // New file `data` will be store in `FOLDER + path + name`
-    let mut data: Option<Bytes> = None;
-    let mut name: Option<String> = None;
-    while let Some(field) = multipart.next_field().await? {
-        match field.name() {
-            Some("file") => {
-                name = Some(
-                    field
-                        .file_name()
-                        .ok_or(FileError::ContentError)?
-                        .to_string(),
-                );
-                data = Some(field.bytes().await?);
-                break;
-            }
-            _ => return Err(FileError::ContentError),
-        }
+    save_upload(storage.as_ref(), &path, &mut multipart).await?;
+    Ok(StatusCode::OK)
+}
+
+/// Field of uploaded file in multipart and its name
+pub async fn next_file(multipart: &mut Multipart) -> Result<(Field<'_>, String), FileError> {
+    let field = multipart
+        .next_field()
+        .await?
+        .ok_or(FileError::ContentError)?;
+    if field.name() != Some("file") {
+        return Err(FileError::ContentError);
     }
-    let data = data.ok_or(FileError::ContentError)?;
-    let name = name.ok_or(FileError::ContentError)?;
-    path.push(&name);
-    if is_traversal(&path) {
+    let name = field
+        .file_name()
+        .ok_or(FileError::ContentError)?
+        .to_string();
+    // Name must not contain folder
+    if Path::new(&name).file_name() != Some(OsStr::new(&name)) {
         return Err(FileError::PathError);
     }
+    Ok((field, name))
+}
+
+/// Write uploaded file into `folder`, existing file isn't replaced
+pub async fn save_upload(
+    storage: &dyn StorageBackend,
+    folder: &Path,
+    multipart: &mut Multipart,
+) -> Result<PathBuf, FileError> {
+    let (field, name) = next_file(multipart).await?;
+    // New file will be store in `folder + name`
+    let path = folder.join(&name);
+    let path = check_path(storage, path.to_str().ok_or(FileError::PathError)?)?;
     // Don't write to a exist file
-    if path.is_file() {
+    if storage.exists(&path).await {
         return Err(FileError::PathError);
     }
-    write(path, data).await?;
-    Ok(StatusCode::OK)
+    let mut data = StreamReader::new(field.map_err(io::Error::other));
+    if let Err(e) = write_path(storage, &path, &mut data).await {
+        // Don't leave broken file
+        let _ = storage.delete(&path).await;
+        return Err(e.into());
+    }
+    Ok(path)
 }
 
-/// Search file based on name
+/// Replace content of an existing file. `If-Match` with its current `ETag` is required,
+/// so that concurrent edits fail with 412 instead of overwriting each other.
+/// In append mode, body is added to the end of a small text file and `If-Match` is optional
+pub async fn write_file(
+    Query(args): Query<WriteArgs>,
+    CheckedPath(path): CheckedPath,
+    Extension(storage): Extension<Storage>,
+    headers: HeaderMap,
+    _: Claim,
+    body: BodyStream,
+) -> Result<Response, FileError> {
+    let _guard = WriteGuard::new(&path).ok_or(FileError::PreconditionFailed)?;
+    let stat = storage.stat(&path).await?;
+    if stat.is_dir {
+        return Err(FileError::PathError);
+    }
+    let if_match = headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok());
+    let etag = file_etag(storage.sha256(&path).await.as_deref(), &stat);
+    match if_match {
+        Some(if_match) if !etag_matches(if_match, &etag) => {
+            return Err(FileError::PreconditionFailed)
+        }
+        None if !args.append => return Err(FileError::PreconditionRequired),
+        _ => (),
+    }
+    let mut body = StreamReader::new(body.map_err(io::Error::other));
+    if args.append {
+        if stat.size > MAX_APPEND_SIZE {
+            return Err(FileError::ContentError);
+        }
+        let mut data = vec![];
+        storage
+            .read(&path, None)
+            .await?
+            .read_to_end(&mut data)
+            .await?;
+        (&mut body)
+            .take(MAX_APPEND_SIZE + 1 - data.len() as u64)
+            .read_to_end(&mut data)
+            .await?;
+        if data.len() as u64 > MAX_APPEND_SIZE || std::str::from_utf8(&data).is_err() {
+            return Err(FileError::ContentError);
+        }
+        write_path(storage.as_ref(), &path, &mut &data[..]).await?;
+    } else {
+        write_path(storage.as_ref(), &path, &mut body).await?;
+    }
+    let stat = storage.stat(&path).await?;
+    let etag = file_etag(storage.sha256(&path).await.as_deref(), &stat);
+    Ok((StatusCode::OK, [(header::ETAG, etag)]).into_response())
+}
+
+/// Search file based on name and media metadata
 pub async fn search_file(
     Query(args): Query<QueryArgs>,
+    Query(filter): Query<MediaFilter>,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
     _: Claim,
 ) -> Result<Json<Vec<File>>, FileError> {
-    let mut search_folders: Vec<PathBuf> = vec![PathBuf::from(CONFIG.folder_path.clone())];
+    let mut search_folders: Vec<PathBuf> = vec![PathBuf::new()];
     let mut files = vec![];
     // Iter all folders to find matched file and folder
-    while search_folders.len() != 0 {
-        let folder = search_folders.pop().ok_or(FileError::PathError)?;
-        for f in File::read_dir(&folder)? {
+    while let Some(folder) = search_folders.pop() {
+        let mut found = File::read_dir(storage.as_ref(), &folder).await?;
+        load_metadata(&pool, &folder, &mut found).await?;
+        for f in found {
             // Push folder into search list
             if f.type_ == "folder" {
-                // Concat path
-                let mut new_folder = folder.clone();
-                new_folder.push(&f.name);
-                search_folders.push(new_folder);
+                search_folders.push(folder.join(&f.name));
             }
-            if f.name.contains(&args.name) {
+            if f.name.contains(&args.name) && filter.matches(f.metadata.as_ref()) {
                 files.push(f.absolute_path(&folder).ok_or(FileError::PathError)?);
             }
         }
     }
     Ok(Json(files))
 }
+
+/// Response of file content, conditional requests and ranges are supported.
+/// Several ranges are sent as "multipart/byteranges".
+/// `Digest` is added if SHA-256 of file is known
+pub async fn file_response(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+) -> Result<Response, FileError> {
+    let stat = storage.stat(path).await?;
+    if stat.is_dir {
+        return Err(FileError::PathError);
+    }
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(stat.modified);
+    let last_modified = HeaderValue::from_str(&httpdate::fmt_http_date(modified))
+        .map_err(|_| FileError::ServerError)?;
+    let sha256 = storage.sha256(path).await;
+    let etag = file_etag(sha256.as_deref(), &stat);
+    let header_str = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    let header_date = |name| header_str(name).and_then(|v| httpdate::parse_http_date(v).ok());
+    if header_str(header::IF_MATCH).is_some_and(|m| !etag_matches(m, &etag))
+        || header_date(header::IF_UNMODIFIED_SINCE).is_some_and(|s| modified > s)
+    {
+        return Err(FileError::PreconditionFailed);
+    }
+    if header_str(header::IF_NONE_MATCH).is_some_and(|n| etag_matches(n, &etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    if header_date(header::IF_MODIFIED_SINCE).is_some_and(|s| modified <= s) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [(header::LAST_MODIFIED, last_modified)],
+        )
+            .into_response());
+    }
+    // Range of a changed file is ignored, whole file is sent instead
+    let if_range = header_str(header::IF_RANGE)
+        .is_none_or(|v| v == etag || httpdate::parse_http_date(v).is_ok_and(|d| d == modified));
+    let mut ranges = match header_str(header::RANGE) {
+        Some(r) if if_range => match parse_ranges(r, stat.size) {
+            Some(r) => r,
+            None => {
+                return Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{}", stat.size))],
+                )
+                    .into_response())
+            }
+        },
+        _ => vec![],
+    };
+    if ranges.len() > MAX_RANGES {
+        ranges.clear();
+    }
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let mut res = match ranges.as_slice() {
+        [] => {
+            let reader = storage.read(path, None).await?;
+            let mut res = StreamBody::new(ReaderStream::new(reader)).into_response();
+            res.headers_mut()
+                .insert(header::CONTENT_LENGTH, stat.size.into());
+            res
+        }
+        [r] => {
+            let reader = storage.read(path, Some(r.clone())).await?;
+            let mut res = StreamBody::new(ReaderStream::new(reader)).into_response();
+            let content_range = format!("bytes {}-{}/{}", r.start, r.end - 1, stat.size);
+            let res_headers = res.headers_mut();
+            res_headers.insert(header::CONTENT_LENGTH, (r.end - r.start).into());
+            if let Ok(v) = HeaderValue::from_str(&content_range) {
+                res_headers.insert(header::CONTENT_RANGE, v);
+            }
+            *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+            res
+        }
+        ranges => byteranges_response(storage, path, ranges, mime.as_ref(), stat.size).await?,
+    };
+    let res_headers = res.headers_mut();
+    if !res_headers.contains_key(header::CONTENT_TYPE) {
+        if let Ok(mime) = HeaderValue::from_str(mime.as_ref()) {
+            res_headers.insert(header::CONTENT_TYPE, mime);
+        }
+    }
+    res_headers.insert(header::LAST_MODIFIED, last_modified);
+    res_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(v) = HeaderValue::from_str(&etag) {
+        res_headers.insert(header::ETAG, v);
+    }
+    let digest = sha256.as_deref().and_then(digest_header);
+    if let Some(v) = digest.and_then(|d| HeaderValue::from_str(&d).ok()) {
+        res_headers.insert("digest", v);
+    }
+    Ok(res)
+}
+
+/// Response of several ranges, each part has its own `Content-Range`
+async fn byteranges_response(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    ranges: &[Range<u64>],
+    mime: &str,
+    size: u64,
+) -> io::Result<Response> {
+    let boundary = format!("{:016x}", rand::random::<u64>());
+    let mut length = 0;
+    let mut parts = vec![];
+    for (i, r) in ranges.iter().enumerate() {
+        let head = format!(
+            "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            if i == 0 { "" } else { "\r\n" },
+            boundary,
+            mime,
+            r.start,
+            r.end - 1,
+            size
+        );
+        length += head.len() as u64 + r.end - r.start;
+        let reader = storage.read(path, Some(r.clone())).await?;
+        parts.push(
+            stream::once(future::ready(Ok(Bytes::from(head)))).chain(ReaderStream::new(reader)),
+        );
+    }
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    length += tail.len() as u64;
+    let body = stream::iter(parts)
+        .flatten()
+        .chain(stream::once(future::ready(Ok(Bytes::from(tail)))));
+    let content_type = format!("multipart/byteranges; boundary={}", boundary);
+    Ok((
+        StatusCode::PARTIAL_CONTENT,
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_LENGTH, length.to_string()),
+        ],
+        StreamBody::new(body),
+    )
+        .into_response())
+}
+
+/// `ETag` of file, SHA-256 if it's known, otherwise modified time and size
+pub fn file_etag(sha256: Option<&str>, stat: &Stat) -> String {
+    match sha256 {
+        Some(sha256) => format!("\"{}\"", sha256),
+        None => format!("\"{}-{}\"", stat.modified, stat.size),
+    }
+}
+
+/// Check `If-Match` or `If-None-Match` header contains `etag` or "*"
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .any(|t| t.trim() == "*" || t.trim() == etag)
+}
+
+/// Parse "bytes=start-end, start-end", ranges that can't be satisfied are skipped.
+/// `None` if no range can be satisfied
+fn parse_ranges(range: &str, size: u64) -> Option<Vec<Range<u64>>> {
+    let ranges: Vec<_> = range
+        .strip_prefix("bytes=")?
+        .split(',')
+        .filter_map(|r| parse_range(r, size))
+        .collect();
+    (!ranges.is_empty()).then_some(ranges)
+}
+
+/// Parse "start-end", `None` if range can't be satisfied
+fn parse_range(range: &str, size: u64) -> Option<Range<u64>> {
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (size.saturating_sub(suffix.parse().ok()?), size),
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.saturating_add(1).min(size),
+        ),
+    };
+    if start >= end {
+        return None;
+    }
+    Some(start..end)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use axum::extract::FromRequest;
+
+    use super::*;
+    use crate::file::{
+        fixture::{claim, pool},
+        folder::{create_folder, get_folder},
+        storage::MemoryStorage,
+    };
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_parse_range() {
+        assert_eq!(parse_ranges("bytes=0-99", 50), Some(vec![0..50]));
+        assert_eq!(parse_ranges("bytes=10-", 50), Some(vec![10..50]));
+        assert_eq!(parse_ranges("bytes=-10", 50), Some(vec![40..50]));
+        assert_eq!(parse_ranges("bytes=50-", 50), None);
+        assert_eq!(
+            parse_ranges("bytes=0-0, 50-, -1", 50),
+            Some(vec![0..1, 49..50])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_response() {
+        let storage = MemoryStorage::new();
+        let path = Path::new("a.txt");
+        storage.write(path, &mut &b"hello"[..]).await.unwrap();
+        let get = |pairs: &[(header::HeaderName, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name, HeaderValue::from_str(value).unwrap());
+            }
+            let storage = &storage;
+            async move { file_response(storage, path, &headers).await }
+        };
+        let res = get(&[(header::RANGE, "bytes=0-0,-2")]).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = res.headers()[header::CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let body = format!(
+            "--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-0/5\r\n\r\nh\r\n\
+            --{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 3-4/5\r\n\r\nlo\r\n--{0}--\r\n",
+            boundary
+        );
+        assert_eq!(
+            res.headers()[header::CONTENT_LENGTH],
+            body.len().to_string()
+        );
+        let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+        // Range of changed file is ignored
+        let res = get(&[(header::RANGE, "bytes=1-"), (header::IF_RANGE, "\"other\"")])
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = get(&[(header::IF_NONE_MATCH, &etag)]).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert!(matches!(
+            get(&[(header::IF_MATCH, "\"other\"")]).await,
+            Err(FileError::PreconditionFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handlers() {
+        let pool = pool().await;
+        let storage: Storage = Arc::new(MemoryStorage::new());
+        let path = |p: &str| CheckedPath(PathBuf::from(p));
+        create_folder(path("a"), Extension(storage.clone()), claim())
+            .await
+            .unwrap();
+        storage
+            .write(Path::new("a/b.txt"), &mut &b"hello"[..])
+            .await
+            .unwrap();
+        rename_file(
+            Query(RenameArgs {
+                to: "/a/c.txt".into(),
+            }),
+            path("a/b.txt"),
+            Extension(storage.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        let Json(files) = get_folder(
+            path("a"),
+            Extension(pool.clone()),
+            Extension(storage.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(files[0].name, "c.txt");
+        assert_eq!(files[0].size, 5);
+        let Json(files) = search_file(
+            Query(QueryArgs { name: "c.".into() }),
+            Query(MediaFilter::default()),
+            Extension(pool),
+            Extension(storage.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(files[0].absolute_path, Some("a/c.txt".into()));
+        // Folder isn't empty
+        assert!(delete_file(path("a"), Extension(storage.clone()), claim())
+            .await
+            .is_err());
+        delete_file(path("a/c.txt"), Extension(storage.clone()), claim())
+            .await
+            .unwrap();
+        assert!(!storage.exists(Path::new("a/c.txt")).await);
+    }
+
+    #[tokio::test]
+    async fn test_write_file() {
+        let storage: Storage = Arc::new(MemoryStorage::new());
+        let path = Path::new("note.txt");
+        storage.write(path, &mut &b"a\n"[..]).await.unwrap();
+        let stat = storage.stat(path).await.unwrap();
+        let etag = file_etag(None, &stat);
+        let write = |append: bool, if_match: Option<&str>, body: &'static str| {
+            let mut headers = HeaderMap::new();
+            if let Some(t) = if_match {
+                headers.insert(header::IF_MATCH, t.parse().unwrap());
+            }
+            let storage = storage.clone();
+            async move {
+                let req = axum::http::Request::new(axum::body::Body::from(body));
+                let body = BodyStream::from_request(req, &()).await.unwrap();
+                write_file(
+                    Query(WriteArgs { append }),
+                    CheckedPath(PathBuf::from("note.txt")),
+                    Extension(storage),
+                    headers,
+                    claim(),
+                    body,
+                )
+                .await
+            }
+        };
+        assert!(matches!(
+            write(false, None, "b").await,
+            Err(FileError::PreconditionRequired)
+        ));
+        assert!(matches!(
+            write(false, Some("\"0-0\""), "b").await,
+            Err(FileError::PreconditionFailed)
+        ));
+        let res = write(false, Some(&etag), "bb\n").await.unwrap();
+        let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+        // The old ETag is outdated
+        assert!(write(false, Some(&file_etag(None, &stat)), "c")
+            .await
+            .is_err());
+        write(true, Some(&etag), "c\n").await.unwrap();
+        write(true, None, "d\n").await.unwrap();
+        let mut data = String::new();
+        let mut reader = storage.read(path, None).await.unwrap();
+        reader.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, "bb\nc\nd\n");
+    }
+}
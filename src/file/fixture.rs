@@ -0,0 +1,25 @@
+//! Setup shared by tests
+
+use std::path::PathBuf;
+
+use sqlx::{migrate, SqlitePool};
+
+use crate::user::Claim;
+
+/// Database in memory with all migrations run
+pub async fn pool() -> SqlitePool {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    migrate!().run(&pool).await.unwrap();
+    pool
+}
+
+/// Empty folder not used by other tests, removed by the test
+pub fn temp_folder() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("file-station-{:x}", rand::random::<u64>()));
+    std::fs::create_dir(&path).unwrap();
+    path
+}
+
+pub fn claim() -> Claim {
+    serde_json::from_str(r#"{"sub":"test","username":"test","exp":0}"#).unwrap()
+}
@@ -1,28 +1,32 @@
-use std::fs::create_dir;
-
-use axum::{http::StatusCode, Json};
+use axum::{extract::Extension, http::StatusCode, Json};
+use sqlx::SqlitePool;
 
 use crate::{
-    file::{CheckedPath, File, FileError},
+    file::{create_path, metadata::load_metadata, storage::Storage, CheckedPath, File, FileError},
     user::Claim,
 };
 
-/// Get folder content based on args
+/// Get folder content based on args, with metadata of media files
 pub async fn get_folder(
     CheckedPath(path): CheckedPath,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
     _: Claim,
 ) -> Result<Json<Vec<File>>, FileError> {
-    if !path.is_dir() {
+    if !storage.is_dir(&path).await {
         return Err(FileError::PathError);
     }
-    Ok(Json(File::read_dir(&path)?))
+    let mut files = File::read_dir(storage.as_ref(), &path).await?;
+    load_metadata(&pool, &path, &mut files).await?;
+    Ok(Json(files))
 }
 
 /// Create folder
 pub async fn create_folder(
     CheckedPath(path): CheckedPath,
+    Extension(storage): Extension<Storage>,
     _: Claim,
 ) -> Result<StatusCode, FileError> {
-    create_dir(path)?;
+    create_path(storage.as_ref(), &path).await?;
     Ok(StatusCode::OK)
 }
@@ -0,0 +1,276 @@
+use std::path::Path;
+
+use axum::{
+    extract::{Extension, Query},
+    Json,
+};
+use percent_encoding::utf8_percent_encode;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{
+    file::{
+        check_path, metadata::prefix_range, storage::Storage, thumbnail::is_image, FileError,
+        PATH_SET,
+    },
+    user::Claim,
+};
+
+const DEFAULT_PER_PAGE: usize = 100;
+const MAX_PER_PAGE: usize = 1000;
+/// Size of thumbnail URLs, clients may change it
+const THUMBNAIL_SIZE: u32 = 256;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GalleryArgs {
+    #[serde(default)]
+    path: String,
+    /// "day" (default) or "month"
+    group: Option<String>,
+    /// Starts from 1
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct OnThisDayArgs {
+    #[serde(default)]
+    path: String,
+    /// "MM-DD", today if not set
+    date: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GalleryItem {
+    pub path: String,
+    /// Capture time, or modified time if it's unknown
+    pub time: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<i64>,
+    /// URL of thumbnail, only images have it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<String>,
+}
+
+impl GalleryItem {
+    pub fn new(path: String, time: i64, width: Option<i64>, height: Option<i64>) -> GalleryItem {
+        let thumbnail = is_image(Path::new(&path)).then(|| {
+            format!(
+                "/api/v1/thumbnail/{}?size={}",
+                utf8_percent_encode(&path, PATH_SET),
+                THUMBNAIL_SIZE
+            )
+        });
+        GalleryItem {
+            path,
+            time,
+            width,
+            height,
+            thumbnail,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GalleryGroup {
+    /// Day, month or year, e.g. "2021-01-02"
+    key: String,
+    items: Vec<GalleryItem>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gallery {
+    /// Images in all pages
+    total: usize,
+    page: usize,
+    per_page: usize,
+    /// The last group may continue in the next page
+    groups: Vec<GalleryGroup>,
+}
+
+/// Get images in `path` and its sub folders, newest first,
+/// grouped by day or month they were taken
+pub async fn get_gallery(
+    Query(args): Query<GalleryArgs>,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    _: Claim,
+) -> Result<Json<Gallery>, FileError> {
+    let path = check_path(storage.as_ref(), &args.path)?;
+    let format = match args.group.as_deref() {
+        None | Some("day") => "%Y-%m-%d",
+        Some("month") => "%Y-%m",
+        Some(_) => return Err(FileError::ContentError),
+    };
+    let (p, low, high) = prefix_range(&path)?;
+    let rows = sqlx::query!(
+        r#"SELECT path, width, height, COALESCE(taken_at, modified) AS "time!: i64",
+        strftime(?, COALESCE(taken_at, modified), 'unixepoch') AS "key!: String"
+        FROM media_metadata WHERE ? = '' OR (path >= ? AND path < ?)
+        ORDER BY 4 DESC, path"#,
+        format,
+        p,
+        low,
+        high
+    )
+    .fetch_all(&pool)
+    .await?;
+    // Files in hidden mounts are skipped
+    let rows: Vec<_> = rows
+        .into_iter()
+        .filter(|r| is_image(Path::new(&r.path)) && storage.contains(Path::new(&r.path)))
+        .collect();
+    let page = args.page.unwrap_or(1).max(1);
+    let per_page = args
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    let total = rows.len();
+    let items = rows
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .map(|r| (r.key, GalleryItem::new(r.path, r.time, r.width, r.height)));
+    Ok(Json(Gallery {
+        total,
+        page,
+        per_page,
+        groups: group(items),
+    }))
+}
+
+/// Get images taken on the same day of previous years, grouped by year
+pub async fn get_on_this_day(
+    Query(args): Query<OnThisDayArgs>,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    _: Claim,
+) -> Result<Json<Vec<GalleryGroup>>, FileError> {
+    let path = check_path(storage.as_ref(), &args.path)?;
+    if let Some(date) = &args.date {
+        let valid = date.len() == 5
+            && date
+                .chars()
+                .enumerate()
+                .all(|(i, c)| if i == 2 { c == '-' } else { c.is_ascii_digit() });
+        if !valid {
+            return Err(FileError::ContentError);
+        }
+    }
+    let (p, low, high) = prefix_range(&path)?;
+    let rows = sqlx::query!(
+        r#"SELECT path, width, height, taken_at AS "time!: i64",
+        strftime('%Y', taken_at, 'unixepoch') AS "key!: String"
+        FROM media_metadata WHERE taken_at IS NOT NULL
+        AND strftime('%m-%d', taken_at, 'unixepoch') = COALESCE(?, strftime('%m-%d', 'now'))
+        AND strftime('%Y', taken_at, 'unixepoch') < strftime('%Y', 'now')
+        AND (? = '' OR (path >= ? AND path < ?))
+        ORDER BY taken_at DESC, path"#,
+        args.date,
+        p,
+        low,
+        high
+    )
+    .fetch_all(&pool)
+    .await?;
+    let items = rows
+        .into_iter()
+        .filter(|r| is_image(Path::new(&r.path)) && storage.contains(Path::new(&r.path)))
+        .map(|r| (r.key, GalleryItem::new(r.path, r.time, r.width, r.height)));
+    Ok(Json(group(items)))
+}
+
+/// Put sorted items with the same key into a group
+fn group(items: impl Iterator<Item = (String, GalleryItem)>) -> Vec<GalleryGroup> {
+    let mut groups: Vec<GalleryGroup> = vec![];
+    for (key, item) in items {
+        match groups.last_mut() {
+            Some(g) if g.key == key => g.items.push(item),
+            _ => groups.push(GalleryGroup {
+                key,
+                items: vec![item],
+            }),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::file::{
+        fixture::{claim, pool},
+        storage::MemoryStorage,
+    };
+
+    #[tokio::test]
+    async fn test_gallery() {
+        let pool = pool().await;
+        let storage: Storage = Arc::new(MemoryStorage::new());
+        // 2021-01-02 03:04:05, the same day, and a year before
+        let photos = [
+            ("a/1.jpg", Some(1_609_556_645)),
+            ("a/2 #.jpg", Some(1_609_556_000)),
+            ("a/b/3.png", Some(1_577_934_245)),
+            ("a/4.mp3", Some(1_609_556_645)),
+            ("c/5.jpg", None),
+        ];
+        for (path, taken_at) in photos {
+            let folder = Path::new(path).parent().unwrap().to_str().unwrap();
+            sqlx::query!(
+                "INSERT INTO media_metadata (path, folder, size, modified, taken_at)
+                VALUES (?, ?, 0, 0, ?)",
+                path,
+                folder,
+                taken_at
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        let args = GalleryArgs {
+            path: "a".into(),
+            group: None,
+            page: Some(1),
+            per_page: Some(2),
+        };
+        let Json(gallery) = get_gallery(
+            Query(args),
+            Extension(pool.clone()),
+            Extension(storage.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(gallery.total, 3);
+        assert_eq!(gallery.groups.len(), 1);
+        assert_eq!(gallery.groups[0].key, "2021-01-02");
+        let item = &gallery.groups[0].items[1];
+        assert_eq!(item.path, "a/2 #.jpg");
+        assert_eq!(
+            item.thumbnail.as_deref(),
+            Some("/api/v1/thumbnail/a/2%20%23.jpg?size=256")
+        );
+        let args = OnThisDayArgs {
+            path: String::new(),
+            date: Some("01-02".into()),
+        };
+        let Json(groups) = get_on_this_day(
+            Query(args),
+            Extension(pool.clone()),
+            Extension(storage.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        let keys: Vec<_> = groups.iter().map(|g| g.key.as_str()).collect();
+        assert_eq!(keys, ["2021", "2020"]);
+    }
+}
@@ -0,0 +1,663 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use exif::{DateTime, Exif, In, Tag, Value};
+use image::ImageReader;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use symphonia::core::{
+    formats::FormatOptions,
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    probe::Hint,
+};
+use tokio::{io::AsyncReadExt, sync::broadcast::error::RecvError};
+
+use crate::{
+    event::subscribe,
+    file::{
+        storage::{Stat, Storage, StorageBackend},
+        File, FileError,
+    },
+};
+
+const IMAGE_EXTENSIONS: [&str; 11] = [
+    "jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff", "heic", "heif", "avif",
+];
+/// ISO base media files, metadata is read from the `moov` box
+const VIDEO_EXTENSIONS: [&str; 5] = ["mp4", "m4v", "mov", "3gp", "m4a"];
+const AUDIO_EXTENSIONS: [&str; 5] = ["mp3", "flac", "wav", "ogg", "oga"];
+/// Storage without local path is read into memory, larger files are skipped
+const MAX_MEMORY_READ: u64 = 64 << 20;
+/// Only the start of small boxes like `mvhd` are needed
+const MAX_BOX_READ: u64 = 256;
+/// Seconds from 1904-01-01, the epoch of MP4 timestamps, to 1970-01-01
+const MP4_EPOCH_OFFSET: u64 = 2_082_844_800;
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaMetadata {
+    /// Capture time in unix timestamp. EXIF time is local time of camera,
+    /// it is taken as UTC so photos stay on the day they were taken
+    #[serde(skip_serializing_if = "Option::is_none")]
+    taken_at: Option<i64>,
+    /// Make and model of camera
+    #[serde(skip_serializing_if = "Option::is_none")]
+    camera: Option<String>,
+    /// Size as displayed, photos rotated by EXIF orientation are swapped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    /// Seconds of audio or video
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    /// Codecs of tracks, e.g. "avc1, mp4a"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    codec: Option<String>,
+}
+
+/// Search filters of media metadata, files without metadata don't match any of them
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaFilter {
+    taken_after: Option<i64>,
+    taken_before: Option<i64>,
+    /// Case insensitive part of camera make or model
+    camera: Option<String>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    min_duration: Option<f64>,
+    max_duration: Option<f64>,
+}
+
+impl MediaFilter {
+    pub fn matches(&self, metadata: Option<&MediaMetadata>) -> bool {
+        let none = MediaMetadata::default();
+        let m = metadata.unwrap_or(&none);
+        let camera = match (&self.camera, &m.camera) {
+            (None, _) => true,
+            (Some(c), Some(camera)) => camera.to_lowercase().contains(&c.to_lowercase()),
+            (Some(_), None) => false,
+        };
+        camera
+            && in_range(m.taken_at, self.taken_after, self.taken_before)
+            && in_range(m.width, self.min_width, None)
+            && in_range(m.height, self.min_height, None)
+            && in_range(m.duration, self.min_duration, self.max_duration)
+    }
+}
+
+/// Missing value is only in range without bounds
+fn in_range<T: PartialOrd>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+    match value {
+        Some(v) => min.is_none_or(|min| v >= min) && max.is_none_or(|max| v <= max),
+        None => min.is_none() && max.is_none(),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum MediaKind {
+    Image,
+    Video,
+    Audio,
+}
+
+fn media_kind(path: &Path) -> Option<MediaKind> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    if IMAGE_EXTENSIONS.contains(&ext.as_str()) {
+        Some(MediaKind::Image)
+    } else if VIDEO_EXTENSIONS.contains(&ext.as_str()) {
+        Some(MediaKind::Video)
+    } else if AUDIO_EXTENSIONS.contains(&ext.as_str()) {
+        Some(MediaKind::Audio)
+    } else {
+        None
+    }
+}
+
+/// Fill metadata of `files` in `folder`, changed files since indexed are skipped
+pub async fn load_metadata(
+    pool: &SqlitePool,
+    folder: &Path,
+    files: &mut [File],
+) -> Result<(), FileError> {
+    let f = path_str(folder)?;
+    let rows = sqlx::query!(
+        "SELECT path, size, modified, taken_at, camera, width, height, duration, codec
+        FROM media_metadata WHERE folder = ?",
+        f
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut found = HashMap::new();
+    for row in rows {
+        let name = match Path::new(&row.path).file_name() {
+            Some(n) => n.to_string_lossy().to_string(),
+            None => continue,
+        };
+        let metadata = MediaMetadata {
+            taken_at: row.taken_at,
+            camera: row.camera,
+            width: row.width.map(|w| w as u32),
+            height: row.height.map(|h| h as u32),
+            duration: row.duration,
+            codec: row.codec,
+        };
+        found.insert(name, (row.size as u64, row.modified as u64, metadata));
+    }
+    for file in files.iter_mut().filter(|f| f.type_ == "file") {
+        match found.remove(&file.name) {
+            Some((size, modified, metadata))
+                if size == file.size && modified == file.last_modified_time =>
+            {
+                file.metadata = Some(metadata)
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Index media files in storage at start, then keep the index updated
+/// by file events until the server stops
+pub async fn index_media(pool: SqlitePool, storage: Storage) {
+    // Subscribe before the scan, so files changed during it aren't missed
+    let mut receiver = subscribe();
+    if let Err(e) = index_tree(&pool, storage.as_ref(), Path::new("")).await {
+        tracing::warn!("failed to index media: {}", e);
+    }
+    loop {
+        let event = match receiver.recv().await {
+            Ok(e) => e,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        for path in event.paths() {
+            if let Err(e) = update(&pool, storage.as_ref(), Path::new(path)).await {
+                tracing::warn!("failed to index media {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Index changed `path`, removed files and folders are removed from index
+async fn update(
+    pool: &SqlitePool,
+    storage: &dyn StorageBackend,
+    path: &Path,
+) -> Result<(), FileError> {
+    match storage.stat(path).await {
+        Ok(stat) if stat.is_dir => index_tree(pool, storage, path).await,
+        Ok(stat) => match media_kind(path) {
+            Some(kind) => index_file(pool, storage, path, &stat, kind).await,
+            None => Ok(()),
+        },
+        Err(_) => {
+            let (p, low, high) = prefix_range(path)?;
+            sqlx::query!(
+                "DELETE FROM media_metadata WHERE path = ? OR (path >= ? AND path < ?)",
+                p,
+                low,
+                high
+            )
+            .execute(pool)
+            .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Index media files in `root` folder, unchanged files are skipped
+async fn index_tree(
+    pool: &SqlitePool,
+    storage: &dyn StorageBackend,
+    root: &Path,
+) -> Result<(), FileError> {
+    let (p, low, high) = prefix_range(root)?;
+    let rows = sqlx::query!(
+        "SELECT path, size, modified FROM media_metadata
+        WHERE ? = '' OR (path >= ? AND path < ?)",
+        p,
+        low,
+        high
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut indexed: HashMap<_, _> = rows
+        .into_iter()
+        .map(|r| (r.path, (r.size as u64, r.modified as u64)))
+        .collect();
+    let mut folders = vec![root.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let entries = match storage.list(&folder).await {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let path = folder.join(&entry.name);
+            if entry.stat.is_dir {
+                folders.push(path);
+                continue;
+            }
+            let kind = match media_kind(&path) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let indexed = indexed.remove(path_str(&path)?);
+            if indexed != Some((entry.stat.size, entry.stat.modified)) {
+                index_file(pool, storage, &path, &entry.stat, kind).await?;
+            }
+        }
+    }
+    // Files not found are removed
+    for path in indexed.keys() {
+        sqlx::query!("DELETE FROM media_metadata WHERE path = ?", path)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Extract metadata of file and save it. Files without metadata are saved too,
+/// so they aren't read again until changed
+async fn index_file(
+    pool: &SqlitePool,
+    storage: &dyn StorageBackend,
+    path: &Path,
+    stat: &Stat,
+    kind: MediaKind,
+) -> Result<(), FileError> {
+    let m = match extract(storage, path, stat, kind).await {
+        Ok(m) => m,
+        Err(e) => {
+            tracing::debug!("no metadata in {}: {}", path.display(), e);
+            MediaMetadata::default()
+        }
+    };
+    let p = path_str(path)?;
+    let folder = path_str(path.parent().unwrap_or(Path::new("")))?;
+    let (size, modified) = (stat.size as i64, stat.modified as i64);
+    let (width, height) = (m.width.map(i64::from), m.height.map(i64::from));
+    sqlx::query!(
+        "INSERT INTO media_metadata
+        (path, folder, size, modified, taken_at, camera, width, height, duration, codec)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT(path) DO UPDATE SET size = excluded.size, modified = excluded.modified,
+        taken_at = excluded.taken_at, camera = excluded.camera, width = excluded.width,
+        height = excluded.height, duration = excluded.duration, codec = excluded.codec",
+        p,
+        folder,
+        size,
+        modified,
+        m.taken_at,
+        m.camera,
+        width,
+        height,
+        m.duration,
+        m.codec
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Read metadata of media file in a blocking thread
+async fn extract(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    stat: &Stat,
+    kind: MediaKind,
+) -> io::Result<MediaMetadata> {
+    enum Source {
+        Local(PathBuf),
+        Memory(Vec<u8>),
+    }
+    let source = match storage.local_path(path) {
+        Some(local) => Source::Local(local),
+        None if stat.size > MAX_MEMORY_READ => return Err(io::ErrorKind::Unsupported.into()),
+        None => {
+            let mut data = vec![];
+            storage
+                .read(path, None)
+                .await?
+                .read_to_end(&mut data)
+                .await?;
+            Source::Memory(data)
+        }
+    };
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    tokio::task::spawn_blocking(move || {
+        let reader: Box<dyn MediaSource> = match source {
+            Source::Local(local) => Box::new(std::fs::File::open(local)?),
+            Source::Memory(data) => Box::new(Cursor::new(data)),
+        };
+        match kind {
+            MediaKind::Image => read_image(reader),
+            MediaKind::Video => read_bmff(reader),
+            MediaKind::Audio => read_audio(reader, &ext),
+        }
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// EXIF of JPEG, HEIF, PNG, WebP and TIFF, and size of images the decoder supports
+fn read_image(reader: Box<dyn MediaSource>) -> io::Result<MediaMetadata> {
+    let mut reader = BufReader::new(reader);
+    let mut metadata = MediaMetadata::default();
+    let mut rotated = false;
+    if let Ok(exif) = exif::Reader::new().read_from_container(&mut reader) {
+        metadata.taken_at = ascii(&exif, Tag::DateTimeOriginal)
+            .or_else(|| ascii(&exif, Tag::DateTime))
+            .and_then(|s| DateTime::from_ascii(s.as_bytes()).ok())
+            .and_then(|t| exif_timestamp(&t));
+        metadata.camera = match (ascii(&exif, Tag::Make), ascii(&exif, Tag::Model)) {
+            (Some(make), Some(model)) if !model.starts_with(&make) => {
+                Some(format!("{} {}", make, model))
+            }
+            (make, model) => model.or(make),
+        };
+        let uint = |tag| exif.get_field(tag, In::PRIMARY)?.value.get_uint(0);
+        metadata.width = uint(Tag::PixelXDimension);
+        metadata.height = uint(Tag::PixelYDimension);
+        // Orientations 5 to 8 are rotated by 90 degrees
+        rotated = uint(Tag::Orientation).is_some_and(|o| (5..=8).contains(&o));
+    }
+    reader.rewind()?;
+    if let Ok((width, height)) = ImageReader::new(reader)
+        .with_guessed_format()?
+        .into_dimensions()
+    {
+        (metadata.width, metadata.height) = (Some(width), Some(height));
+    }
+    if rotated {
+        (metadata.width, metadata.height) = (metadata.height, metadata.width);
+    }
+    Ok(metadata)
+}
+
+/// Trimmed ASCII field of the main image
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(v) => {
+            let s = String::from_utf8_lossy(v.first()?);
+            let s = s.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!s.is_empty()).then(|| s.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn exif_timestamp(t: &DateTime) -> Option<i64> {
+    if !(1..=12).contains(&t.month) || !(1..=31).contains(&t.day) {
+        return None;
+    }
+    let days = days_from_civil(t.year.into(), t.month.into(), t.day.into());
+    Some(days * 86400 + i64::from(t.hour) * 3600 + i64::from(t.minute) * 60 + i64::from(t.second))
+}
+
+/// Days since 1970-01-01 of a date in proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Duration, creation time, video size and codecs from the `moov` box of MP4 and QuickTime
+fn read_bmff(mut reader: Box<dyn MediaSource>) -> io::Result<MediaMetadata> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let (start, end) =
+        find_box(&mut reader, 0, len, &[b"moov"])?.ok_or(io::ErrorKind::InvalidData)?;
+    let mut metadata = MediaMetadata::default();
+    let mut codecs: Vec<String> = vec![];
+    for (kind, start, end) in child_boxes(&mut reader, start, end)? {
+        match &kind {
+            b"mvhd" => {
+                let b = box_body(&mut reader, start, end)?;
+                let (created, timescale, duration) = match b.first() {
+                    Some(1) => (be(&b, 4, 8), be(&b, 20, 4), be(&b, 24, 8)),
+                    _ => (be(&b, 4, 4), be(&b, 12, 4), be(&b, 16, 4)),
+                };
+                if timescale > 0 && duration > 0 {
+                    metadata.duration = Some(duration as f64 / timescale as f64);
+                }
+                // Many cameras leave it zero
+                if created > MP4_EPOCH_OFFSET {
+                    metadata.taken_at = Some((created - MP4_EPOCH_OFFSET) as i64);
+                }
+            }
+            b"trak" => {
+                let (width, height) = match find_box(&mut reader, start, end, &[b"tkhd"])? {
+                    Some((s, e)) => {
+                        let b = box_body(&mut reader, s, e)?;
+                        let offset = if b.first() == Some(&1) { 88 } else { 76 };
+                        // 16.16 fixed point
+                        (be(&b, offset, 4) >> 16, be(&b, offset + 4, 4) >> 16)
+                    }
+                    None => (0, 0),
+                };
+                let handler = match find_box(&mut reader, start, end, &[b"mdia", b"hdlr"])? {
+                    Some((s, e)) => box_body(&mut reader, s, e)?.get(8..12).map(|h| h.to_vec()),
+                    None => None,
+                };
+                if !matches!(handler.as_deref(), Some(b"vide") | Some(b"soun")) {
+                    continue;
+                }
+                if handler.as_deref() == Some(b"vide") && width > 0 && height > 0 {
+                    metadata.width = Some(width as u32);
+                    metadata.height = Some(height as u32);
+                }
+                let stsd = [b"mdia", b"minf", b"stbl", b"stsd"];
+                if let Some((s, e)) = find_box(&mut reader, start, end, &stsd)? {
+                    // Format of the first sample entry
+                    if let Some(f) = box_body(&mut reader, s, e)?.get(12..16) {
+                        codecs.push(String::from_utf8_lossy(f).trim().to_string());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    metadata.codec = (!codecs.is_empty()).then(|| codecs.join(", "));
+    Ok(metadata)
+}
+
+/// Boxes in `start..end` as (type, body start, end)
+fn child_boxes<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+) -> io::Result<Vec<([u8; 4], u64, u64)>> {
+    let mut boxes = vec![];
+    let mut pos = start;
+    while pos + 8 <= end {
+        reader.seek(SeekFrom::Start(pos))?;
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let (body, size) = match be(&header, 0, 4) {
+            // 64 bits size follows the type
+            1 => {
+                let mut size = [0; 8];
+                reader.read_exact(&mut size)?;
+                (pos + 16, u64::from_be_bytes(size))
+            }
+            // Box extends to the end
+            0 => (pos + 8, end - pos),
+            size => (pos + 8, size),
+        };
+        // Sizes are read from the file, so broken ones must not move back
+        let next = match pos.checked_add(size) {
+            Some(next) if size >= body - pos && next > pos && next <= end => next,
+            _ => break,
+        };
+        boxes.push((kind, body, next));
+        pos = next;
+    }
+    Ok(boxes)
+}
+
+/// Find box by the types of it and its ancestors in `start..end`
+fn find_box<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    path: &[&[u8; 4]],
+) -> io::Result<Option<(u64, u64)>> {
+    let (mut start, mut end) = (start, end);
+    for kind in path {
+        match child_boxes(reader, start, end)?
+            .into_iter()
+            .find(|b| &b.0 == *kind)
+        {
+            Some((_, s, e)) => (start, end) = (s, e),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some((start, end)))
+}
+
+fn box_body<R: Read + Seek>(reader: &mut R, start: u64, end: u64) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(start))?;
+    let mut body = vec![];
+    reader
+        .by_ref()
+        .take((end - start).min(MAX_BOX_READ))
+        .read_to_end(&mut body)?;
+    Ok(body)
+}
+
+/// Big endian unsigned integer of `len` bytes at `offset`, zero if out of bounds
+fn be(data: &[u8], offset: usize, len: usize) -> u64 {
+    data.get(offset..offset + len)
+        .map(|b| b.iter().fold(0, |n, b| n << 8 | u64::from(*b)))
+        .unwrap_or(0)
+}
+
+/// Duration and codec of the default track of audio file
+fn read_audio(reader: Box<dyn MediaSource>, ext: &str) -> io::Result<MediaMetadata> {
+    let stream = MediaSourceStream::new(reader, Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(ext);
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(io::Error::other)?;
+    let params = &probed
+        .format
+        .default_track()
+        .ok_or(io::ErrorKind::InvalidData)?
+        .codec_params;
+    let duration = match (params.time_base, params.n_frames, params.sample_rate) {
+        (Some(base), Some(frames), _) => {
+            let time = base.calc_time(frames);
+            Some(time.seconds as f64 + time.frac)
+        }
+        (None, Some(frames), Some(rate)) => Some(frames as f64 / f64::from(rate)),
+        _ => None,
+    };
+    let codec = symphonia::default::get_codecs()
+        .get_codec(params.codec)
+        .map(|c| c.short_name.to_string());
+    Ok(MediaMetadata {
+        duration,
+        codec,
+        ..Default::default()
+    })
+}
+
+/// Path, and the range of paths inside it
+pub fn prefix_range(path: &Path) -> io::Result<(&str, String, String)> {
+    let p = path_str(path)?;
+    // '0' follows '/'
+    Ok((p, format!("{}/", p), format!("{}0", p)))
+}
+
+fn path_str(path: &Path) -> io::Result<&str> {
+    path.to_str().ok_or(io::ErrorKind::InvalidInput.into())
+}
+
+#[cfg(test)]
+mod test {
+    use image::{ImageFormat, RgbImage};
+
+    use super::*;
+
+    /// Box of `kind` with `body`, which may be child boxes
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut b = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(kind);
+        b.extend_from_slice(body);
+        b
+    }
+
+    #[test]
+    fn test_media_metadata() {
+        // Video of 1920x1080, 10 seconds, created at 2021-01-02 03:04:05
+        let mut mvhd = vec![0; 100];
+        mvhd[4..8].copy_from_slice(&((1_609_556_645 + MP4_EPOCH_OFFSET) as u32).to_be_bytes());
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&10_000u32.to_be_bytes());
+        let mut tkhd = vec![0; 84];
+        tkhd[76..80].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+        tkhd[80..84].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+        let mut hdlr = vec![0; 24];
+        hdlr[8..12].copy_from_slice(b"vide");
+        let mut stsd = vec![0; 16];
+        stsd[12..16].copy_from_slice(b"avc1");
+        let stbl = mp4_box(b"stbl", &mp4_box(b"stsd", &stsd));
+        let minf = mp4_box(b"minf", &stbl);
+        let mdia = mp4_box(b"mdia", &[mp4_box(b"hdlr", &hdlr), minf].concat());
+        let trak = mp4_box(b"trak", &[mp4_box(b"tkhd", &tkhd), mdia].concat());
+        let moov = mp4_box(b"moov", &[mp4_box(b"mvhd", &mvhd), trak].concat());
+        let data = [mp4_box(b"ftyp", b"isom"), mp4_box(b"mdat", &[0; 64]), moov].concat();
+        let metadata = read_bmff(Box::new(Cursor::new(data))).unwrap();
+        assert_eq!(metadata.taken_at, Some(1_609_556_645));
+        assert_eq!((metadata.width, metadata.height), (Some(1920), Some(1080)));
+        assert_eq!(metadata.duration, Some(10.0));
+        assert_eq!(metadata.codec.as_deref(), Some("avc1"));
+
+        let time = DateTime::from_ascii(b"2021:01:02 03:04:05").unwrap();
+        assert_eq!(exif_timestamp(&time), Some(1_609_556_645));
+        let mut png = vec![];
+        RgbImage::new(40, 20)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let image = read_image(Box::new(Cursor::new(png))).unwrap();
+        assert_eq!((image.width, image.height), (Some(40), Some(20)));
+
+        let filter = MediaFilter {
+            taken_after: Some(1_600_000_000),
+            min_width: Some(1280),
+            ..Default::default()
+        };
+        assert!(filter.matches(Some(&metadata)));
+        assert!(!filter.matches(Some(&image)));
+        assert!(!filter.matches(None));
+        assert!(MediaFilter::default().matches(None));
+    }
+
+    #[test]
+    fn test_oversized_box() {
+        // 64 bits size overflows the position of the box after it
+        let mut data = mp4_box(b"free", b"");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(&(u64::MAX - 7).to_be_bytes());
+        let boxes = child_boxes(&mut Cursor::new(&data), 0, data.len() as u64).unwrap();
+        assert_eq!(boxes, vec![(*b"free", 8, 8)]);
+        assert!(read_bmff(Box::new(Cursor::new(data))).is_err());
+    }
+}
@@ -1,23 +1,57 @@
+pub mod access;
+pub mod album;
+pub mod checksum;
+pub mod dav;
+pub mod dedupe;
+pub mod du;
 pub mod file;
+#[cfg(test)]
+pub mod fixture;
 pub mod folder;
+pub mod gallery;
+pub mod metadata;
+pub mod quota;
+pub mod render;
+pub mod s3;
+pub mod sftp;
 pub mod share;
+pub mod storage;
+pub mod thumbnail;
+pub mod tracking;
 
-use std::fs::{canonicalize, metadata, read_dir};
 use std::io;
-use std::path::PathBuf;
-use std::time::SystemTime;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
 use axum::extract::multipart::MultipartError;
-use axum::extract::{FromRequestParts, Path};
+use axum::extract::{self, FromRequestParts, MatchedPath};
 use axum::http::request::Parts;
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use axum::{async_trait, Json};
-use percent_encoding::percent_decode_str;
+use percent_encoding::{percent_decode_str, AsciiSet, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sqlx::SqlitePool;
+use tokio::io::AsyncRead;
+
+use crate::{
+    event::{publish, EventType, FileEvent},
+    user::Claim,
+    CONFIG,
+};
+use metadata::MediaMetadata;
+use quota::{QuotaExceeded, QuotaStorage};
+use storage::{role_storage, Entry, Stat, Storage, StorageBackend};
 
-use crate::CONFIG;
+/// Characters escaped when path is put in URL, '/' separates folders
+pub const PATH_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -30,61 +64,75 @@ pub struct File {
     /// Indicate the absolute path of the file
     #[serde(skip_serializing_if = "Option::is_none")]
     absolute_path: Option<String>,
+    /// Hex encoded SHA-256, recorded when file is written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// EXIF and container metadata of photos, audio and video
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<MediaMetadata>,
 }
 
 impl File {
     /// Get the file infomation from `path`
-    fn new(path: &PathBuf) -> Result<File, FileError> {
-        let meta = metadata(path)?;
+    async fn new(storage: &dyn StorageBackend, path: &Path) -> Result<File, FileError> {
+        // Root of storage has no name
         let name = match path.file_name() {
-            Some(s) => match s.to_str() {
-                Some(s) => s.to_string(),
-                None => return Err(FileError::PathError),
-            },
-            None => return Err(FileError::PathError),
-        };
-        let last_modified_time = match meta.modified() {
-            Ok(m) => match m.duration_since(SystemTime::UNIX_EPOCH) {
-                Ok(m) => m.as_secs(),
-                Err(_) => 0,
-            },
-            Err(_) => 0,
+            Some(s) => s.to_str().ok_or(FileError::PathError)?.to_string(),
+            None => String::new(),
         };
-        Ok(File {
+        let stat = storage.stat(path).await?;
+        let mut file = File::from_stat(name, &stat);
+        file.sha256 = storage.sha256(path).await;
+        Ok(file)
+    }
+
+    fn from_stat(name: String, stat: &Stat) -> File {
+        File {
             name,
-            size: meta.len(),
-            type_: if meta.is_dir() { "folder" } else { "file" }.into(),
-            last_modified_time,
+            size: stat.size,
+            type_: if stat.is_dir { "folder" } else { "file" }.into(),
+            last_modified_time: stat.modified,
             absolute_path: None,
-        })
+            sha256: None,
+            metadata: None,
+        }
     }
 
     /// Add "absolute" folder `path` to file
-    fn absolute_path(mut self, path: &PathBuf) -> Option<Self> {
-        let mut abs_path = if path.starts_with(&CONFIG.folder_path) {
-            path.strip_prefix(&CONFIG.folder_path).ok()?.to_path_buf()
-        } else {
-            return None;
-        };
-        abs_path.push(&self.name);
+    fn absolute_path(mut self, path: &Path) -> Option<Self> {
+        let abs_path = path.join(&self.name);
         self.absolute_path = Some(abs_path.to_str()?.to_string());
         Some(self)
     }
 
     /// Get the information of file in the `path` folder
-    fn read_dir(path: &PathBuf) -> Result<Vec<File>, FileError> {
-        let files: Vec<_> = read_dir(path)?
-            .filter_map(|rd| rd.ok())
-            .filter_map(|v| File::new(&v.path()).ok())
-            .collect();
+    async fn read_dir(storage: &dyn StorageBackend, path: &Path) -> Result<Vec<File>, FileError> {
+        let mut files = vec![];
+        for entry in storage.list(path).await? {
+            let sha256 = match entry.stat.is_dir {
+                true => None,
+                false => storage.sha256(&path.join(&entry.name)).await,
+            };
+            let mut file = File::from(entry);
+            file.sha256 = sha256;
+            files.push(file);
+        }
         Ok(files)
     }
 }
 
+impl From<Entry> for File {
+    fn from(entry: Entry) -> Self {
+        File::from_stat(entry.name, &entry.stat)
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum FileError {
     #[error("Io Error")]
-    IoError(#[from] io::Error),
+    IoError(io::Error),
+    #[error("Quota exceeded")]
+    QuotaExceeded(u64),
     #[error("Path Error")]
     PathError,
     #[error("Upload Error")]
@@ -95,12 +143,79 @@ pub enum FileError {
     DatabaseError(#[from] sqlx::Error),
     #[error("Server error")]
     ServerError,
+    #[error("File has been changed")]
+    PreconditionFailed,
+    #[error("If-Match is required")]
+    PreconditionRequired,
+    #[error("Share has expired")]
+    Expired,
+    #[error("Download limit reached")]
+    DownloadLimitReached,
+    #[error("Wrong password")]
+    WrongPassword,
+    #[error("Too many attempts")]
+    TooManyAttempts(u64),
+    #[error("Permission denied")]
+    Forbidden,
+    #[error("File is too large")]
+    TooLarge,
+    #[error("Shared file has been removed")]
+    Removed,
+}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> Self {
+        match QuotaExceeded::remaining(&e) {
+            Some(remaining) => FileError::QuotaExceeded(remaining),
+            None => FileError::IoError(e),
+        }
+    }
+}
+
+impl FileError {
+    /// Status code of error response
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FileError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            FileError::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+            FileError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            FileError::PreconditionRequired => StatusCode::PRECONDITION_REQUIRED,
+            FileError::Expired | FileError::DownloadLimitReached | FileError::Removed => {
+                StatusCode::GONE
+            }
+            FileError::WrongPassword => StatusCode::UNAUTHORIZED,
+            FileError::Forbidden => StatusCode::FORBIDDEN,
+            FileError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
 }
 
 impl IntoResponse for FileError {
     fn into_response(self) -> axum::response::Response {
+        let status = self.status();
+        if let FileError::QuotaExceeded(remaining) = self {
+            return (
+                status,
+                Json(json!({
+                    "error": self.to_string(),
+                    "remaining": remaining
+                })),
+            )
+                .into_response();
+        }
+        if let FileError::TooManyAttempts(wait) = self {
+            return (
+                status,
+                [(header::RETRY_AFTER, wait.to_string())],
+                Json(json!({
+                    "error": self.to_string()
+                })),
+            )
+                .into_response();
+        }
         (
-            StatusCode::BAD_REQUEST,
+            status,
             Json(json!({
                 "error": self.to_string()
             })),
@@ -111,6 +226,8 @@ impl IntoResponse for FileError {
 
 #[derive(Deserialize)]
 pub struct QueryArgs {
+    /// Part of name, all files match if it's empty
+    #[serde(default)]
     name: String,
 }
 
@@ -119,7 +236,7 @@ pub struct RenameArgs {
     to: String,
 }
 
-/// Path Extractor with check
+/// Path Extractor with check, the path is relative to the root of storage
 pub struct CheckedPath(PathBuf);
 
 #[async_trait]
@@ -130,70 +247,162 @@ where
     type Rejection = FileError;
 
     async fn from_request_parts(req: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let path = req.uri.path();
-        let path = if path.starts_with("/files/") && path != "/files/" {
-            // use extractor to extract relative path of `/files`
-            let Path(p) = Path::<String>::from_request_parts(req, state)
-                .await
-                .map_err(|_| FileError::PathError)?;
-            p
-        } else if path == "/files/" {
+        // Only routes have `MatchedPath`, nested services like `/dav` don't
+        let matched = req.extensions.get::<MatchedPath>().map(|m| m.as_str());
+        let path = match matched {
+            Some(m) if m.ends_with("/*path") => {
+                // use extractor to extract relative path of `/files`
+                let extract::Path(p) = extract::Path::<String>::from_request_parts(req, state)
+                    .await
+                    .map_err(|_| FileError::PathError)?;
+                p
+            }
             // In axum 0.6, Path extractor cannot extract "/files/", so we return "." directly
-            String::from(".")
-        } else {
-            // `/file` path contains relative path starts with '/'
-            percent_decode_str(path)
+            Some(m) if m.ends_with('/') => String::from("."),
+            // Nested service gets relative path starts with '/'
+            _ => percent_decode_str(req.uri.path())
                 .decode_utf8()
                 .map_err(|_| FileError::PathError)?
-                .to_string()
+                .to_string(),
         };
-        // Concat and check path is valid
-        let path = concat_path_str(&path);
-        if is_traversal(&path) {
-            return Err(FileError::PathError);
+        let storage = req
+            .extensions
+            .get::<Storage>()
+            .ok_or(FileError::ServerError)?;
+        Ok(CheckedPath(check_path(storage.as_ref(), &path)?))
+    }
+}
+
+/// Convert path in request to the path relative to the root of storage,
+/// then detect path traversal
+pub fn check_path(storage: &dyn StorageBackend, s: &str) -> Result<PathBuf, FileError> {
+    let mut path = PathBuf::new();
+    for c in Path::new(s).components() {
+        match c {
+            Component::Normal(p) => path.push(p),
+            Component::RootDir | Component::CurDir => (),
+            // ".." and "C:"
+            _ => return Err(FileError::PathError),
+        }
+    }
+    if !storage.contains(&path) {
+        return Err(FileError::PathError);
+    }
+    Ok(path)
+}
+
+/// Get path relative to the storage folder, e.g. "folder/file".
+/// Path in a mount starts with the name of mount
+pub fn relative_path(path: &Path) -> Option<PathBuf> {
+    if CONFIG.mounts.is_empty() {
+        return Some(path.strip_prefix(&CONFIG.folder_path).ok()?.to_path_buf());
+    }
+    CONFIG.mounts.iter().find_map(|m| {
+        let rest = path.strip_prefix(&m.path).ok()?;
+        Some(Path::new(&m.name).join(rest))
+    })
+}
+
+/// Storage seen and written by the user, with hidden mounts and quota applied
+pub fn storage_for_user(
+    storage: &Storage,
+    pool: &SqlitePool,
+    username: &str,
+    role: &str,
+) -> Storage {
+    let storage = role_storage(storage, role);
+    if !quota::enabled() {
+        return storage;
+    }
+    Arc::new(QuotaStorage::new(
+        storage,
+        pool.clone(),
+        username,
+        CONFIG.user_quota,
+        CONFIG.folder_quotas.clone(),
+    ))
+}
+
+/// Replace `Storage` of request with the one seen by the logged in user
+pub async fn user_storage<B>(claim: Option<Claim>, mut req: Request<B>, next: Next<B>) -> Response {
+    if let Some(claim) = claim {
+        let extensions = req.extensions();
+        if let (Some(storage), Some(pool)) =
+            (extensions.get::<Storage>(), extensions.get::<SqlitePool>())
+        {
+            let storage = storage_for_user(storage, pool, &claim.username, &claim.role);
+            req.extensions_mut().insert(storage);
         }
-        Ok(CheckedPath(path))
     }
+    next.run(req).await
+}
+
+/// Delete file or empty folder.
+/// Every frontend writes through these functions, so they stay consistent
+pub async fn remove_path(storage: &dyn StorageBackend, path: &Path) -> io::Result<()> {
+    storage.delete(path).await?;
+    publish(FileEvent::new(EventType::Delete, path));
+    Ok(())
 }
 
-/// Concat `s` to base path
-fn concat_path_str(s: &String) -> PathBuf {
-    let mut path = CONFIG.folder_path.clone();
-    // AVOID ANTI-PATTEN `path.push` by triming '/' in the beginning of the path
-    path.push(&PathBuf::from(s.trim_start_matches('/')));
-    path
+/// Rename or move file/folder
+pub async fn rename_path(storage: &dyn StorageBackend, from: &Path, to: &Path) -> io::Result<()> {
+    storage.rename(from, to).await?;
+    publish(FileEvent::rename(from, to));
+    Ok(())
 }
 
-/// Detect path traversal
-/// Because of the anti-patten path.push, we should use this function when we join paths
-fn is_traversal(path: &PathBuf) -> bool {
-    let abs_path = match canonicalize(path) {
-        Ok(p) => p,
-        Err(_) => match path.exists() {
-            // Path not exist doesn't means it is illegal
-            false => path.into(),
-            true => return true,
-        },
+/// Create folder
+pub async fn create_path(storage: &dyn StorageBackend, path: &Path) -> io::Result<()> {
+    storage.mkdir(path).await?;
+    publish(FileEvent::new(EventType::Create, path));
+    Ok(())
+}
+
+/// Create or replace file with `data`
+pub async fn write_path(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    data: &mut (dyn AsyncRead + Send + Unpin),
+) -> io::Result<u64> {
+    let existed = storage.exists(path).await;
+    let size = storage.write(path, data).await?;
+    file_written(path, !existed);
+    Ok(size)
+}
+
+/// Notify that content of file is written
+pub fn file_written(path: &Path, created: bool) {
+    let type_ = if created {
+        EventType::Create
+    } else {
+        EventType::Update
     };
-    !abs_path.starts_with(&CONFIG.folder_path)
+    publish(FileEvent::new(type_, path));
 }
 
 #[cfg(test)]
 mod test {
+    use super::storage::LocalStorage;
     use super::*;
 
     #[test]
     fn test_traversal() {
-        assert_eq!(is_traversal(&PathBuf::from("files/test_file")), false);
-        assert_eq!(is_traversal(&PathBuf::from("src")), true);
-        assert_eq!(is_traversal(&PathBuf::from("/etc/passwd")), true);
+        let storage = LocalStorage::new("files".into());
+        assert!(check_path(&storage, "test_file").is_ok());
+        assert!(check_path(&storage, "/test_folder/../test_file").is_err());
+        assert!(check_path(&storage, "../src").is_err());
+        assert_eq!(
+            check_path(&storage, "/test_folder/./a").unwrap(),
+            PathBuf::from("test_folder/a")
+        );
     }
 
-    #[test]
-    fn test_file_struct() {
-        let file = File::new(&PathBuf::from("files/test_folder")).unwrap();
-        let abs_path = canonicalize(&PathBuf::from("files")).unwrap();
-        let file = file.absolute_path(&abs_path).unwrap();
+    #[tokio::test]
+    async fn test_file_struct() {
+        let storage = LocalStorage::new("files".into());
+        let file = File::new(&storage, Path::new("test_folder")).await.unwrap();
+        let file = file.absolute_path(Path::new("")).unwrap();
         assert_eq!(file.absolute_path, Some("test_folder".to_string()));
     }
 }
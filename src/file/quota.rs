@@ -0,0 +1,585 @@
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use axum::{async_trait, extract::Extension, Json};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{
+    config::FolderQuota,
+    file::{
+        storage::{role_storage, Entry, Reader, Stat, Storage, StorageBackend},
+        FileError,
+    },
+    user::Claim,
+    CONFIG,
+};
+
+/// Write that exceeds quota, carried by `io::Error` through storage
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    /// Bytes that can still be written
+    pub remaining: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "quota exceeded, {} bytes remaining", self.remaining)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+impl QuotaExceeded {
+    fn error(remaining: u64) -> io::Error {
+        io::Error::other(QuotaExceeded { remaining })
+    }
+
+    /// Get remaining bytes if `e` is caused by quota
+    pub fn remaining(e: &io::Error) -> Option<u64> {
+        let quota = e.get_ref()?.downcast_ref::<QuotaExceeded>()?;
+        Some(quota.remaining)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    used: u64,
+    /// `None` if unlimited
+    quota: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct FolderUsage {
+    path: String,
+    used: u64,
+    quota: u64,
+}
+
+#[derive(Serialize)]
+pub struct UsageIndex {
+    user: Usage,
+    folders: Vec<FolderUsage>,
+}
+
+/// Check if any quota is configured
+pub fn enabled() -> bool {
+    CONFIG.user_quota.is_some() || !CONFIG.folder_quotas.is_empty()
+}
+
+/// Get used bytes and quota of current user and the folders with quota
+pub async fn get_usage(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    claim: Claim,
+) -> Result<Json<UsageIndex>, FileError> {
+    let user = Usage {
+        used: user_used(&pool, &claim.username).await?,
+        quota: CONFIG.user_quota,
+    };
+    let mut folders = vec![];
+    for folder in &CONFIG.folder_quotas {
+        // Folders in hidden mounts are skipped
+        if !storage.exists(&folder.path).await {
+            continue;
+        }
+        folders.push(FolderUsage {
+            path: folder.path.to_str().unwrap_or_default().to_string(),
+            used: folder_used(&pool, &folder.path).await?,
+            quota: folder.size,
+        });
+    }
+    Ok(Json(UsageIndex { user, folders }))
+}
+
+/// Storage written by one user, counts used bytes and rejects writes over quota
+pub struct QuotaStorage {
+    inner: Storage,
+    pool: SqlitePool,
+    username: String,
+    user_quota: Option<u64>,
+    folder_quotas: Vec<FolderQuota>,
+}
+
+impl QuotaStorage {
+    pub fn new(
+        inner: Storage,
+        pool: SqlitePool,
+        username: &str,
+        user_quota: Option<u64>,
+        folder_quotas: Vec<FolderQuota>,
+    ) -> QuotaStorage {
+        QuotaStorage {
+            inner,
+            pool,
+            username: username.to_string(),
+            user_quota,
+            folder_quotas,
+        }
+    }
+
+    /// Quota folders containing `path`
+    fn folders<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a FolderQuota> {
+        self.folder_quotas
+            .iter()
+            .filter(move |f| path.starts_with(&f.path))
+    }
+
+    async fn size_of(&self, path: &Path) -> u64 {
+        match self.inner.stat(path).await {
+            Ok(s) if !s.is_dir => s.size,
+            _ => 0,
+        }
+    }
+
+    /// Update used bytes after file at `path` is changed from `old_size` to `size`
+    async fn record(&self, path: &Path, old_size: u64, size: u64) -> io::Result<()> {
+        if self.user_quota.is_some() {
+            let (p, size) = (path_str(path)?, size as i64);
+            sqlx::query!(
+                "INSERT INTO file_owner (path, username, size) VALUES (?, ?, ?)
+                ON CONFLICT(path) DO UPDATE SET username = excluded.username, size = excluded.size",
+                p,
+                self.username,
+                size
+            )
+            .execute(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+        }
+        self.add_used(path, size as i64 - old_size as i64).await
+    }
+
+    /// Update used bytes after file of `size` at `path` is deleted
+    async fn forget(&self, path: &Path, size: u64) -> io::Result<()> {
+        let p = path_str(path)?;
+        sqlx::query!("DELETE FROM file_owner WHERE path = ?", p)
+            .execute(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+        self.add_used(path, -(size as i64)).await
+    }
+
+    async fn add_used(&self, path: &Path, delta: i64) -> io::Result<()> {
+        for folder in self.folders(path) {
+            let p = path_str(&folder.path)?;
+            sqlx::query!(
+                "UPDATE folder_usage SET used = max(used + ?, 0) WHERE path = ?",
+                delta,
+                p
+            )
+            .execute(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reader that fails with `QuotaExceeded` when data is longer than `max`
+struct LimitedReader<'a> {
+    inner: &'a mut (dyn AsyncRead + Send + Unpin),
+    max: u64,
+    read: u64,
+}
+
+impl AsyncRead for LimitedReader<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, buf))?;
+        this.read += (buf.filled().len() - filled) as u64;
+        if this.read > this.max {
+            // Nothing is read when it fails
+            buf.set_filled(filled);
+            return Poll::Ready(Err(QuotaExceeded::error(this.max)));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for QuotaStorage {
+    fn contains(&self, path: &Path) -> bool {
+        self.inner.contains(path)
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        self.inner.local_path(path)
+    }
+
+    fn writable(&self, path: &Path) -> bool {
+        self.inner.writable(path)
+    }
+
+    /// Quota is added again by `storage_for_user`
+    fn for_role(&self, role: &str) -> Option<Storage> {
+        Some(role_storage(&self.inner, role))
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        self.inner.list(path).await
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<Stat> {
+        self.inner.stat(path).await
+    }
+
+    async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<Reader> {
+        self.inner.read(path, range).await
+    }
+
+    /// Data over quota fails the write before it completes, so the old content is kept
+    async fn write(
+        &self,
+        path: &Path,
+        data: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<u64> {
+        let old_size = self.size_of(path).await;
+        let size = match self.max_size(path).await? {
+            Some(max) => {
+                let mut limited = LimitedReader {
+                    inner: data,
+                    max,
+                    read: 0,
+                };
+                self.inner.write(path, &mut limited).await?
+            }
+            None => self.inner.write(path, data).await?,
+        };
+        self.record(path, old_size, size).await?;
+        Ok(size)
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        // Only the folders which `to` enters or `from` leaves are changed
+        let entered: Vec<_> = self
+            .folders(to)
+            .filter(|f| !from.starts_with(&f.path))
+            .collect();
+        let left: Vec<_> = self
+            .folders(from)
+            .filter(|f| !to.starts_with(&f.path))
+            .collect();
+        let size = match entered.is_empty() && left.is_empty() {
+            true => 0,
+            false => folder_size(self.inner.as_ref(), from).await?,
+        };
+        // File at `to` is replaced
+        let replaced = self.size_of(to).await;
+        for folder in &entered {
+            let used = folder_used(&self.pool, &folder.path)
+                .await
+                .map_err(io::Error::other)?;
+            if used + size > folder.size + replaced {
+                let remaining = (folder.size + replaced).saturating_sub(used);
+                return Err(QuotaExceeded::error(remaining));
+            }
+        }
+        self.inner.rename(from, to).await?;
+        let mut tx = self.pool.begin().await.map_err(io::Error::other)?;
+        let mut changes: Vec<_> = self.folders(to).map(|f| (f, -(replaced as i64))).collect();
+        changes.extend(left.into_iter().map(|f| (f, -(size as i64))));
+        changes.extend(entered.into_iter().map(|f| (f, size as i64)));
+        for (folder, delta) in changes {
+            let p = path_str(&folder.path)?;
+            sqlx::query!(
+                "UPDATE folder_usage SET used = max(used + ?, 0) WHERE path = ?",
+                delta,
+                p
+            )
+            .execute(&mut tx)
+            .await
+            .map_err(io::Error::other)?;
+        }
+        // Owned files replaced at `to` aren't counted anymore
+        let (from, to) = (path_str(from)?, path_str(to)?);
+        let to_prefix = format!("{}/", to);
+        let to_prefix_len = to_prefix.chars().count() as i64;
+        sqlx::query!(
+            "DELETE FROM file_owner WHERE path = ? OR substr(path, 1, ?) = ?",
+            to,
+            to_prefix_len,
+            to_prefix
+        )
+        .execute(&mut tx)
+        .await
+        .map_err(io::Error::other)?;
+        // Owned files inside renamed folder are moved too
+        let prefix = format!("{}/", from);
+        let start = from.chars().count() as i64 + 1;
+        let prefix_len = prefix.chars().count() as i64;
+        sqlx::query!(
+            "UPDATE file_owner SET path = ? || substr(path, ?)
+            WHERE path = ? OR substr(path, 1, ?) = ?",
+            to,
+            start,
+            from,
+            prefix_len,
+            prefix
+        )
+        .execute(&mut tx)
+        .await
+        .map_err(io::Error::other)?;
+        tx.commit().await.map_err(io::Error::other)
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let size = self.size_of(from).await;
+        if let Some(max) = self.max_size(to).await? {
+            if size > max {
+                return Err(QuotaExceeded::error(max));
+            }
+        }
+        let old_size = self.size_of(to).await;
+        let size = self.inner.copy(from, to).await?;
+        self.record(to, old_size, size).await?;
+        Ok(size)
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        let size = self.size_of(path).await;
+        self.inner.delete(path).await?;
+        self.forget(path, size).await
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn sha256(&self, path: &Path) -> Option<String> {
+        self.inner.sha256(path).await
+    }
+
+    async fn max_size(&self, path: &Path) -> io::Result<Option<u64>> {
+        // Size of the replaced file is given back
+        let old_size = self.size_of(path).await;
+        let mut max = None;
+        if let Some(quota) = self.user_quota {
+            let used = user_used(&self.pool, &self.username)
+                .await
+                .map_err(io::Error::other)?;
+            let p = path_str(path)?;
+            let owned = sqlx::query!(
+                "SELECT size FROM file_owner WHERE path = ? AND username = ?",
+                p,
+                self.username
+            )
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(io::Error::other)?
+            .map(|r| r.size as u64)
+            .unwrap_or(0);
+            max = Some((quota + owned).saturating_sub(used));
+        }
+        for folder in self.folders(path) {
+            let used = folder_used(&self.pool, &folder.path)
+                .await
+                .map_err(io::Error::other)?;
+            let remaining = (folder.size + old_size).saturating_sub(used);
+            max = Some(max.map_or(remaining, |m: u64| m.min(remaining)));
+        }
+        Ok(max)
+    }
+
+    async fn written(&self, path: &Path, old_size: u64) -> io::Result<()> {
+        self.inner.written(path, old_size).await?;
+        let size = self.size_of(path).await;
+        self.record(path, old_size, size).await
+    }
+}
+
+/// Total size of files in folder, or size of file
+pub async fn folder_size(storage: &dyn StorageBackend, path: &Path) -> io::Result<u64> {
+    let stat = storage.stat(path).await?;
+    if !stat.is_dir {
+        return Ok(stat.size);
+    }
+    let (mut size, mut folders) = (0, vec![path.to_path_buf()]);
+    while let Some(folder) = folders.pop() {
+        for entry in storage.list(&folder).await? {
+            match entry.stat.is_dir {
+                true => folders.push(folder.join(&entry.name)),
+                false => size += entry.stat.size,
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// Recount used bytes of quota folders, and sizes of owned files.
+/// Changes made outside of file-station are counted here
+pub async fn scan_usage(
+    pool: &SqlitePool,
+    storage: &dyn StorageBackend,
+    folders: &[FolderQuota],
+) -> Result<(), FileError> {
+    for folder in folders {
+        let used = folder_size(storage, &folder.path).await.unwrap_or(0) as i64;
+        let p = path_str(&folder.path)?;
+        sqlx::query!(
+            "INSERT INTO folder_usage (path, used) VALUES (?, ?)
+            ON CONFLICT(path) DO UPDATE SET used = excluded.used",
+            p,
+            used
+        )
+        .execute(pool)
+        .await?;
+    }
+    let owned = sqlx::query!("SELECT path, size FROM file_owner")
+        .fetch_all(pool)
+        .await?;
+    for row in owned {
+        match storage.stat(Path::new(&row.path)).await {
+            Ok(s) if s.is_dir => (),
+            Ok(s) if s.size as i64 == row.size => continue,
+            Ok(s) => {
+                let size = s.size as i64;
+                sqlx::query!(
+                    "UPDATE file_owner SET size = ? WHERE path = ?",
+                    size,
+                    row.path
+                )
+                .execute(pool)
+                .await?;
+                continue;
+            }
+            Err(_) => (),
+        }
+        sqlx::query!("DELETE FROM file_owner WHERE path = ?", row.path)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Rescan usage at start, then every `FS_QUOTA_SCAN` seconds
+pub async fn scan_loop(pool: SqlitePool, storage: Storage) {
+    let interval = Duration::from_secs(CONFIG.quota_scan_interval.max(1));
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = scan_usage(&pool, storage.as_ref(), &CONFIG.folder_quotas).await {
+            tracing::warn!("failed to scan usage: {}", e);
+        }
+    }
+}
+
+async fn user_used(pool: &SqlitePool, username: &str) -> Result<u64, sqlx::Error> {
+    let used = sqlx::query!(
+        "SELECT COALESCE(SUM(size), 0) AS \"used!: i64\" FROM file_owner WHERE username = ?",
+        username
+    )
+    .fetch_one(pool)
+    .await?
+    .used;
+    Ok(used as u64)
+}
+
+async fn folder_used(pool: &SqlitePool, path: &Path) -> Result<u64, sqlx::Error> {
+    let p = path.to_str().unwrap_or_default();
+    let used = sqlx::query!("SELECT used FROM folder_usage WHERE path = ?", p)
+        .fetch_optional(pool)
+        .await?
+        .map(|r| r.used)
+        .unwrap_or(0);
+    Ok(used as u64)
+}
+
+fn path_str(path: &Path) -> io::Result<&str> {
+    path.to_str().ok_or(io::ErrorKind::InvalidInput.into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::file::{fixture::pool, storage::MemoryStorage};
+
+    #[tokio::test]
+    async fn test_quota() {
+        let pool = pool().await;
+        let inner: Storage = Arc::new(MemoryStorage::new());
+        inner.mkdir(Path::new("a")).await.unwrap();
+        let folders = vec![FolderQuota {
+            path: "a".into(),
+            size: 8,
+        }];
+        let storage =
+            QuotaStorage::new(inner.clone(), pool.clone(), "u", Some(10), folders.clone());
+        storage
+            .write(Path::new("b"), &mut &b"123456"[..])
+            .await
+            .unwrap();
+        assert_eq!(user_used(&pool, "u").await.unwrap(), 6);
+        // Only 4 bytes left for user
+        let e = storage
+            .write(Path::new("a/c"), &mut &b"12345"[..])
+            .await
+            .unwrap_err();
+        assert_eq!(QuotaExceeded::remaining(&e), Some(4));
+        assert!(!inner.exists(Path::new("a/c")).await);
+        // Data longer than the size checked before writing keeps the old file
+        let e = storage
+            .write(Path::new("b"), &mut &b"12345678901"[..])
+            .await
+            .unwrap_err();
+        assert_eq!(QuotaExceeded::remaining(&e), Some(10));
+        let mut content = vec![];
+        let mut reader = inner.read(Path::new("b"), None).await.unwrap();
+        tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, b"123456");
+        assert_eq!(user_used(&pool, "u").await.unwrap(), 6);
+        // Replaced file is given back
+        storage
+            .write(Path::new("b"), &mut &b"1234567890"[..])
+            .await
+            .unwrap();
+        storage.delete(Path::new("b")).await.unwrap();
+        assert_eq!(user_used(&pool, "u").await.unwrap(), 0);
+        scan_usage(&pool, inner.as_ref(), &folders).await.unwrap();
+        storage
+            .write(Path::new("a/c"), &mut &b"12345"[..])
+            .await
+            .unwrap();
+        // Folder "a" has 3 bytes left
+        let e = storage
+            .copy(Path::new("a/c"), Path::new("a/d"))
+            .await
+            .unwrap_err();
+        assert_eq!(QuotaExceeded::remaining(&e), Some(3));
+        storage
+            .rename(Path::new("a/c"), Path::new("c"))
+            .await
+            .unwrap();
+        assert_eq!(folder_used(&pool, Path::new("a")).await.unwrap(), 0);
+        assert_eq!(user_used(&pool, "u").await.unwrap(), 5);
+        // Owned file replaced by rename isn't counted anymore
+        storage
+            .write(Path::new("a/d"), &mut &b"123"[..])
+            .await
+            .unwrap();
+        storage
+            .rename(Path::new("c"), Path::new("a/d"))
+            .await
+            .unwrap();
+        assert_eq!(folder_used(&pool, Path::new("a")).await.unwrap(), 5);
+        assert_eq!(user_used(&pool, "u").await.unwrap(), 5);
+        storage.delete(Path::new("a/d")).await.unwrap();
+        assert_eq!(folder_used(&pool, Path::new("a")).await.unwrap(), 0);
+        assert_eq!(user_used(&pool, "u").await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,325 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use lazy_static::lazy_static;
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use syntect::{
+    highlighting::ThemeSet,
+    html::highlighted_html_for_string,
+    parsing::{SyntaxReference, SyntaxSet},
+};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    file::{
+        storage::{Storage, StorageBackend},
+        CheckedPath, FileError, PATH_SET,
+    },
+    user::Claim,
+    CONFIG,
+};
+
+const MARKDOWN_EXTENSIONS: [&str; 3] = ["md", "markdown", "mdown"];
+const THEME: &str = "InspiredGitHub";
+/// CSS properties kept in `style`, which are used by highlighted code
+const STYLE_PROPERTIES: [&str; 5] = [
+    "color",
+    "background-color",
+    "font-weight",
+    "font-style",
+    "text-decoration",
+];
+
+/// Makes URL of a file linked in Markdown, `None` if it can't be linked
+pub type LinkFn = Box<dyn Fn(&Path) -> Option<String> + Send + Sync>;
+
+lazy_static! {
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref THEMES: ThemeSet = ThemeSet::load_defaults();
+    static ref SANITIZER: ammonia::Builder<'static> = {
+        let mut builder = ammonia::Builder::default();
+        builder
+            .add_tag_attributes("pre", &["style"])
+            .add_tag_attributes("span", &["style"])
+            .attribute_filter(|_, attribute, value| match attribute {
+                "style" if !safe_style(value) => None,
+                _ => Some(Cow::Borrowed(value)),
+            });
+        builder
+    };
+    static ref CACHE: Mutex<RenderCache> = Mutex::new(RenderCache::default());
+}
+
+/// Rendered HTML, the oldest ones are removed when it's full
+#[derive(Default)]
+struct RenderCache {
+    entries: HashMap<String, Arc<String>>,
+    order: VecDeque<String>,
+    size: u64,
+}
+
+impl RenderCache {
+    fn get(&self, key: &str) -> Option<Arc<String>> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: String, html: Arc<String>) {
+        let len = html.len() as u64;
+        if len > CONFIG.render_cache_size || self.entries.contains_key(&key) {
+            return;
+        }
+        while self.size + len > CONFIG.render_cache_size {
+            match self.order.pop_front() {
+                Some(old) => {
+                    if let Some(h) = self.entries.remove(&old) {
+                        self.size -= h.len() as u64;
+                    }
+                }
+                None => break,
+            }
+        }
+        self.size += len;
+        self.order.push_back(key.clone());
+        self.entries.insert(key, html);
+    }
+}
+
+/// Get file rendered to HTML, Markdown is sanitized and source code is highlighted.
+/// Relative links in Markdown point to `/api/v1/file/`
+pub async fn render_file(
+    CheckedPath(path): CheckedPath,
+    Extension(storage): Extension<Storage>,
+    headers: HeaderMap,
+    _: Claim,
+) -> Result<Response, FileError> {
+    let link: LinkFn = Box::new(|p| {
+        let p = p.to_str()?;
+        Some(format!("/api/v1/file/{}", utf8_percent_encode(p, PATH_SET)))
+    });
+    render_response(storage.as_ref(), &path, &headers, "", link).await
+}
+
+/// Cached HTML of `path`. `scope` separates HTML of the same file rendered with other links
+pub async fn render_response(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+    scope: &str,
+    link: LinkFn,
+) -> Result<Response, FileError> {
+    let stat = storage.stat(path).await?;
+    if stat.is_dir {
+        return Err(FileError::PathError);
+    }
+    if stat.size > CONFIG.render_max_size {
+        return Err(FileError::ContentError);
+    }
+    let etag = format!("\"{}-{}\"", stat.modified, stat.size);
+    let none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    if none_match == Some(etag.as_str()) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    let key = format!("{}\0{}\0{}", scope, path.display(), etag);
+    let cached = CACHE.lock().unwrap().get(&key);
+    let html = match cached {
+        Some(html) => html,
+        None => {
+            let mut data = vec![];
+            storage
+                .read(path, None)
+                .await?
+                .take(CONFIG.render_max_size)
+                .read_to_end(&mut data)
+                .await?;
+            // Binary files aren't rendered
+            let text = String::from_utf8(data).map_err(|_| FileError::ContentError)?;
+            let path = path.to_path_buf();
+            let html = tokio::task::spawn_blocking(move || render(&text, &path, &link))
+                .await
+                .map_err(|_| FileError::ServerError)?;
+            let html = Arc::new(html);
+            CACHE.lock().unwrap().insert(key, html.clone());
+            html
+        }
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+            (header::ETAG, etag),
+        ],
+        html.to_string(),
+    )
+        .into_response())
+}
+
+fn render(text: &str, path: &Path, link: &LinkFn) -> String {
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if MARKDOWN_EXTENSIONS.contains(&ext.as_str()) {
+        return render_markdown(text, path.parent().unwrap_or(Path::new("")), link);
+    }
+    let first_line = text.lines().next().unwrap_or_default();
+    let syntax = SYNTAXES
+        .find_syntax_by_extension(&ext)
+        .or_else(|| SYNTAXES.find_syntax_by_first_line(first_line))
+        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+    highlight(text, syntax)
+}
+
+/// Markdown in `folder` to sanitized HTML, code blocks are highlighted
+fn render_markdown(text: &str, folder: &Path, link: &LinkFn) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_SMART_PUNCTUATION;
+    let mut events = vec![];
+    // Language and content of the code block being read
+    let mut code: Option<(String, String)> = None;
+    for event in Parser::new_ext(text, options) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(lang) => lang.to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((lang, String::new()));
+            }
+            Event::Text(text) if code.is_some() => {
+                if let Some((_, c)) = &mut code {
+                    c.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                if let Some((lang, c)) = code.take() {
+                    let syntax = SYNTAXES
+                        .find_syntax_by_token(&lang)
+                        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+                    events.push(Event::Html(highlight(&c, syntax).into()));
+                }
+            }
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => {
+                let dest_url = resolve_link(&dest_url, folder, link).map_or(dest_url, Into::into);
+                events.push(Event::Start(Tag::Link {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                }));
+            }
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => {
+                let dest_url = resolve_link(&dest_url, folder, link).map_or(dest_url, Into::into);
+                events.push(Event::Start(Tag::Image {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                }));
+            }
+            e => events.push(e),
+        }
+    }
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    SANITIZER.clean(&html).to_string()
+}
+
+/// URL of relative link `dest` in `folder`, `None` for other links
+/// and paths out of storage
+fn resolve_link(dest: &str, folder: &Path, link: &LinkFn) -> Option<String> {
+    if dest.is_empty() || dest.starts_with(['#', '/', '?']) {
+        return None;
+    }
+    let end = dest.find(['#', '?']).unwrap_or(dest.len());
+    let (target, rest) = dest.split_at(end);
+    // Links with scheme like "https:" and "mailto:"
+    if target.split('/').next().is_some_and(|s| s.contains(':')) {
+        return None;
+    }
+    let target = percent_decode_str(target).decode_utf8().ok()?;
+    let mut path = folder.to_path_buf();
+    for c in Path::new(target.as_ref()).components() {
+        match c {
+            Component::Normal(p) => path.push(p),
+            Component::CurDir => (),
+            Component::ParentDir if path.pop() => (),
+            _ => return None,
+        }
+    }
+    let url = link(&path)?;
+    // Query of URL is used by the link itself
+    match rest.strip_prefix('#') {
+        Some(fragment) => Some(format!("{}#{}", url, fragment)),
+        None => Some(url),
+    }
+}
+
+fn highlight(code: &str, syntax: &SyntaxReference) -> String {
+    highlighted_html_for_string(code, &SYNTAXES, syntax, &THEMES.themes[THEME])
+        .unwrap_or_else(|_| format!("<pre>{}</pre>", ammonia::clean_text(code)))
+}
+
+/// Style made by highlighter, like "color:#323232;font-weight:bold;"
+fn safe_style(style: &str) -> bool {
+    style
+        .split(';')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .all(|declaration| match declaration.split_once(':') {
+            Some((property, value)) => {
+                STYLE_PROPERTIES.contains(&property.trim())
+                    && value
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '#' | ' ' | '-'))
+            }
+            None => false,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let link: LinkFn = Box::new(|p| Some(format!("/f/{}", p.display())));
+        let markdown = "[a](b.md#x) ![i](../img/c%20d.png) [w](https://a.com) [s](javascript:alert(1))\n\
+            [up](../../../x)\n\n<script>alert(1)</script><span style=\"position:fixed\">p</span>\n\n\
+            ```rust\nfn main() {}\n```\n";
+        let html = render(markdown, Path::new("docs/a/README.md"), &link);
+        assert!(html.contains(r#"href="/f/docs/a/b.md#x""#));
+        assert!(html.contains(r#"src="/f/docs/img/c%20d.png""#));
+        assert!(html.contains(r#"href="https://a.com""#));
+        assert!(html.contains(r#"href="../../../x""#));
+        assert!(!html.contains("javascript"));
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("position"));
+        assert!(html.contains("<span style=\"color:"));
+        let html = render("<b>x</b>", Path::new("a.html"), &link);
+        assert!(html.contains("&lt;") && !html.contains("<b>"));
+        assert!(safe_style("color:#323232;font-weight:bold;"));
+        assert!(!safe_style("background:url(x)"));
+    }
+}
@@ -0,0 +1,1287 @@
+use std::collections::HashMap;
+use std::env::temp_dir;
+use std::fs::create_dir_all;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{Extension, Query},
+    http::{header, request::Parts, HeaderMap, Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::{escape::escape, events::Event, Reader};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use tokio::{
+    fs::{read_dir, remove_dir_all, remove_file, File as AsyncFile},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use crate::{
+    event::{publish, EventType, FileEvent},
+    file::{
+        check_path,
+        file::{file_etag, file_response},
+        file_written,
+        quota::QuotaExceeded,
+        remove_path,
+        storage::{Stat, Storage, StorageBackend},
+        storage_for_user, File, FileError,
+    },
+    user::{get_role, get_unix_timestamp, Claim},
+};
+
+/// SHA-256 of empty string, used in chunk signature
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+/// Allowed difference between request time and server time, in seconds
+const MAX_SKEW: u64 = 15 * 60;
+/// Max seconds presigned URLs are valid, same as AWS
+const MAX_EXPIRES: u64 = 7 * 24 * 60 * 60;
+const MAX_KEYS: usize = 1000;
+/// Staging files and multipart uploads untouched for longer are removed
+const STAGING_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const CLEAN_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_PART_NUMBER: u32 = 10000;
+/// Max size of a single aws-chunked chunk
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+/// Max size of XML request body
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// Escape everything except unreserved characters, as SigV4 requires
+const S3_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>";
+const XML_NS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum S3Error {
+    #[error("Access Denied")]
+    AccessDenied,
+    #[error("The AWS access key Id you provided does not exist in our records.")]
+    InvalidAccessKeyId,
+    #[error("The request signature we calculated does not match the signature you provided.")]
+    SignatureDoesNotMatch,
+    #[error("The difference between the request time and the server's time is too large.")]
+    RequestTimeTooSkewed,
+    #[error("The specified bucket does not exist.")]
+    NoSuchBucket,
+    #[error("The specified key does not exist.")]
+    NoSuchKey,
+    #[error("The specified multipart upload does not exist.")]
+    NoSuchUpload,
+    #[error("Invalid Argument")]
+    InvalidArgument,
+    #[error("One or more of the specified parts could not be found.")]
+    InvalidPart,
+    #[error("The list of parts was not in ascending order.")]
+    InvalidPartOrder,
+    #[error("The XML you provided was not well-formed.")]
+    MalformedXML,
+    #[error("The provided 'x-amz-content-sha256' header does not match what was computed.")]
+    XAmzContentSHA256Mismatch,
+    #[error("A header you provided implies functionality that is not implemented.")]
+    NotImplemented,
+    #[error("Io Error")]
+    IoError(#[from] io::Error),
+    #[error("Database error")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl IntoResponse for S3Error {
+    fn into_response(self) -> Response {
+        let (status, code) = match &self {
+            S3Error::AccessDenied => (StatusCode::FORBIDDEN, "AccessDenied"),
+            S3Error::InvalidAccessKeyId => (StatusCode::FORBIDDEN, "InvalidAccessKeyId"),
+            S3Error::SignatureDoesNotMatch => (StatusCode::FORBIDDEN, "SignatureDoesNotMatch"),
+            S3Error::RequestTimeTooSkewed => (StatusCode::FORBIDDEN, "RequestTimeTooSkewed"),
+            S3Error::NoSuchBucket => (StatusCode::NOT_FOUND, "NoSuchBucket"),
+            S3Error::NoSuchKey => (StatusCode::NOT_FOUND, "NoSuchKey"),
+            S3Error::NoSuchUpload => (StatusCode::NOT_FOUND, "NoSuchUpload"),
+            S3Error::InvalidArgument => (StatusCode::BAD_REQUEST, "InvalidArgument"),
+            S3Error::InvalidPart => (StatusCode::BAD_REQUEST, "InvalidPart"),
+            S3Error::InvalidPartOrder => (StatusCode::BAD_REQUEST, "InvalidPartOrder"),
+            S3Error::MalformedXML => (StatusCode::BAD_REQUEST, "MalformedXML"),
+            S3Error::XAmzContentSHA256Mismatch => {
+                (StatusCode::BAD_REQUEST, "XAmzContentSHA256Mismatch")
+            }
+            S3Error::NotImplemented => (StatusCode::NOT_IMPLEMENTED, "NotImplemented"),
+            S3Error::IoError(e) if e.kind() == io::ErrorKind::NotFound => {
+                (StatusCode::NOT_FOUND, "NoSuchKey")
+            }
+            S3Error::IoError(e) if QuotaExceeded::remaining(e).is_some() => {
+                (StatusCode::INSUFFICIENT_STORAGE, "QuotaExceeded")
+            }
+            S3Error::IoError(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                (StatusCode::FORBIDDEN, "AccessDenied")
+            }
+            S3Error::IoError(_) | S3Error::DatabaseError(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "InternalError")
+            }
+        };
+        let body = format!(
+            "{}<Error><Code>{}</Code><Message>{}</Message></Error>",
+            XML_HEADER,
+            code,
+            escape(self.to_string())
+        );
+        xml_response(status, body)
+    }
+}
+
+impl From<FileError> for S3Error {
+    fn from(e: FileError) -> Self {
+        match e {
+            FileError::IoError(e) => S3Error::IoError(e),
+            FileError::QuotaExceeded(remaining) => {
+                S3Error::IoError(io::Error::other(QuotaExceeded { remaining }))
+            }
+            _ => S3Error::NoSuchKey,
+        }
+    }
+}
+
+/// Signature of a verified request, chunks of streaming upload are signed with it
+struct Signature {
+    username: String,
+    key: Vec<u8>,
+    date: String,
+    scope: String,
+    signature: String,
+    /// Value of `x-amz-content-sha256`
+    payload: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct S3Key {
+    access_key: String,
+    /// Secret key is only shown when it is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct S3KeyArgs {
+    access_key: String,
+}
+
+/// Object in bucket listing
+struct Object {
+    key: String,
+    size: u64,
+    last_modified_time: u64,
+    /// The same as ETag of `GET`
+    etag: String,
+}
+
+/// Create access key for current user
+pub async fn add_s3_key(
+    Extension(db): Extension<SqlitePool>,
+    claim: Claim,
+) -> Result<Json<S3Key>, FileError> {
+    let access_key = format!("FS{}", random_string(18).to_ascii_uppercase());
+    let secret_key = random_string(40);
+    sqlx::query!(
+        "INSERT INTO s3_key (access_key, secret_key, username) VALUES (?, ?, ?)",
+        access_key,
+        secret_key,
+        claim.username
+    )
+    .execute(&db)
+    .await?;
+    Ok(Json(S3Key {
+        access_key,
+        secret_key: Some(secret_key),
+    }))
+}
+
+/// Get access keys of current user
+pub async fn get_s3_keys(
+    Extension(db): Extension<SqlitePool>,
+    claim: Claim,
+) -> Result<Json<Vec<S3Key>>, FileError> {
+    let keys = sqlx::query!(
+        "SELECT access_key FROM s3_key WHERE username = ?",
+        claim.username
+    )
+    .fetch_all(&db)
+    .await?
+    .into_iter()
+    .map(|r| S3Key {
+        access_key: r.access_key,
+        secret_key: None,
+    })
+    .collect();
+    Ok(Json(keys))
+}
+
+/// Delete access key of current user
+pub async fn delete_s3_key(
+    Query(args): Query<S3KeyArgs>,
+    Extension(db): Extension<SqlitePool>,
+    claim: Claim,
+) -> Result<StatusCode, FileError> {
+    sqlx::query!(
+        "DELETE FROM s3_key WHERE access_key = ? AND username = ?",
+        args.access_key,
+        claim.username
+    )
+    .execute(&db)
+    .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Entry of S3 requests, path style `/bucket/key` is used
+pub async fn s3(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    req: Request<Body>,
+) -> Result<Response, S3Error> {
+    let (parts, body) = req.into_parts();
+    let sig = authenticate(&pool, &parts).await?;
+    let role = get_role(&pool, &sig.username)
+        .await
+        .map_err(|_| S3Error::InvalidAccessKeyId)?;
+    let storage = storage_for_user(&storage, &pool, &sig.username, &role);
+    let storage = storage.as_ref();
+    let query = parse_query(parts.uri.query().unwrap_or(""));
+    let path = percent_decode_str(parts.uri.path())
+        .decode_utf8()
+        .map_err(|_| S3Error::InvalidArgument)?
+        .to_string();
+    let (bucket, key) = path
+        .trim_start_matches('/')
+        .split_once('/')
+        .unwrap_or((path.trim_start_matches('/'), ""));
+    let method = parts.method.as_str();
+    if bucket.is_empty() {
+        return match method {
+            "GET" => list_buckets(storage, &sig).await,
+            _ => Err(S3Error::NotImplemented),
+        };
+    }
+    // Bucket must be a folder in the top level
+    let bucket_path = check_path(storage, bucket).map_err(|_| S3Error::NoSuchBucket)?;
+    if bucket_path.components().count() != 1 {
+        return Err(S3Error::NoSuchBucket);
+    }
+    let is_bucket = storage.is_dir(&bucket_path).await;
+    if key.is_empty() {
+        return match method {
+            "PUT" => create_bucket(storage, &bucket_path).await,
+            _ if !is_bucket => Err(S3Error::NoSuchBucket),
+            "HEAD" => Ok(StatusCode::OK.into_response()),
+            "GET" if query.contains_key("location") => Ok(xml_response(
+                StatusCode::OK,
+                format!("{}<LocationConstraint xmlns=\"{}\"/>", XML_HEADER, XML_NS),
+            )),
+            "GET" if query.get("list-type").map(|t| t.as_str()) == Some("2") => {
+                list_objects(storage, bucket, &query).await
+            }
+            _ => Err(S3Error::NotImplemented),
+        };
+    }
+    if !is_bucket {
+        return Err(S3Error::NoSuchBucket);
+    }
+    let path =
+        check_path(storage, &format!("{}/{}", bucket, key)).map_err(|_| S3Error::AccessDenied)?;
+    match (method, query.get("uploadId")) {
+        ("POST", None) if query.contains_key("uploads") => {
+            create_multipart(&pool, &sig, bucket, key).await
+        }
+        ("PUT", Some(id)) => {
+            let number = query
+                .get("partNumber")
+                .and_then(|n| n.parse().ok())
+                .filter(|n| (1..=MAX_PART_NUMBER).contains(n))
+                .ok_or(S3Error::InvalidArgument)?;
+            upload_part(&pool, &sig, bucket, key, id, number, &parts.headers, body).await
+        }
+        ("POST", Some(id)) => {
+            complete_multipart(&pool, storage, &sig, bucket, key, id, &path, body).await
+        }
+        ("DELETE", Some(id)) => abort_multipart(&pool, &sig, bucket, key, id).await,
+        ("GET", None) | ("HEAD", None) => get_object(storage, key, &path, &parts.headers).await,
+        ("PUT", None) => put_object(storage, &sig, key, &path, &parts.headers, body).await,
+        ("DELETE", None) => delete_object(storage, key, &path).await,
+        _ => Err(S3Error::NotImplemented),
+    }
+}
+
+/// Verify AWS Signature Version 4 in `Authorization` header or presigned URL
+async fn authenticate(pool: &SqlitePool, parts: &Parts) -> Result<Signature, S3Error> {
+    let query = parse_query(parts.uri.query().unwrap_or(""));
+    let now = get_unix_timestamp();
+    let (credential, signed_headers, signature, date, payload) =
+        if let Some(auth) = get_header(&parts.headers, "authorization") {
+            let auth = auth
+                .strip_prefix("AWS4-HMAC-SHA256 ")
+                .ok_or(S3Error::AccessDenied)?;
+            let fields: HashMap<&str, &str> = auth
+                .split(',')
+                .filter_map(|f| f.trim().split_once('='))
+                .collect();
+            let field = |name| fields.get(name).map(|f| f.to_string());
+            let date = get_header(&parts.headers, "x-amz-date").ok_or(S3Error::AccessDenied)?;
+            let time = parse_amz_date(date).ok_or(S3Error::AccessDenied)?;
+            if time.abs_diff(now) > MAX_SKEW {
+                return Err(S3Error::RequestTimeTooSkewed);
+            }
+            let payload = get_header(&parts.headers, "x-amz-content-sha256")
+                .ok_or(S3Error::InvalidArgument)?;
+            (
+                field("Credential").ok_or(S3Error::AccessDenied)?,
+                field("SignedHeaders").ok_or(S3Error::AccessDenied)?,
+                field("Signature").ok_or(S3Error::AccessDenied)?,
+                date.to_string(),
+                payload.to_string(),
+            )
+        } else if query.get("X-Amz-Algorithm").map(|a| a.as_str()) == Some("AWS4-HMAC-SHA256") {
+            let field = |name| query.get(name).cloned().ok_or(S3Error::AccessDenied);
+            let date = field("X-Amz-Date")?;
+            let time = parse_amz_date(&date).ok_or(S3Error::AccessDenied)?;
+            let expires: u64 = field("X-Amz-Expires")?
+                .parse()
+                .map_err(|_| S3Error::AccessDenied)?;
+            if expires > MAX_EXPIRES {
+                return Err(S3Error::AccessDenied);
+            }
+            let expires_at = time.checked_add(expires).ok_or(S3Error::AccessDenied)?;
+            if now > expires_at || time > now + MAX_SKEW {
+                return Err(S3Error::AccessDenied);
+            }
+            (
+                field("X-Amz-Credential")?,
+                field("X-Amz-SignedHeaders")?,
+                field("X-Amz-Signature")?,
+                date,
+                "UNSIGNED-PAYLOAD".to_string(),
+            )
+        } else {
+            return Err(S3Error::AccessDenied);
+        };
+    let (access_key, scope) = credential.split_once('/').ok_or(S3Error::AccessDenied)?;
+    let result = sqlx::query!(
+        "SELECT secret_key, username FROM s3_key WHERE access_key = ?",
+        access_key
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(S3Error::InvalidAccessKeyId)?;
+    let key = signing_key(&result.secret_key, scope).ok_or(S3Error::AccessDenied)?;
+    let canonical = canonical_request(parts, &signed_headers, &payload)?;
+    let expected = hex::encode(hmac(&key, &string_to_sign(&date, scope, &canonical)));
+    if !constant_time_eq(&expected, &signature) {
+        return Err(S3Error::SignatureDoesNotMatch);
+    }
+    Ok(Signature {
+        username: result.username,
+        key,
+        date,
+        scope: scope.to_string(),
+        signature,
+        payload,
+    })
+}
+
+fn canonical_request(
+    parts: &Parts,
+    signed_headers: &str,
+    payload: &str,
+) -> Result<String, S3Error> {
+    let path = percent_decode_str(parts.uri.path())
+        .decode_utf8()
+        .map_err(|_| S3Error::InvalidArgument)?;
+    let uri = path
+        .split('/')
+        .map(|s| utf8_percent_encode(s, S3_SET).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    let mut query: Vec<(String, String)> = parse_query(parts.uri.query().unwrap_or(""))
+        .into_iter()
+        .filter(|(k, _)| k != "X-Amz-Signature")
+        .map(|(k, v)| {
+            (
+                utf8_percent_encode(&k, S3_SET).to_string(),
+                utf8_percent_encode(&v, S3_SET).to_string(),
+            )
+        })
+        .collect();
+    query.sort();
+    let query: Vec<String> = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    let headers: String = signed_headers
+        .split(';')
+        .map(|name| {
+            let values: Vec<String> = parts
+                .headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .map(|v| v.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect();
+            format!("{}:{}\n", name, values.join(","))
+        })
+        .collect();
+    Ok(format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        parts.method.as_str(),
+        uri,
+        query.join("&"),
+        headers,
+        signed_headers,
+        payload
+    ))
+}
+
+fn string_to_sign(date: &str, scope: &str, canonical: &str) -> String {
+    format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        date,
+        scope,
+        hex::encode(Sha256::digest(canonical.as_bytes()))
+    )
+}
+
+/// Derive signing key from secret and scope "date/region/service/aws4_request"
+fn signing_key(secret: &str, scope: &str) -> Option<Vec<u8>> {
+    let mut parts = scope.split('/');
+    let (date, region, service) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next()? != "aws4_request" {
+        return None;
+    }
+    let key = hmac(format!("AWS4{}", secret).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    Some(hmac(&key, "aws4_request"))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Signature of a chunk in `aws-chunked` payload
+fn chunk_signature(sig: &Signature, previous: &str, data: &[u8]) -> String {
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+        sig.date,
+        sig.scope,
+        previous,
+        EMPTY_SHA256,
+        hex::encode(Sha256::digest(data))
+    );
+    hex::encode(hmac(&sig.key, &string_to_sign))
+}
+
+async fn list_buckets(storage: &dyn StorageBackend, sig: &Signature) -> Result<Response, S3Error> {
+    let buckets: String = File::read_dir(storage, Path::new(""))
+        .await
+        .map_err(|_| S3Error::NoSuchBucket)?
+        .iter()
+        .filter(|f| f.type_ == "folder")
+        .map(|f| {
+            format!(
+                "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+                escape(f.name.as_str()),
+                iso8601(f.last_modified_time)
+            )
+        })
+        .collect();
+    let owner = escape(sig.username.as_str());
+    Ok(xml_response(
+        StatusCode::OK,
+        format!(
+            "{}<ListAllMyBucketsResult xmlns=\"{}\"><Owner><ID>{}</ID><DisplayName>{}</DisplayName></Owner>\
+            <Buckets>{}</Buckets></ListAllMyBucketsResult>",
+            XML_HEADER, XML_NS, owner, owner, buckets
+        ),
+    ))
+}
+
+async fn create_bucket(
+    storage: &dyn StorageBackend,
+    bucket_path: &Path,
+) -> Result<Response, S3Error> {
+    create_folders(storage, bucket_path).await?;
+    Ok(StatusCode::OK.into_response())
+}
+
+/// ListObjectsV2, folders are walked as keys separated by '/'
+async fn list_objects(
+    storage: &dyn StorageBackend,
+    bucket: &str,
+    query: &HashMap<String, String>,
+) -> Result<Response, S3Error> {
+    let get = |name: &str| query.get(name).map(|v| v.as_str());
+    let prefix = get("prefix").unwrap_or("");
+    let delimiter = get("delimiter").filter(|d| !d.is_empty());
+    let max_keys = match get("max-keys") {
+        Some(m) => m.parse().map_err(|_| S3Error::InvalidArgument)?,
+        None => MAX_KEYS,
+    }
+    .min(MAX_KEYS);
+    let start = get("continuation-token").or(get("start-after"));
+    let encode = |s: &str| match get("encoding-type") {
+        Some("url") => utf8_percent_encode(s, S3_SET).to_string(),
+        _ => escape(s).to_string(),
+    };
+    // Only the folder containing `prefix` need to be walked
+    let base_key = match prefix.rfind('/') {
+        Some(i) => &prefix[..=i],
+        None => "",
+    };
+    let mut objects = vec![];
+    if let Ok(base) = check_path(storage, &format!("{}/{}", bucket, base_key)) {
+        if storage.is_dir(&base).await {
+            let shallow = delimiter == Some("/");
+            walk(storage, &base, base_key, shallow, &mut objects).await?;
+        }
+    }
+    objects.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let (mut contents, mut prefixes) = (String::new(), vec![]);
+    let (mut count, mut next, mut truncated) = (0, None, false);
+    for obj in objects.iter().filter(|o| o.key.starts_with(prefix)) {
+        // Keys contain delimiter after prefix are rolled up into common prefix
+        let common = delimiter.and_then(|d| {
+            let i = obj.key[prefix.len()..].find(d)?;
+            Some(obj.key[..prefix.len() + i + d.len()].to_string())
+        });
+        let item = common.as_deref().unwrap_or(&obj.key);
+        if start.is_some_and(|s| item <= s) || prefixes.last() == Some(&item.to_string()) {
+            continue;
+        }
+        if count == max_keys {
+            truncated = true;
+            break;
+        }
+        count += 1;
+        next = Some(item.to_string());
+        match common {
+            Some(c) => prefixes.push(c),
+            None => contents.push_str(&format!(
+                "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag>\
+                <Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                encode(&obj.key),
+                iso8601(obj.last_modified_time),
+                escape(obj.etag.as_str()),
+                obj.size
+            )),
+        }
+    }
+    let prefixes: String = prefixes
+        .iter()
+        .map(|p| {
+            format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                encode(p)
+            )
+        })
+        .collect();
+    let mut result = format!(
+        "<Name>{}</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount><MaxKeys>{}</MaxKeys>\
+        <IsTruncated>{}</IsTruncated>",
+        escape(bucket),
+        encode(prefix),
+        count,
+        max_keys,
+        truncated
+    );
+    if let Some(d) = delimiter {
+        result.push_str(&format!("<Delimiter>{}</Delimiter>", encode(d)));
+    }
+    if get("encoding-type") == Some("url") {
+        result.push_str("<EncodingType>url</EncodingType>");
+    }
+    if let Some(t) = get("continuation-token") {
+        result.push_str(&format!(
+            "<ContinuationToken>{}</ContinuationToken>",
+            escape(t)
+        ));
+    }
+    if let Some(s) = get("start-after") {
+        result.push_str(&format!("<StartAfter>{}</StartAfter>", encode(s)));
+    }
+    if let (true, Some(n)) = (truncated, next) {
+        result.push_str(&format!(
+            "<NextContinuationToken>{}</NextContinuationToken>",
+            escape(n.as_str())
+        ));
+    }
+    Ok(xml_response(
+        StatusCode::OK,
+        format!(
+            "{}<ListBucketResult xmlns=\"{}\">{}{}{}</ListBucketResult>",
+            XML_HEADER, XML_NS, result, contents, prefixes
+        ),
+    ))
+}
+
+/// Collect objects in `folder`, folders are listed as "key/".
+/// With `shallow`, members of sub folders are skipped
+async fn walk(
+    storage: &dyn StorageBackend,
+    folder: &Path,
+    key: &str,
+    shallow: bool,
+    objects: &mut Vec<Object>,
+) -> io::Result<()> {
+    let mut folders = vec![(folder.to_path_buf(), key.to_string())];
+    while let Some((folder, key)) = folders.pop() {
+        let files = File::read_dir(storage, &folder)
+            .await
+            .map_err(|_| io::ErrorKind::NotFound)?;
+        for f in files {
+            let stat = Stat {
+                is_dir: f.type_ == "folder",
+                size: f.size,
+                modified: f.last_modified_time,
+            };
+            let mut object = Object {
+                key: format!("{}{}", key, f.name),
+                size: f.size,
+                last_modified_time: f.last_modified_time,
+                etag: file_etag(f.sha256.as_deref(), &stat),
+            };
+            if !stat.is_dir {
+                objects.push(object);
+                continue;
+            }
+            object.key.push('/');
+            object.size = 0;
+            let path = folder.join(&f.name);
+            let empty = storage.list(&path).await?.is_empty();
+            if shallow || empty {
+                objects.push(object);
+            } else {
+                folders.push((path, object.key));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn get_object(
+    storage: &dyn StorageBackend,
+    key: &str,
+    path: &Path,
+    headers: &HeaderMap,
+) -> Result<Response, S3Error> {
+    let stat = storage.stat(path).await.map_err(|_| S3Error::NoSuchKey)?;
+    // Folder is returned as empty object "key/"
+    if stat.is_dir && key.ends_with('/') {
+        return Ok(StatusCode::OK.into_response());
+    }
+    if stat.is_dir {
+        return Err(S3Error::NoSuchKey);
+    }
+    // ETag is set by `file_response`, the same as of downloads and WebDAV
+    Ok(file_response(storage, path, headers).await?)
+}
+
+async fn put_object(
+    storage: &dyn StorageBackend,
+    sig: &Signature,
+    key: &str,
+    path: &Path,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, S3Error> {
+    if headers.contains_key("x-amz-copy-source") {
+        return Err(S3Error::NotImplemented);
+    }
+    // Empty object "key/" is created as folder
+    if key.ends_with('/') {
+        create_folders(storage, path).await?;
+        return Ok(etag_response(hex::encode(Md5::digest(b""))));
+    }
+    if storage.is_dir(path).await {
+        return Err(S3Error::InvalidArgument);
+    }
+    // Write into temporary file, so failed upload won't leave a broken file
+    let temp = staging_dir()?.join(random_string(32));
+    let mut file = AsyncFile::create(&temp).await?;
+    let md5 = match receive_body(sig, body, &mut file).await {
+        Ok(m) => m,
+        Err(e) => {
+            remove_file(&temp).await?;
+            return Err(e);
+        }
+    };
+    move_into(storage, &temp, path).await?;
+    Ok(etag_response(md5))
+}
+
+async fn delete_object(
+    storage: &dyn StorageBackend,
+    key: &str,
+    path: &Path,
+) -> Result<Response, S3Error> {
+    // Only empty folder can be deleted as object "key/"
+    if key.ends_with('/') == storage.is_dir(path).await {
+        match remove_path(storage, path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn create_multipart(
+    pool: &SqlitePool,
+    sig: &Signature,
+    bucket: &str,
+    key: &str,
+) -> Result<Response, S3Error> {
+    let upload_id = random_string(32);
+    create_dir_all(staging_dir()?.join(&upload_id))?;
+    sqlx::query!(
+        "INSERT INTO s3_upload (upload_id, bucket, key, username) VALUES (?, ?, ?, ?)",
+        upload_id,
+        bucket,
+        key,
+        sig.username
+    )
+    .execute(pool)
+    .await?;
+    Ok(xml_response(
+        StatusCode::OK,
+        format!(
+            "{}<InitiateMultipartUploadResult xmlns=\"{}\"><Bucket>{}</Bucket><Key>{}</Key>\
+            <UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+            XML_HEADER,
+            XML_NS,
+            escape(bucket),
+            escape(key),
+            upload_id
+        ),
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload_part(
+    pool: &SqlitePool,
+    sig: &Signature,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    number: u32,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response, S3Error> {
+    if headers.contains_key("x-amz-copy-source") {
+        return Err(S3Error::NotImplemented);
+    }
+    let folder = upload_folder(pool, sig, bucket, key, upload_id).await?;
+    let mut file = AsyncFile::create(folder.join(number.to_string())).await?;
+    let md5 = receive_body(sig, body, &mut file).await?;
+    Ok(etag_response(md5))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn complete_multipart(
+    pool: &SqlitePool,
+    storage: &dyn StorageBackend,
+    sig: &Signature,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    path: &Path,
+    body: Body,
+) -> Result<Response, S3Error> {
+    let folder = upload_folder(pool, sig, bucket, key, upload_id).await?;
+    let parts = parse_parts(&read_body(body).await?)?;
+    if parts.is_empty() {
+        return Err(S3Error::MalformedXML);
+    }
+    if parts.windows(2).any(|w| w[0].0 >= w[1].0) {
+        return Err(S3Error::InvalidPartOrder);
+    }
+    // Concat parts, and check ETag of each part
+    let temp = folder.join("complete");
+    let mut file = AsyncFile::create(&temp).await?;
+    let mut digests = vec![];
+    let mut buf = vec![0; 64 * 1024];
+    for (number, etag) in &parts {
+        let mut part = AsyncFile::open(folder.join(number.to_string()))
+            .await
+            .map_err(|_| S3Error::InvalidPart)?;
+        let mut md5 = Md5::new();
+        loop {
+            let n = part.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            md5.update(&buf[..n]);
+            file.write_all(&buf[..n]).await?;
+        }
+        let digest = md5.finalize();
+        if hex::encode(digest) != etag.trim_matches('"') {
+            return Err(S3Error::InvalidPart);
+        }
+        digests.extend_from_slice(&digest);
+    }
+    file.flush().await?;
+    if storage.is_dir(path).await {
+        return Err(S3Error::InvalidArgument);
+    }
+    move_into(storage, &temp, path).await?;
+    remove_dir_all(&folder).await?;
+    sqlx::query!("DELETE FROM s3_upload WHERE upload_id = ?", upload_id)
+        .execute(pool)
+        .await?;
+    let etag = format!("\"{}-{}\"", hex::encode(Md5::digest(&digests)), parts.len());
+    Ok(xml_response(
+        StatusCode::OK,
+        format!(
+            "{}<CompleteMultipartUploadResult xmlns=\"{}\"><Location>/{}/{}</Location>\
+            <Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>",
+            XML_HEADER,
+            XML_NS,
+            escape(bucket),
+            escape(key),
+            escape(bucket),
+            escape(key),
+            escape(etag.as_str())
+        ),
+    ))
+}
+
+async fn abort_multipart(
+    pool: &SqlitePool,
+    sig: &Signature,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+) -> Result<Response, S3Error> {
+    let folder = upload_folder(pool, sig, bucket, key, upload_id).await?;
+    remove_dir_all(&folder).await?;
+    sqlx::query!("DELETE FROM s3_upload WHERE upload_id = ?", upload_id)
+        .execute(pool)
+        .await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Get folder storing the parts of upload, only the user who created it can access
+async fn upload_folder(
+    pool: &SqlitePool,
+    sig: &Signature,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+) -> Result<PathBuf, S3Error> {
+    sqlx::query!(
+        "SELECT id FROM s3_upload WHERE upload_id = ? AND bucket = ? AND key = ? AND username = ?",
+        upload_id,
+        bucket,
+        key,
+        sig.username
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(S3Error::NoSuchUpload)?;
+    let folder = staging_dir()?.join(upload_id);
+    match folder.is_dir() {
+        true => Ok(folder),
+        false => Err(S3Error::NoSuchUpload),
+    }
+}
+
+/// Get part number and ETag from `<CompleteMultipartUpload>`
+fn parse_parts(body: &str) -> Result<Vec<(u32, String)>, S3Error> {
+    let mut reader = Reader::from_str(body);
+    let mut parts = vec![];
+    let (mut number, mut etag) = (None, None);
+    let mut field = String::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => field = e.local_name().as_ref().to_string(),
+            Ok(Event::Text(t)) => {
+                let text = t.into_inner();
+                match field.as_str() {
+                    "PartNumber" => number = text.trim().parse().ok(),
+                    // Quotes of ETag are read as entity references, and they are ignored
+                    "ETag" => etag.get_or_insert_with(String::new).push_str(text.trim()),
+                    _ => (),
+                }
+            }
+            Ok(Event::End(e)) => {
+                if e.local_name().as_ref() == "Part" {
+                    let part = (number.take(), etag.take());
+                    let (n, t) = match part {
+                        (Some(n), Some(t)) => (n, t),
+                        _ => return Err(S3Error::MalformedXML),
+                    };
+                    parts.push((n, t));
+                }
+                field.clear();
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(_) => return Err(S3Error::MalformedXML),
+        }
+    }
+    Ok(parts)
+}
+
+/// Write request body into `file`, return hex MD5 of content.
+/// Both plain and `aws-chunked` payload are supported
+async fn receive_body(
+    sig: &Signature,
+    mut body: Body,
+    file: &mut AsyncFile,
+) -> Result<String, S3Error> {
+    let mut md5 = Md5::new();
+    let verify_chunk = match sig.payload.as_str() {
+        "STREAMING-AWS4-HMAC-SHA256-PAYLOAD" | "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER" => true,
+        "STREAMING-UNSIGNED-PAYLOAD-TRAILER" => false,
+        payload => {
+            let mut sha256 = Sha256::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(|_| S3Error::InvalidArgument)?;
+                md5.update(&chunk);
+                sha256.update(&chunk);
+                file.write_all(&chunk).await?;
+            }
+            if payload != "UNSIGNED-PAYLOAD" && hex::encode(sha256.finalize()) != payload {
+                return Err(S3Error::XAmzContentSHA256Mismatch);
+            }
+            file.flush().await?;
+            return Ok(hex::encode(md5.finalize()));
+        }
+    };
+    // Chunk is "size;chunk-signature=signature\r\ndata\r\n", ends with zero size chunk
+    let mut buf: Vec<u8> = vec![];
+    let mut previous = sig.signature.clone();
+    loop {
+        let line_end = loop {
+            if let Some(i) = buf.windows(2).position(|w| w == b"\r\n") {
+                break i;
+            }
+            if buf.len() > 1024 || !fill(&mut body, &mut buf).await? {
+                return Err(S3Error::InvalidArgument);
+            }
+        };
+        let line = String::from_utf8_lossy(&buf[..line_end]).to_string();
+        buf.drain(..line_end + 2);
+        let (size, signature) = match line.split_once(';') {
+            Some((s, ext)) => (s, ext.strip_prefix("chunk-signature=")),
+            None => (line.as_str(), None),
+        };
+        let size = usize::from_str_radix(size.trim(), 16).map_err(|_| S3Error::InvalidArgument)?;
+        if size > MAX_CHUNK_SIZE {
+            return Err(S3Error::InvalidArgument);
+        }
+        // Trailing headers after the last chunk are ignored
+        while size > 0 && buf.len() < size + 2 {
+            if !fill(&mut body, &mut buf).await? {
+                return Err(S3Error::InvalidArgument);
+            }
+        }
+        let data = &buf[..size];
+        if verify_chunk {
+            let expected = chunk_signature(sig, &previous, data);
+            if !signature.is_some_and(|s| constant_time_eq(&expected, s)) {
+                return Err(S3Error::SignatureDoesNotMatch);
+            }
+            previous = expected;
+        }
+        if size == 0 {
+            break;
+        }
+        md5.update(data);
+        file.write_all(data).await?;
+        buf.drain(..size + 2);
+    }
+    file.flush().await?;
+    Ok(hex::encode(md5.finalize()))
+}
+
+/// Read more data from `body`, return false if body is finished
+async fn fill(body: &mut Body, buf: &mut Vec<u8>) -> Result<bool, S3Error> {
+    match body.data().await {
+        Some(Ok(chunk)) => {
+            buf.extend_from_slice(&chunk);
+            Ok(true)
+        }
+        Some(Err(_)) => Err(S3Error::InvalidArgument),
+        None => Ok(false),
+    }
+}
+
+async fn read_body(mut body: Body) -> Result<String, S3Error> {
+    let mut buf = vec![];
+    while fill(&mut body, &mut buf).await? {
+        if buf.len() > MAX_BODY_SIZE {
+            return Err(S3Error::MalformedXML);
+        }
+    }
+    String::from_utf8(buf).map_err(|_| S3Error::MalformedXML)
+}
+
+/// Move temporary file to `path` in storage, missing folders are created
+async fn move_into(storage: &dyn StorageBackend, temp: &Path, path: &Path) -> Result<(), S3Error> {
+    if let Some(parent) = path.parent() {
+        create_folders(storage, parent).await?;
+    }
+    let existed = storage.exists(path).await;
+    let mut file = AsyncFile::open(temp).await?;
+    storage.write(path, &mut file).await?;
+    remove_file(temp).await?;
+    file_written(path, !existed);
+    Ok(())
+}
+
+/// Create folder and its missing parents, like `mkdir -p`
+async fn create_folders(storage: &dyn StorageBackend, path: &Path) -> io::Result<()> {
+    let mut missing = vec![];
+    for p in path.ancestors() {
+        if p.as_os_str().is_empty() || storage.is_dir(p).await {
+            break;
+        }
+        missing.push(p);
+    }
+    for p in missing.iter().rev() {
+        storage.mkdir(p).await?;
+    }
+    if let Some(p) = missing.last() {
+        publish(FileEvent::new(EventType::Create, p));
+    }
+    Ok(())
+}
+
+/// Remove staging files and multipart uploads untouched for `STAGING_EXPIRY`
+/// periodically, until the server stops
+pub async fn clean_staging(pool: SqlitePool) {
+    let mut interval = tokio::time::interval(CLEAN_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = remove_stale_uploads(&pool).await {
+            tracing::warn!("failed to clean S3 staging folder: {:?}", e);
+        }
+    }
+}
+
+async fn remove_stale_uploads(pool: &SqlitePool) -> Result<(), S3Error> {
+    let staging = staging_dir()?;
+    let mut entries = read_dir(&staging).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        // Parts added to a multipart upload update modified time of its folder
+        let modified = entry.metadata().await?.modified()?;
+        if modified.elapsed().unwrap_or_default() < STAGING_EXPIRY {
+            continue;
+        }
+        if entry.file_type().await?.is_dir() {
+            remove_dir_all(entry.path()).await?;
+        } else {
+            remove_file(entry.path()).await?;
+        }
+    }
+    // Uploads can't be completed without their parts
+    let uploads = sqlx::query!("SELECT upload_id FROM s3_upload")
+        .fetch_all(pool)
+        .await?;
+    for upload in uploads {
+        if !staging.join(&upload.upload_id).exists() {
+            sqlx::query!("DELETE FROM s3_upload WHERE upload_id = ?", upload.upload_id)
+                .execute(pool)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Compare signatures in constant time, so time taken doesn't leak the expected one
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// Folder of temporary files, outside of the file folder
+fn staging_dir() -> io::Result<PathBuf> {
+    let path = temp_dir().join("file-station-s3");
+    create_dir_all(&path)?;
+    Ok(path)
+}
+
+/// Decode query string, value of flag like "?uploads" is empty
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| p.split_once('=').unwrap_or((p, "")))
+        .map(|(k, v)| {
+            (
+                percent_decode_str(k).decode_utf8_lossy().to_string(),
+                percent_decode_str(v).decode_utf8_lossy().to_string(),
+            )
+        })
+        .collect()
+}
+
+fn get_header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn etag_response(md5: String) -> Response {
+    (StatusCode::OK, [(header::ETAG, format!("\"{}\"", md5))]).into_response()
+}
+
+fn xml_response(status: StatusCode, body: String) -> Response {
+    (status, [(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+/// Parse "20130524T000000Z" into unix timestamp
+fn parse_amz_date(date: &str) -> Option<u64> {
+    if date.len() != 16 || !date.is_ascii() || &date[8..9] != "T" || &date[15..] != "Z" {
+        return None;
+    }
+    let num = |r: std::ops::Range<usize>| date[r].parse::<i64>().ok();
+    let days = days_from_civil(num(0..4)?, num(4..6)?, num(6..8)?);
+    let secs = days * 86400 + num(9..11)? * 3600 + num(11..13)? * 60 + num(13..15)?;
+    u64::try_from(secs).ok()
+}
+
+/// Format unix timestamp as "2013-05-24T00:00:00.000Z"
+fn iso8601(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+    let (y, m, d) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        y,
+        m,
+        d,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Days since 1970-01-01 of the date, see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Date of days since 1970-01-01
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (
+        if m <= 2 {
+            yoe + era * 400 + 1
+        } else {
+            yoe + era * 400
+        },
+        m,
+        d,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::fixture::pool;
+
+    #[test]
+    fn test_signature() {
+        // Example "GET Object" in AWS document of Signature Version 4
+        let req = Request::builder()
+            .method("GET")
+            .uri("/test.txt")
+            .header("host", "examplebucket.s3.amazonaws.com")
+            .header("range", "bytes=0-9")
+            .header("x-amz-content-sha256", EMPTY_SHA256)
+            .header("x-amz-date", "20130524T000000Z")
+            .body(())
+            .unwrap();
+        let (parts, _) = req.into_parts();
+        let scope = "20130524/us-east-1/s3/aws4_request";
+        let canonical = canonical_request(
+            &parts,
+            "host;range;x-amz-content-sha256;x-amz-date",
+            EMPTY_SHA256,
+        )
+        .unwrap();
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY", scope).unwrap();
+        let signature = hex::encode(hmac(
+            &key,
+            &string_to_sign("20130524T000000Z", scope, &canonical),
+        ));
+        assert_eq!(
+            signature,
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+        let expected = "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41";
+        assert!(constant_time_eq(expected, &signature));
+        assert!(!constant_time_eq(expected, &signature[1..]));
+        assert!(!constant_time_eq(expected, &signature.replace('f', "e")));
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!(parse_amz_date("20130524T000000Z"), Some(1369353600));
+        assert_eq!(iso8601(1369353600), "2013-05-24T00:00:00.000Z");
+        assert_eq!(parse_amz_date("2013-05-24"), None);
+    }
+
+    #[tokio::test]
+    async fn test_presigned_expires() {
+        let pool = pool().await;
+        let date = iso8601(get_unix_timestamp())
+            .replace(['-', ':'], "")
+            .replace(".000", "");
+        let presigned = |expires: &str| {
+            let uri = format!(
+                "/test.txt?X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential=KEY%2F{}%2Fus-east-1%2Fs3%2Faws4_request&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host&X-Amz-Signature=0",
+                &date[..8],
+                date,
+                expires
+            );
+            Request::builder().uri(uri).body(()).unwrap().into_parts().0
+        };
+        for expires in ["604801", "18446744073709551615"] {
+            let res = authenticate(&pool, &presigned(expires)).await;
+            assert!(matches!(res, Err(S3Error::AccessDenied)));
+        }
+        // Valid expiration goes on to check the key
+        let res = authenticate(&pool, &presigned("604800")).await;
+        assert!(matches!(res, Err(S3Error::InvalidAccessKeyId)));
+    }
+}
@@ -0,0 +1,738 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, ErrorKind, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{Extension, Query},
+    http::StatusCode as HttpStatusCode,
+    Json,
+};
+use rand::rngs::OsRng;
+use russh::{
+    keys::{load_secret_key, ssh_key::LineEnding, Algorithm, PrivateKey, PublicKey},
+    server::{Auth, Config, Handler, Msg, Server, Session},
+    Channel, ChannelId, MethodKind, MethodSet,
+};
+use russh_sftp::protocol::{
+    Attrs, Data, File as SftpFile, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode,
+    Version,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::{
+    fs::{File as AsyncFile, OpenOptions as AsyncOpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{
+    file::{
+        check_path, create_path, file_written,
+        quota::QuotaExceeded,
+        remove_path, rename_path,
+        storage::{unshare, Stat, Storage},
+        storage_for_user, FileError,
+    },
+    user::{get_role, verify_login, Claim},
+    CONFIG,
+};
+
+/// Max length of data returned by one read request
+const MAX_READ_LEN: u32 = 64 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SftpError {
+    #[error("Io Error")]
+    IoError(#[from] io::Error),
+    #[error("Status")]
+    Status(StatusCode),
+}
+
+impl From<SftpError> for StatusCode {
+    fn from(e: SftpError) -> Self {
+        match e {
+            SftpError::IoError(e) => match e.kind() {
+                ErrorKind::NotFound => StatusCode::NoSuchFile,
+                ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+                _ => StatusCode::Failure,
+            },
+            SftpError::Status(s) => s,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SshKey {
+    id: i64,
+    /// Public key in OpenSSH format, without comment
+    public_key: String,
+    comment: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddSshKeyArgs {
+    /// Line of `authorized_keys`, e.g. "ssh-ed25519 AAAA... user@host"
+    public_key: String,
+}
+
+#[derive(Deserialize)]
+pub struct SshKeyArgs {
+    id: i64,
+}
+
+/// Add public key of current user
+pub async fn add_ssh_key(
+    Extension(db): Extension<SqlitePool>,
+    claim: Claim,
+    Json(args): Json<AddSshKeyArgs>,
+) -> Result<Json<SshKey>, FileError> {
+    let mut key =
+        PublicKey::from_openssh(args.public_key.trim()).map_err(|_| FileError::ContentError)?;
+    let comment = key.comment().to_string();
+    key.set_comment("");
+    let public_key = key.to_openssh().map_err(|_| FileError::ContentError)?;
+    let id = sqlx::query!(
+        "INSERT INTO ssh_key (username, public_key, comment) VALUES (?, ?, ?)",
+        claim.username,
+        public_key,
+        comment
+    )
+    .execute(&db)
+    .await?
+    .last_insert_rowid();
+    Ok(Json(SshKey {
+        id,
+        public_key,
+        comment,
+    }))
+}
+
+/// Get public keys of current user
+pub async fn get_ssh_keys(
+    Extension(db): Extension<SqlitePool>,
+    claim: Claim,
+) -> Result<Json<Vec<SshKey>>, FileError> {
+    let keys = sqlx::query!(
+        "SELECT id, public_key, comment FROM ssh_key WHERE username = ?",
+        claim.username
+    )
+    .fetch_all(&db)
+    .await?
+    .into_iter()
+    .map(|r| SshKey {
+        id: r.id,
+        public_key: r.public_key,
+        comment: r.comment,
+    })
+    .collect();
+    Ok(Json(keys))
+}
+
+/// Delete public key of current user
+pub async fn delete_ssh_key(
+    Query(args): Query<SshKeyArgs>,
+    Extension(db): Extension<SqlitePool>,
+    claim: Claim,
+) -> Result<HttpStatusCode, FileError> {
+    sqlx::query!(
+        "DELETE FROM ssh_key WHERE id = ? AND username = ?",
+        args.id,
+        claim.username
+    )
+    .execute(&db)
+    .await?;
+    Ok(HttpStatusCode::OK)
+}
+
+/// Run SFTP server on `addr`
+pub async fn serve_sftp(pool: SqlitePool, storage: Storage, addr: SocketAddr) -> io::Result<()> {
+    let config = Config {
+        methods: MethodSet::from(&[MethodKind::Password, MethodKind::PublicKey][..]),
+        auth_rejection_time: Duration::from_secs(3),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        inactivity_timeout: Some(Duration::from_secs(3600)),
+        keys: vec![host_key(&CONFIG.sftp_host_key)?],
+        ..Default::default()
+    };
+    SshServer { pool, storage }
+        .run_on_address(Arc::new(config), addr)
+        .await
+}
+
+/// Load host key, or generate one if it doesn't exist
+fn host_key(path: &Path) -> io::Result<PrivateKey> {
+    if path.exists() {
+        return load_secret_key(path, None).map_err(io::Error::other);
+    }
+    let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).map_err(io::Error::other)?;
+    let pem = key.to_openssh(LineEnding::LF).map_err(io::Error::other)?;
+    // Only readable by the server, and never replaces a key created meanwhile
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(pem.as_bytes())?;
+    tracing::info!("generated SFTP host key {}", path.display());
+    Ok(key)
+}
+
+struct SshServer {
+    pool: SqlitePool,
+    storage: Storage,
+}
+
+impl Server for SshServer {
+    type Handler = SshSession;
+
+    fn new_client(&mut self, addr: Option<SocketAddr>) -> SshSession {
+        SshSession {
+            // Clients without address share one throttle
+            ip: addr.map_or(IpAddr::from([0, 0, 0, 0]), |a| a.ip()),
+            pool: self.pool.clone(),
+            storage: self.storage.clone(),
+            channels: HashMap::new(),
+        }
+    }
+}
+
+/// SSH connection, only SFTP subsystem is provided
+struct SshSession {
+    /// Password attempts of client are throttled
+    ip: IpAddr,
+    pool: SqlitePool,
+    /// Replaced by the storage seen by the user after authentication
+    storage: Storage,
+    /// Channels waiting for subsystem request
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+impl SshSession {
+    /// Check if `key` is one of the public keys of `username`
+    async fn has_key(&self, username: &str, key: &PublicKey) -> bool {
+        let keys = sqlx::query!(
+            "SELECT public_key FROM ssh_key WHERE username = ?",
+            username
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default();
+        keys.iter()
+            .any(|r| match PublicKey::from_openssh(&r.public_key) {
+                Ok(k) => k.key_data() == key.key_data(),
+                Err(_) => false,
+            })
+    }
+}
+
+impl Handler for SshSession {
+    type Error = russh::Error;
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        match verify_login(&self.pool, self.ip, user, password).await {
+            Ok(role) => {
+                self.storage = storage_for_user(&self.storage, &self.pool, user, &role);
+                Ok(Auth::Accept)
+            }
+            Err(_) => Ok(Auth::reject()),
+        }
+    }
+
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        match self.has_key(user, public_key).await {
+            true => Ok(Auth::Accept),
+            false => Ok(Auth::reject()),
+        }
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if !self.has_key(user, public_key).await {
+            return Ok(Auth::reject());
+        }
+        match get_role(&self.pool, user).await {
+            Ok(role) => {
+                self.storage = storage_for_user(&self.storage, &self.pool, user, &role);
+                Ok(Auth::Accept)
+            }
+            Err(_) => Ok(Auth::reject()),
+        }
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.close(channel)
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel_id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        match (name, self.channels.remove(&channel_id)) {
+            ("sftp", Some(channel)) => {
+                session.channel_success(channel_id)?;
+                let sftp = SftpSession::new(self.storage.clone());
+                russh_sftp::server::run(channel.into_stream(), sftp).await;
+            }
+            _ => session.channel_failure(channel_id)?,
+        }
+        Ok(())
+    }
+}
+
+/// File or folder opened by client
+enum Opened {
+    /// File in local file system, which can be written at any offset
+    File {
+        file: AsyncFile,
+        path: PathBuf,
+        created: bool,
+        written: bool,
+        /// Size before opened, to count used bytes when closed
+        old_size: u64,
+        /// Max size allowed by quota
+        max_size: Option<u64>,
+    },
+    /// Read only file of storage without local path
+    Stored {
+        path: PathBuf,
+    },
+    Folder {
+        path: PathBuf,
+        done: bool,
+    },
+}
+
+/// SFTP requests of one channel.
+/// Client sees the storage folder as "/", and paths are checked like the web API
+struct SftpSession {
+    storage: Storage,
+    handles: HashMap<String, Opened>,
+    next_handle: u64,
+}
+
+impl SftpSession {
+    fn new(storage: Storage) -> SftpSession {
+        SftpSession {
+            storage,
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    /// Map path sent by client to path in storage, with the same check as `CheckedPath`
+    fn checked_path(&self, path: &str) -> Result<PathBuf, SftpError> {
+        check_path(self.storage.as_ref(), &normalize(path))
+            .map_err(|_| SftpError::Status(StatusCode::PermissionDenied))
+    }
+
+    /// Local path is needed for writing at random offset
+    fn writable_path(&self, path: &Path) -> Result<PathBuf, SftpError> {
+        if !self.storage.writable(path) {
+            return Err(SftpError::Status(StatusCode::PermissionDenied));
+        }
+        self.storage
+            .local_path(path)
+            .ok_or(SftpError::Status(StatusCode::OpUnsupported))
+    }
+
+    fn add_handle(&mut self, opened: Opened) -> String {
+        self.next_handle += 1;
+        let handle = self.next_handle.to_string();
+        self.handles.insert(handle.clone(), opened);
+        handle
+    }
+
+    fn get_handle(&mut self, handle: &str) -> Result<&mut Opened, SftpError> {
+        self.handles
+            .get_mut(handle)
+            .ok_or(SftpError::Status(StatusCode::Failure))
+    }
+}
+
+impl russh_sftp::server::Handler for SftpSession {
+    type Error = SftpError;
+
+    fn unimplemented(&self) -> Self::Error {
+        SftpError::Status(StatusCode::OpUnsupported)
+    }
+
+    async fn init(&mut self, _: u32, _: HashMap<String, String>) -> Result<Version, Self::Error> {
+        Ok(Version::new())
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        Ok(Name {
+            id,
+            files: vec![SftpFile::dummy(normalize(&path))],
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let stat = self.storage.stat(&self.checked_path(&path)?).await?;
+        Ok(Attrs {
+            id,
+            attrs: attributes(&stat),
+        })
+    }
+
+    /// Links are always followed by storage
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        self.stat(id, path).await
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let stat = match self.get_handle(&handle)? {
+            Opened::File { file, .. } => Stat::from(file.metadata().await?),
+            Opened::Stored { path } | Opened::Folder { path, .. } => {
+                let path = path.clone();
+                self.storage.stat(&path).await?
+            }
+        };
+        Ok(Attrs {
+            id,
+            attrs: attributes(&stat),
+        })
+    }
+
+    /// Only truncating is supported, other attributes are ignored
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let path = self.checked_path(&path)?;
+        if let Some(size) = attrs.size {
+            let old_size = self.storage.stat(&path).await?.size;
+            check_quota(self.storage.max_size(&path).await?, size)?;
+            let local = self.writable_path(&path)?;
+            unshare(&local).await?;
+            AsyncOpenOptions::new()
+                .write(true)
+                .open(local)
+                .await?
+                .set_len(size)
+                .await?;
+            self.storage.written(&path, old_size).await?;
+            file_written(&path, false);
+        }
+        Ok(ok(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        if let (
+            Some(size),
+            Opened::File {
+                file,
+                written,
+                max_size,
+                ..
+            },
+        ) = (attrs.size, self.get_handle(&handle)?)
+        {
+            check_quota(*max_size, size)?;
+            file.set_len(size).await?;
+            *written = true;
+        }
+        Ok(ok(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let path = self.checked_path(&path)?;
+        if !self.storage.is_dir(&path).await {
+            return Err(SftpError::Status(StatusCode::NoSuchFile));
+        }
+        let handle = self.add_handle(Opened::Folder { path, done: false });
+        Ok(Handle { id, handle })
+    }
+
+    /// All entries are returned at once, then EOF
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        let path = match self.get_handle(&handle)? {
+            Opened::Folder { done: true, .. } => {
+                return Err(SftpError::Status(StatusCode::Eof));
+            }
+            Opened::Folder { path, done } => {
+                *done = true;
+                path.clone()
+            }
+            _ => return Err(SftpError::Status(StatusCode::Failure)),
+        };
+        let files = self
+            .storage
+            .list(&path)
+            .await?
+            .into_iter()
+            .map(|e| SftpFile::new(e.name, attributes(&e.stat)))
+            .collect();
+        Ok(Name { id, files })
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let path = self.checked_path(&filename)?;
+        let stat = self.storage.stat(&path).await.ok();
+        if stat.as_ref().is_some_and(|s| s.is_dir) {
+            return Err(SftpError::Status(StatusCode::Failure));
+        }
+        let read_only = pflags.bits() == OpenFlags::READ.bits();
+        let local = match self.storage.local_path(&path) {
+            _ if !read_only && !self.storage.writable(&path) => {
+                return Err(SftpError::Status(StatusCode::PermissionDenied));
+            }
+            Some(p) => p,
+            None if read_only && stat.is_some() => {
+                let handle = self.add_handle(Opened::Stored { path });
+                return Ok(Handle { id, handle });
+            }
+            None => return Err(SftpError::Status(StatusCode::OpUnsupported)),
+        };
+        let created = stat.is_none();
+        let max_size = match read_only {
+            true => None,
+            false => {
+                unshare(&local).await?;
+                self.storage.max_size(&path).await?
+            }
+        };
+        let file = AsyncOpenOptions::from(OpenOptions::from(pflags))
+            .open(&local)
+            .await?;
+        let truncated = pflags.contains(OpenFlags::TRUNCATE);
+        let handle = self.add_handle(Opened::File {
+            file,
+            created,
+            written: truncated && !created,
+            old_size: stat.map_or(0, |s| s.size),
+            max_size,
+            path,
+        });
+        Ok(Handle { id, handle })
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let len = len.min(MAX_READ_LEN) as usize;
+        let mut data = vec![0; len];
+        let n = match self.get_handle(&handle)? {
+            Opened::File { file, .. } => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.read(&mut data).await?
+            }
+            Opened::Stored { path } => {
+                let path = path.clone();
+                let range = offset..offset.saturating_add(len as u64);
+                let mut reader = self.storage.read(&path, Some(range)).await?;
+                reader.read(&mut data).await?
+            }
+            Opened::Folder { .. } => return Err(SftpError::Status(StatusCode::Failure)),
+        };
+        if n == 0 && !data.is_empty() {
+            return Err(SftpError::Status(StatusCode::Eof));
+        }
+        data.truncate(n);
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let (file, written, max_size) = match self.get_handle(&handle)? {
+            Opened::File {
+                file,
+                written,
+                max_size,
+                ..
+            } => (file, written, *max_size),
+            _ => return Err(SftpError::Status(StatusCode::Failure)),
+        };
+        check_quota(max_size, offset.saturating_add(data.len() as u64))?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(&data).await?;
+        *written = true;
+        Ok(ok(id))
+    }
+
+    /// Changes are published when written file is closed
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        match self.handles.remove(&handle) {
+            Some(Opened::File {
+                mut file,
+                path,
+                created,
+                written,
+                old_size,
+                ..
+            }) => {
+                file.flush().await?;
+                if created || written {
+                    self.storage.written(&path, old_size).await?;
+                    file_written(&path, created);
+                }
+            }
+            Some(_) => (),
+            None => return Err(SftpError::Status(StatusCode::Failure)),
+        }
+        Ok(ok(id))
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        let path = self.checked_path(&filename)?;
+        if self.storage.is_dir(&path).await {
+            return Err(SftpError::Status(StatusCode::Failure));
+        }
+        remove_path(self.storage.as_ref(), &path).await?;
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let path = self.checked_path(&path)?;
+        create_path(self.storage.as_ref(), &path).await?;
+        Ok(ok(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        let path = self.checked_path(&path)?;
+        if path.as_os_str().is_empty() || !self.storage.is_dir(&path).await {
+            return Err(SftpError::Status(StatusCode::Failure));
+        }
+        remove_path(self.storage.as_ref(), &path).await?;
+        Ok(ok(id))
+    }
+
+    /// Like SFTPv3, existing target is not overwritten
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        let from = self.checked_path(&oldpath)?;
+        let to = self.checked_path(&newpath)?;
+        if from.as_os_str().is_empty() || self.storage.exists(&to).await || to.starts_with(&from) {
+            return Err(SftpError::Status(StatusCode::Failure));
+        }
+        rename_path(self.storage.as_ref(), &from, &to).await?;
+        Ok(ok(id))
+    }
+}
+
+/// Check file end `size` against max size allowed by quota
+fn check_quota(max_size: Option<u64>, size: u64) -> Result<(), SftpError> {
+    match max_size {
+        Some(max) if size > max => Err(io::Error::other(QuotaExceeded { remaining: max }).into()),
+        _ => Ok(()),
+    }
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".into(),
+        language_tag: "en-US".into(),
+    }
+}
+
+/// Resolve "." and ".." in path sent by client, result always starts with '/'.
+/// Working directory of client is always the root
+fn normalize(path: &str) -> String {
+    let mut parts = vec![];
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Attributes of file in storage, owner and permission are fixed
+fn attributes(stat: &Stat) -> FileAttributes {
+    let mut attrs = FileAttributes::empty();
+    attrs.size = Some(stat.size);
+    attrs.permissions = Some(if stat.is_dir { 0o40755 } else { 0o100644 });
+    attrs.mtime = Some(stat.modified as u32);
+    attrs.atime = attrs.mtime;
+    attrs
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("."), "/");
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("/a/./b/"), "/a/b");
+        assert_eq!(normalize("a/../../b"), "/b");
+        assert_eq!(normalize("/../.."), "/");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_host_key() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("file-station-key-{}", std::process::id()));
+        let key = host_key(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(host_key(&path).unwrap(), key);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
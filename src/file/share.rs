@@ -1,58 +1,273 @@
-use std::fs::read;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
+use argon2::PasswordHash;
 use axum::{
-    extract::{Extension, Query},
-    http::StatusCode,
+    extract::{ConnectInfo, Extension, Multipart, Query},
+    headers::{Cookie, HeaderMapExt},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
+use futures_util::TryStreamExt;
+use percent_encoding::utf8_percent_encode;
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use sqlx::SqlitePool;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
 
 use crate::{
-    file::{concat_path_str, is_traversal, File, FileError},
-    user::Claim,
+    file::{
+        access::{purge_access, Access},
+        album::{album_files, album_paths, check_album},
+        check_path, create_path,
+        file::{file_response, next_file, save_upload, WriteGuard},
+        quota::QuotaStorage,
+        remove_path, rename_path,
+        render::{render_response, LinkFn},
+        storage::{Storage, StorageBackend},
+        tracking::file_identity,
+        write_path, File, FileError, PATH_SET,
+    },
+    throttle,
+    user::{check_hash, gen_hash, get_unix_timestamp, sign_token, verify_token, Claim},
+    CONFIG,
 };
 
-#[derive(Deserialize)]
+/// Interval of removing expired shares
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+/// Length of random url, long enough that it can't be guessed
+const URL_LENGTH: usize = 32;
+/// Lifetime of share session cookie in seconds
+const SESSION_AGE: u64 = 60 * 60;
+/// Max number tried by renaming uploaded file, like "a (1000).txt"
+const MAX_RENAMES: usize = 1000;
+
+#[derive(Deserialize, Default)]
 pub struct AddShareArgs {
+    #[serde(default)]
     path: String,
+    /// Share album instead of path
+    album: Option<i64>,
     password: Option<String>,
+    /// Unix timestamp, never expires if not set
+    expires_at: Option<i64>,
+    /// Unlimited if not set
+    max_downloads: Option<i64>,
+    label: Option<String>,
+    /// "view", "download" (default), "upload", "edit" or "drop"
+    permission: Option<String>,
+    /// Max size of each file uploaded to "drop" share
+    max_file_size: Option<i64>,
+    /// Max size of all files uploaded to "drop" share
+    max_total_size: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ShareIdArgs {
+    id: i64,
+}
+
+/// Settings to change, unset fields are kept
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateShareArgs {
+    /// Empty label is removed
+    label: Option<String>,
+    /// Empty password is removed
+    password: Option<String>,
+    /// 0 never expires
+    expires_at: Option<i64>,
+    /// 0 is unlimited, download count is kept
+    max_downloads: Option<i64>,
 }
 
 #[derive(Deserialize)]
 pub struct QueryShareArgs {
     url: String,
     file_path: String,
+    /// Not needed if share session cookie is valid
     password: Option<String>,
     download: Option<bool>,
 }
 
+#[derive(Deserialize)]
+pub struct ShareUploadArgs {
+    url: String,
+    password: Option<String>,
+    /// Folder in share, files of "drop" share are always in the shared folder
+    #[serde(default)]
+    file_path: String,
+}
+
+#[derive(Deserialize)]
+pub struct ShareRenameArgs {
+    url: String,
+    password: Option<String>,
+    file_path: String,
+    /// New path in share
+    to: String,
+}
+
+#[derive(Deserialize)]
+pub struct ShareSessionArgs {
+    url: String,
+    password: String,
+}
+
+/// Claims of share session cookie, issued after password is checked
+#[derive(Serialize, Deserialize)]
+struct ShareSession {
+    id: i64,
+    url: String,
+    /// Sessions are invalid after password is changed
+    key: String,
+    exp: u64,
+}
+
 /// All field is optional,
 /// because all field is null by default in database
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ShareIndex {
+    id: i64,
     path: Option<String>,
     url: Option<String>,
-    /// Password is optional in creation
-    password: Option<String>,
+    /// Password is optional in creation, and never returned
+    has_password: bool,
+    album_id: Option<i64>,
+    expires_at: Option<i64>,
+    /// Seconds until expiration, 0 if it has expired
+    remaining: Option<i64>,
+    max_downloads: Option<i64>,
+    download_count: i64,
+    last_access_at: Option<i64>,
+    label: Option<String>,
+    permission: String,
+    max_file_size: Option<i64>,
+    max_total_size: Option<i64>,
+    /// Size of files uploaded to "drop" share
+    uploaded_size: i64,
+    /// When shared file was found removed or replaced
+    broken_at: Option<i64>,
 }
 
-/// Add share file/folder, return url
+/// What visitors of share can do
+#[derive(Clone, Copy, PartialEq)]
+enum Permission {
+    /// List files and preview them with `/share/render`
+    View,
+    /// Download files too
+    Download,
+    /// Upload files and create folders too
+    Upload,
+    /// Rename and delete files too
+    Edit,
+    /// Upload to the shared folder, nothing can be listed or downloaded
+    Drop,
+}
+
+impl Permission {
+    fn parse(permission: &str) -> Option<Permission> {
+        match permission {
+            "view" => Some(Permission::View),
+            "download" => Some(Permission::Download),
+            "upload" => Some(Permission::Upload),
+            "edit" => Some(Permission::Edit),
+            "drop" => Some(Permission::Drop),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Permission::View => "view",
+            Permission::Download => "download",
+            Permission::Upload => "upload",
+            Permission::Edit => "edit",
+            Permission::Drop => "drop",
+        }
+    }
+
+    fn can_download(&self) -> bool {
+        matches!(
+            self,
+            Permission::Download | Permission::Upload | Permission::Edit
+        )
+    }
+
+    fn can_upload(&self) -> bool {
+        matches!(self, Permission::Upload | Permission::Edit)
+    }
+}
+
+/// Add share file/folder or album, return its id and url.
+/// A path can be shared several times with different settings
 pub async fn add_share_file(
     Query(args): Query<AddShareArgs>,
     Extension(db): Extension<SqlitePool>,
-    _: Claim,
+    Extension(storage): Extension<Storage>,
+    claim: Claim,
 ) -> Result<impl IntoResponse, FileError> {
-    let path = concat_path_str(&args.path);
-    if is_traversal(&path) {
-        return Err(FileError::PathError);
+    if args
+        .expires_at
+        .is_some_and(|e| e <= get_unix_timestamp() as i64)
+    {
+        return Err(FileError::ContentError);
+    }
+    let limits = [args.max_downloads, args.max_file_size, args.max_total_size];
+    if limits.iter().flatten().any(|m| *m <= 0) {
+        return Err(FileError::ContentError);
+    }
+    let permission = match args.permission.as_deref() {
+        Some(p) => Permission::parse(p).ok_or(FileError::ContentError)?,
+        None => Permission::Download,
+    };
+    let password = args
+        .password
+        .filter(|p| !p.is_empty())
+        .map(|p| gen_hash(&p).ok_or(FileError::ServerError))
+        .transpose()?;
+    let label = args.label.filter(|l| !l.is_empty());
+    let path = match args.album {
+        Some(id) => {
+            check_album(&db, id, &claim.username).await?;
+            None
+        }
+        None => {
+            let path = check_path(storage.as_ref(), &args.path)?;
+            // Files are dropped into a folder
+            if permission == Permission::Drop && !storage.is_dir(&path).await {
+                return Err(FileError::PathError);
+            }
+            Some(path)
+        }
+    };
+    // Shares follow their files by it when files are moved
+    let file_id = path
+        .as_deref()
+        .and_then(|p| file_identity(storage.as_ref(), p));
+    let path = path
+        .map(|p| p.to_str().map(String::from).ok_or(FileError::PathError))
+        .transpose()?;
+    if permission == Permission::Drop && path.is_none() {
+        return Err(FileError::ContentError);
     }
+    let permission = permission.as_str();
     let mut counter = 0; // Set a counter to limit rng generate frequency
     let url = loop {
         // Generate random url and ensure it is unique
-        let random = rand::random::<u32>().to_string();
+        let random: String = OsRng
+            .sample_iter(&Alphanumeric)
+            .take(URL_LENGTH)
+            .map(char::from)
+            .collect();
         let result = sqlx::query!("SELECT url FROM share where url = ?", random)
             .fetch_all(&db)
             .await?;
@@ -64,59 +279,689 @@ pub async fn add_share_file(
             return Err(FileError::ServerError);
         }
     };
-    sqlx::query!(
-        "INSERT INTO share (path, url, password) VALUES (?, ?, ?)",
-        args.path,
+    let id = sqlx::query!(
+        "INSERT INTO share (path, url, password, album_id, expires_at, max_downloads, label,
+        permission, max_file_size, max_total_size, file_id)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        path,
         url,
-        args.password
+        password,
+        args.album,
+        args.expires_at,
+        args.max_downloads,
+        label,
+        permission,
+        args.max_file_size,
+        args.max_total_size,
+        file_id
+    )
+    .execute(&db)
+    .await?
+    .last_insert_rowid();
+    Ok(Json(json!({ "id": id, "url": url })))
+}
+
+/// Change label, password, expiration or download limit of share
+pub async fn update_share(
+    Query(args): Query<ShareIdArgs>,
+    Extension(db): Extension<SqlitePool>,
+    _: Claim,
+    Json(update): Json<UpdateShareArgs>,
+) -> Result<StatusCode, FileError> {
+    sqlx::query!("SELECT id FROM share WHERE id = ?", args.id)
+        .fetch_one(&db)
+        .await?;
+    // All fields are checked before any of them is changed
+    let now = get_unix_timestamp() as i64;
+    if update
+        .expires_at
+        .is_some_and(|e| e < 0 || (e > 0 && e <= now))
+    {
+        return Err(FileError::ContentError);
+    }
+    if update.max_downloads.is_some_and(|m| m < 0) {
+        return Err(FileError::ContentError);
+    }
+    let password = match &update.password {
+        Some(p) if !p.is_empty() => Some(gen_hash(p).ok_or(FileError::ServerError)?),
+        _ => None,
+    };
+    let (set_label, set_password) = (update.label.is_some(), update.password.is_some());
+    let (set_expires_at, set_max_downloads) =
+        (update.expires_at.is_some(), update.max_downloads.is_some());
+    let label = update.label.filter(|l| !l.is_empty());
+    let expires_at = update.expires_at.filter(|e| *e > 0);
+    let max_downloads = update.max_downloads.filter(|m| *m > 0);
+    sqlx::query!(
+        "UPDATE share SET label = CASE WHEN ? THEN ? ELSE label END,
+        password = CASE WHEN ? THEN ? ELSE password END,
+        expires_at = CASE WHEN ? THEN ? ELSE expires_at END,
+        max_downloads = CASE WHEN ? THEN ? ELSE max_downloads END
+        WHERE id = ?",
+        set_label,
+        label,
+        set_password,
+        password,
+        set_expires_at,
+        expires_at,
+        set_max_downloads,
+        max_downloads,
+        args.id
     )
     .execute(&db)
     .await?;
-    Ok(Json(json!({ "url": url })))
+    Ok(StatusCode::OK)
 }
 
-/// Delete share
+/// Delete share, other shares of the same path are kept
 pub async fn delete_share(
-    Query(args): Query<AddShareArgs>,
+    Query(args): Query<ShareIdArgs>,
     Extension(db): Extension<SqlitePool>,
     _: Claim,
 ) -> Result<StatusCode, FileError> {
-    sqlx::query!("DELETE FROM share WHERE path = ?", args.path)
+    sqlx::query!("DELETE FROM share WHERE id = ?", args.id)
         .execute(&db)
         .await?;
     Ok(StatusCode::OK)
 }
 
-/// Get share file/folder
+/// Shared path or album, found by url
+struct Share {
+    id: i64,
+    url: String,
+    root: ShareRoot,
+    permission: Permission,
+    max_file_size: Option<i64>,
+    max_total_size: Option<i64>,
+    uploaded_size: i64,
+    /// `Set-Cookie` of new session, after password is checked
+    session: Option<String>,
+}
+
+enum ShareRoot {
+    Path(PathBuf),
+    Album(Vec<PathBuf>),
+}
+
+impl Share {
+    /// Find share by url, check its password or session, expiration and download limit.
+    /// Unknown urls and wrong passwords are throttled
+    async fn find(
+        db: &SqlitePool,
+        storage: &dyn StorageBackend,
+        url: &str,
+        password: Option<&str>,
+        headers: &HeaderMap,
+        ip: IpAddr,
+    ) -> Result<Share, FileError> {
+        let keys = [throttle::ip_key(ip), throttle::share_key(url)];
+        throttle::check(&keys).map_err(FileError::TooManyAttempts)?;
+        let result = sqlx::query!(
+            r#"SELECT id AS "id!", path, password, album_id, expires_at, max_downloads,
+            download_count, permission, max_file_size, max_total_size, uploaded_size, broken_at
+            FROM share WHERE url = ?"#,
+            url
+        )
+        .fetch_optional(db)
+        .await?;
+        let result = match result {
+            Some(r) => r,
+            None => {
+                // Guessing urls is throttled by IP
+                throttle::fail(&keys[..1]);
+                return Err(sqlx::Error::RowNotFound.into());
+            }
+        };
+        let session = match &result.password {
+            Some(hash) if has_session(headers, result.id, url, hash) => None,
+            Some(hash) => match password {
+                Some(p) if check_hash(&p.to_string(), hash) => {
+                    throttle::succeed(&keys[1]);
+                    Some(session_cookie(result.id, url, hash)?)
+                }
+                _ => {
+                    throttle::fail(&keys);
+                    return Err(FileError::WrongPassword);
+                }
+            },
+            None => None,
+        };
+        if result
+            .expires_at
+            .is_some_and(|e| e <= get_unix_timestamp() as i64)
+        {
+            return Err(FileError::Expired);
+        }
+        if result
+            .max_downloads
+            .is_some_and(|m| result.download_count >= m)
+        {
+            return Err(FileError::DownloadLimitReached);
+        }
+        if result.broken_at.is_some() {
+            return Err(FileError::Removed);
+        }
+        let root = match result.album_id {
+            Some(album_id) => ShareRoot::Album(album_paths(db, album_id).await?),
+            None => {
+                let path = result.path.ok_or(FileError::PathError)?;
+                ShareRoot::Path(check_path(storage, &path)?)
+            }
+        };
+        Ok(Share {
+            id: result.id,
+            url: url.to_string(),
+            root,
+            permission: Permission::parse(&result.permission).ok_or(FileError::ServerError)?,
+            max_file_size: result.max_file_size,
+            max_total_size: result.max_total_size,
+            uploaded_size: result.uploaded_size,
+            session,
+        })
+    }
+
+    /// Files of "drop" share can't be read
+    fn check_readable(&self) -> Result<(), FileError> {
+        match self.permission {
+            Permission::Drop => Err(FileError::Forbidden),
+            _ => Ok(()),
+        }
+    }
+
+    /// Path of `file_path` in shared folder, which can be changed by visitors.
+    /// The shared folder itself and albums can't be changed
+    async fn resolve_writable(
+        &self,
+        storage: &dyn StorageBackend,
+        file_path: &str,
+    ) -> Result<PathBuf, FileError> {
+        let root = match &self.root {
+            ShareRoot::Path(root) if storage.is_dir(root).await => root,
+            _ => return Err(FileError::Forbidden),
+        };
+        let path = self.resolve(storage, file_path).await?;
+        // Checked again, the path is written
+        let path = check_path(storage, path.to_str().ok_or(FileError::PathError)?)?;
+        if !path.starts_with(root) || path == *root {
+            return Err(FileError::PathError);
+        }
+        Ok(path)
+    }
+
+    /// Add cookie of new session to response
+    fn respond(&self, mut res: Response) -> Response {
+        let cookie = self.session.as_deref().map(HeaderValue::from_str);
+        if let Some(Ok(cookie)) = cookie {
+            res.headers_mut().append(header::SET_COOKIE, cookie);
+        }
+        res
+    }
+
+    /// Update last access time, and count a download if `download` is set.
+    /// Fail if download limit is reached by other requests
+    async fn record_access(&self, db: &SqlitePool, download: bool) -> Result<(), FileError> {
+        let now = get_unix_timestamp() as i64;
+        let count = i64::from(download);
+        let result = sqlx::query!(
+            "UPDATE share SET download_count = download_count + ?, last_access_at = ?
+            WHERE id = ? AND (? = 0 OR max_downloads IS NULL OR download_count < max_downloads)",
+            count,
+            now,
+            self.id,
+            count
+        )
+        .execute(db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(FileError::DownloadLimitReached);
+        }
+        Ok(())
+    }
+
+    /// Path of `file_path` in share
+    async fn resolve(
+        &self,
+        storage: &dyn StorageBackend,
+        file_path: &str,
+    ) -> Result<PathBuf, FileError> {
+        match &self.root {
+            ShareRoot::Album(paths) => {
+                let path = check_path(storage, file_path)?;
+                // Only files in album can be read
+                if !paths.contains(&path) {
+                    return Err(FileError::PathError);
+                }
+                Ok(path)
+            }
+            ShareRoot::Path(root) => {
+                let mut path = root.clone();
+                // When share file is single file, don't concat file_path
+                if storage.is_dir(root).await {
+                    // Because of the user-input `file_path`, we should check it and `path` again
+                    path.push(check_path(storage, file_path)?);
+                    if !storage.contains(&path) {
+                        return Err(FileError::PathError);
+                    }
+                }
+                Ok(path)
+            }
+        }
+    }
+
+    /// Download URL of `path` through the share, `None` if it isn't shared
+    fn download_url(&self, path: &Path) -> Option<String> {
+        let file_path = match &self.root {
+            ShareRoot::Album(paths) => paths.iter().find(|p| *p == path)?.as_path(),
+            ShareRoot::Path(root) => path.strip_prefix(root).ok()?,
+        };
+        Some(format!(
+            "/api/v1/share?url={}&file_path={}&download=true",
+            utf8_percent_encode(&self.url, PATH_SET),
+            utf8_percent_encode(file_path.to_str()?, PATH_SET)
+        ))
+    }
+}
+
+/// Get share file/folder, every access is recorded
 pub async fn get_share_file(
     Query(args): Query<QueryShareArgs>,
     Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Result<Response, FileError> {
-    let result = sqlx::query!("SELECT * FROM share WHERE url = ?", args.url)
-        .fetch_one(&db)
+    let download = args.download == Some(true);
+    let access = Access::new(
+        &db,
+        &args.url,
+        addr.ip(),
+        &headers,
+        &args.file_path,
+        download,
+    );
+    let result = read_share(&args, &db, storage.as_ref(), addr.ip(), &headers).await;
+    access.record(result).await
+}
+
+async fn read_share(
+    args: &QueryShareArgs,
+    db: &SqlitePool,
+    storage: &dyn StorageBackend,
+    ip: IpAddr,
+    headers: &HeaderMap,
+) -> Result<Response, FileError> {
+    let password = args.password.as_deref();
+    let share = Share::find(db, storage, &args.url, password, headers, ip).await?;
+    share.check_readable()?;
+    let download = args.download == Some(true);
+    if download && !share.permission.can_download() {
+        return Err(FileError::Forbidden);
+    }
+    if let ShareRoot::Album(paths) = &share.root {
+        if check_path(storage, &args.file_path)?.as_os_str().is_empty() {
+            share.record_access(db, false).await?;
+            let files = album_files(storage, paths).await;
+            return Ok(share.respond(Json(files).into_response()));
+        }
+    }
+    let path = share.resolve(storage, &args.file_path).await?;
+    if storage.is_dir(&path).await {
+        let files = File::read_dir(storage, &path).await?;
+        share.record_access(db, false).await?;
+        Ok(share.respond(Json(files).into_response()))
+    } else {
+        let res = share_response(storage, &path, headers, download).await?;
+        // Counted before sending, so concurrent downloads can't exceed the limit.
+        // Requests of a later part or a cached file aren't counted
+        let counted = download && starts_download(&res);
+        share.record_access(db, counted).await?;
+        Ok(share.respond(res))
+    }
+}
+
+/// Upload file to share with multipart, return its name.
+/// Files in "drop" share are renamed if the name is used, so nothing is overwritten.
+/// In other shares, existing files can't be uploaded like `upload_file`
+pub async fn upload_share_file(
+    Query(args): Query<ShareUploadArgs>,
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, FileError> {
+    let password = args.password.as_deref();
+    let ip = addr.ip();
+    let share = Share::find(&db, storage.as_ref(), &args.url, password, &headers, ip).await?;
+    let storage = share_storage(&storage, &db);
+    if share.permission.can_upload() {
+        let folder = match &share.root {
+            ShareRoot::Path(_) => share.resolve(storage.as_ref(), &args.file_path).await?,
+            ShareRoot::Album(_) => return Err(FileError::Forbidden),
+        };
+        if !storage.is_dir(&folder).await {
+            return Err(FileError::PathError);
+        }
+        let path = save_upload(storage.as_ref(), &folder, &mut multipart).await?;
+        share.record_access(&db, false).await?;
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        return Ok(share.respond(Json(json!({ "name": name })).into_response()));
+    }
+    let folder = match (&share.root, share.permission) {
+        (ShareRoot::Path(root), Permission::Drop) if storage.is_dir(root).await => root,
+        _ => return Err(FileError::Forbidden),
+    };
+    let (field, name) = next_file(&mut multipart).await?;
+    let (path, _guard) = free_name(storage.as_ref(), folder, &name).await?;
+    // Read one more byte to find files over the limit
+    let remaining = share
+        .max_total_size
+        .map(|m| (m - share.uploaded_size).max(0) as u64);
+    let max_file_size = share.max_file_size.map(|m| m as u64);
+    let limit = remaining.into_iter().chain(max_file_size).min();
+    let mut data = StreamReader::new(field.map_err(io::Error::other))
+        .take(limit.map_or(u64::MAX, |l| l.saturating_add(1)));
+    let size = match write_path(storage.as_ref(), &path, &mut data).await {
+        Ok(size) => size,
+        Err(e) => {
+            // Don't leave broken file
+            let _ = remove_path(storage.as_ref(), &path).await;
+            return Err(e.into());
+        }
+    };
+    if limit.is_some_and(|l| size > l) {
+        let _ = remove_path(storage.as_ref(), &path).await;
+        return Err(match max_file_size {
+            Some(m) if size > m => FileError::TooLarge,
+            _ => FileError::QuotaExceeded(remaining.unwrap_or_default()),
+        });
+    }
+    // Other uploads may be finished at the same time
+    let added = size as i64;
+    let result = sqlx::query!(
+        "UPDATE share SET uploaded_size = uploaded_size + ?
+        WHERE id = ? AND (max_total_size IS NULL OR uploaded_size + ? <= max_total_size)",
+        added,
+        share.id,
+        added
+    )
+    .execute(&db)
+    .await?;
+    if result.rows_affected() == 0 {
+        let _ = remove_path(storage.as_ref(), &path).await;
+        return Err(FileError::QuotaExceeded(0));
+    }
+    share.record_access(&db, false).await?;
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    Ok(share.respond(Json(json!({ "name": name })).into_response()))
+}
+
+/// Create folder in share with "upload" or "edit" permission
+pub async fn create_share_folder(
+    Query(args): Query<QueryShareArgs>,
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response, FileError> {
+    let password = args.password.as_deref();
+    let ip = addr.ip();
+    let share = Share::find(&db, storage.as_ref(), &args.url, password, &headers, ip).await?;
+    if !share.permission.can_upload() {
+        return Err(FileError::Forbidden);
+    }
+    let path = share
+        .resolve_writable(storage.as_ref(), &args.file_path)
         .await?;
-    if args.password != result.password {
-        return Err(FileError::ContentError);
+    let storage = share_storage(&storage, &db);
+    create_path(storage.as_ref(), &path).await?;
+    share.record_access(&db, false).await?;
+    Ok(share.respond(StatusCode::OK.into_response()))
+}
+
+/// Rename file in share with "edit" permission, `to` is a path in share too
+pub async fn rename_share_file(
+    Query(args): Query<ShareRenameArgs>,
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response, FileError> {
+    let password = args.password.as_deref();
+    let ip = addr.ip();
+    let share = Share::find(&db, storage.as_ref(), &args.url, password, &headers, ip).await?;
+    if share.permission != Permission::Edit {
+        return Err(FileError::Forbidden);
     }
-    let path = result.path.ok_or(FileError::PathError)?;
-    let mut path = concat_path_str(&path);
-    // When share file is single file, don't concat file_path
-    if !path.is_file() {
-        path.push(args.file_path);
+    let from = share
+        .resolve_writable(storage.as_ref(), &args.file_path)
+        .await?;
+    let to = share.resolve_writable(storage.as_ref(), &args.to).await?;
+    let storage = share_storage(&storage, &db);
+    rename_path(storage.as_ref(), &from, &to).await?;
+    share.record_access(&db, false).await?;
+    Ok(share.respond(StatusCode::OK.into_response()))
+}
+
+/// Delete file or empty folder in share with "edit" permission
+pub async fn delete_share_file(
+    Query(args): Query<QueryShareArgs>,
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response, FileError> {
+    let password = args.password.as_deref();
+    let ip = addr.ip();
+    let share = Share::find(&db, storage.as_ref(), &args.url, password, &headers, ip).await?;
+    if share.permission != Permission::Edit {
+        return Err(FileError::Forbidden);
     }
-    // Because of the user-input `file_path`, we should check `path` again
-    if is_traversal(&path) {
+    let path = share
+        .resolve_writable(storage.as_ref(), &args.file_path)
+        .await?;
+    let storage = share_storage(&storage, &db);
+    remove_path(storage.as_ref(), &path).await?;
+    share.record_access(&db, false).await?;
+    Ok(share.respond(StatusCode::OK.into_response()))
+}
+
+/// Check password of share and set session cookie,
+/// so password isn't sent again in query string
+pub async fn add_share_session(
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(args): Json<ShareSessionArgs>,
+) -> Result<Response, FileError> {
+    let share = Share::find(
+        &db,
+        storage.as_ref(),
+        &args.url,
+        Some(&args.password),
+        &headers,
+        addr.ip(),
+    )
+    .await?;
+    Ok(share.respond(StatusCode::OK.into_response()))
+}
+
+/// Get shared file rendered to HTML, relative links in Markdown
+/// are downloaded through the share. Rendering isn't counted as a download
+pub async fn render_share_file(
+    Query(args): Query<QueryShareArgs>,
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response, FileError> {
+    let access = Access::new(&db, &args.url, addr.ip(), &headers, &args.file_path, false);
+    let result = render_share(&args, &db, storage.as_ref(), addr.ip(), &headers).await;
+    access.record(result).await
+}
+
+async fn render_share(
+    args: &QueryShareArgs,
+    db: &SqlitePool,
+    storage: &dyn StorageBackend,
+    ip: IpAddr,
+    headers: &HeaderMap,
+) -> Result<Response, FileError> {
+    let password = args.password.as_deref();
+    let mut share = Share::find(db, storage, &args.url, password, headers, ip).await?;
+    share.check_readable()?;
+    let path = share.resolve(storage, &args.file_path).await?;
+    if storage.is_dir(&path).await {
         return Err(FileError::PathError);
     }
-    if path.is_dir() {
-        Ok(Json(File::read_dir(&path)?).into_response())
-    } else {
-        if args.download == Some(true) {
-            Ok(read(&path)?.into_response())
-        } else {
-            Ok(Json(File::new(&path)?).into_response())
+    share.record_access(db, false).await?;
+    let scope = format!("share:{}", share.url);
+    let session = share.session.take();
+    let link: LinkFn = Box::new(move |p| share.download_url(p));
+    let mut res = render_response(storage, &path, headers, &scope, link).await?;
+    if let Some(Ok(cookie)) = session.as_deref().map(HeaderValue::from_str) {
+        res.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+    Ok(res)
+}
+
+/// Storage written by visitors of share, with folder quotas applied.
+/// Visitors aren't users, so user quota isn't
+fn share_storage(storage: &Storage, db: &SqlitePool) -> Storage {
+    if CONFIG.folder_quotas.is_empty() {
+        return storage.clone();
+    }
+    Arc::new(QuotaStorage::new(
+        storage.clone(),
+        db.clone(),
+        "",
+        None,
+        CONFIG.folder_quotas.clone(),
+    ))
+}
+
+/// Path in `folder` not used by other files, "a (1).txt" is used if "a.txt" exists.
+/// The guard keeps other uploads from taking it
+async fn free_name(
+    storage: &dyn StorageBackend,
+    folder: &Path,
+    name: &str,
+) -> Result<(PathBuf, WriteGuard), FileError> {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    for i in 0..=MAX_RENAMES {
+        let name = match i {
+            0 => name.to_string(),
+            i => format!("{} ({}){}", stem, i, ext),
+        };
+        let path = folder.join(name);
+        let path = check_path(storage, path.to_str().ok_or(FileError::PathError)?)?;
+        if storage.exists(&path).await {
+            continue;
+        }
+        if let Some(guard) = WriteGuard::new(&path) {
+            // Checked again, it may be written before the guard is taken
+            if !storage.exists(&path).await {
+                return Ok((path, guard));
+            }
         }
     }
+    Err(FileError::PathError)
+}
+
+/// Name of session cookie, each share has its own
+fn session_name(url: &str) -> String {
+    format!("share-{}", url)
+}
+
+/// Part of SHA-256 of password hash, changes with password
+fn session_key(hash: &str) -> String {
+    hex::encode(&Sha256::digest(hash.as_bytes())[..8])
+}
+
+/// `Set-Cookie` of new session of share
+fn session_cookie(id: i64, url: &str, hash: &str) -> Result<String, FileError> {
+    let session = ShareSession {
+        id,
+        url: url.to_string(),
+        key: session_key(hash),
+        exp: get_unix_timestamp() + SESSION_AGE,
+    };
+    let token = sign_token(&session).ok_or(FileError::ServerError)?;
+    Ok(format!(
+        "{}={}; Max-Age={}; Path=/api/v1/share; HttpOnly; SameSite=Lax",
+        session_name(url),
+        token,
+        SESSION_AGE
+    ))
+}
+
+/// Check request has a valid session cookie of share
+fn has_session(headers: &HeaderMap, id: i64, url: &str, hash: &str) -> bool {
+    headers
+        .typed_get::<Cookie>()
+        .and_then(|c| verify_token::<ShareSession>(c.get(&session_name(url))?))
+        .is_some_and(|s| s.id == id && s.url == url && s.key == session_key(hash))
+}
+
+/// Content of shared file if `download` is set, otherwise its information
+async fn share_response(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    headers: &HeaderMap,
+    download: bool,
+) -> Result<Response, FileError> {
+    if !download {
+        return Ok(Json(File::new(storage, path).await?).into_response());
+    }
+    let mut res = file_response(storage, path, headers).await?;
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    if let Ok(v) = HeaderValue::from_str(&content_disposition(name)) {
+        res.headers_mut().insert(header::CONTENT_DISPOSITION, v);
+    }
+    Ok(res)
+}
+
+/// "attachment" with ASCII name for old clients and UTF-8 name for others
+fn content_disposition(name: &str) -> String {
+    let ascii: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii,
+        utf8_percent_encode(name, PATH_SET)
+    )
+}
+
+/// Whether response sends the file from its start, seeking videos isn't another download
+fn starts_download(res: &Response) -> bool {
+    match res.status() {
+        StatusCode::OK => true,
+        StatusCode::PARTIAL_CONTENT => res
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            // Several ranges start at the first one
+            .is_none_or(|r| r.starts_with("bytes 0-")),
+        _ => false,
+    }
 }
 
 /// Get all share file
@@ -124,8 +969,634 @@ pub async fn get_share_index(
     Extension(db): Extension<SqlitePool>,
     _: Claim,
 ) -> Result<Json<Vec<ShareIndex>>, FileError> {
-    let result = sqlx::query_as!(ShareIndex, "SELECT path, url, password FROM share")
-        .fetch_all(&db)
-        .await?;
+    let now = get_unix_timestamp() as i64;
+    let result = sqlx::query_as!(
+        ShareIndex,
+        r#"SELECT id AS "id!", path, url, password IS NOT NULL AS "has_password!: bool",
+        album_id, expires_at, MAX(expires_at - ?, 0) AS "remaining: i64", max_downloads,
+        download_count, last_access_at, label, permission, max_file_size, max_total_size,
+        uploaded_size, broken_at FROM share ORDER BY id"#,
+        now
+    )
+    .fetch_all(&db)
+    .await?;
     Ok(Json(result))
 }
+
+/// Hash passwords of shares created before passwords were hashed
+pub async fn hash_share_passwords(db: &SqlitePool) -> Result<(), FileError> {
+    let rows = sqlx::query!("SELECT id, password FROM share WHERE password IS NOT NULL")
+        .fetch_all(db)
+        .await?;
+    for row in rows {
+        let password = match row.password {
+            Some(p) if PasswordHash::new(&p).is_err() => p,
+            _ => continue,
+        };
+        let hash = gen_hash(&password).ok_or(FileError::ServerError)?;
+        sqlx::query!("UPDATE share SET password = ? WHERE id = ?", hash, row.id)
+            .execute(db)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Remove shares expired longer than `FS_SHARE_EXPIRED_KEEP`, return removed count.
+/// Visitors of shares expired recently are told they're expired,
+/// and shares with recorded accesses are kept until the accesses are removed
+pub async fn purge_expired(db: &SqlitePool) -> Result<u64, FileError> {
+    let before = get_unix_timestamp() as i64 - CONFIG.share_expired_keep as i64;
+    let result = sqlx::query!(
+        "DELETE FROM share WHERE expires_at <= ?
+        AND NOT EXISTS (SELECT 1 FROM share_access WHERE share_id = share.id)",
+        before
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Remove expired shares and old access records periodically, until the server stops
+pub async fn purge_shares(db: SqlitePool) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = purge_access(&db).await {
+            tracing::warn!("failed to remove old share accesses: {}", e);
+        }
+        match purge_expired(&db).await {
+            Ok(0) => (),
+            Ok(n) => tracing::info!("removed {} expired shares", n),
+            Err(e) => tracing::warn!("failed to remove expired shares: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use axum::{body::Body, extract::FromRequest, http::Request};
+
+    use super::*;
+    use crate::{
+        config::FolderQuota,
+        file::{
+            fixture::{claim, pool},
+            storage::MemoryStorage,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_share_expire() {
+        let db = pool().await;
+        let storage: Storage = Arc::new(MemoryStorage::new());
+        storage
+            .write(Path::new("a.txt"), &mut &b"a"[..])
+            .await
+            .unwrap();
+        let now = get_unix_timestamp() as i64;
+        let add = |expires_at| AddShareArgs {
+            path: "a.txt".into(),
+            expires_at,
+            ..Default::default()
+        };
+        // Expiration must be in the future
+        assert!(add_share_file(
+            Query(add(Some(now))),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            claim()
+        )
+        .await
+        .is_err());
+        add_share_file(
+            Query(add(Some(now + 100))),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        let Json(index) = get_share_index(Extension(db.clone()), claim())
+            .await
+            .unwrap();
+        assert!(index[0].remaining.is_some_and(|r| r > 0 && r <= 100));
+        let url = index[0].url.clone().unwrap();
+        let query = || QueryShareArgs {
+            url: url.clone(),
+            file_path: String::new(),
+            password: None,
+            download: Some(true),
+        };
+        get_share_file(
+            Query(query()),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        sqlx::query!("UPDATE share SET expires_at = ?", now)
+            .execute(&db)
+            .await
+            .unwrap();
+        // Recently expired share is kept
+        assert_eq!(purge_expired(&db).await.unwrap(), 0);
+        let res = get_share_file(
+            Query(query()),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(res, Err(FileError::Expired)));
+        let expired = now - CONFIG.share_expired_keep as i64;
+        sqlx::query!("UPDATE share SET expires_at = ?", expired)
+            .execute(&db)
+            .await
+            .unwrap();
+        // Accesses of the share are kept for `FS_SHARE_LOG_DAYS`
+        assert_eq!(purge_expired(&db).await.unwrap(), 0);
+        sqlx::query!("DELETE FROM share_access")
+            .execute(&db)
+            .await
+            .unwrap();
+        assert_eq!(purge_expired(&db).await.unwrap(), 1);
+        let res = get_share_file(
+            Query(query()),
+            Extension(db),
+            Extension(storage),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(FileError::DatabaseError(sqlx::Error::RowNotFound))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_share_download_limit() {
+        let db = pool().await;
+        let storage: Storage = Arc::new(MemoryStorage::new());
+        storage
+            .write(Path::new("a.txt"), &mut &b"abc"[..])
+            .await
+            .unwrap();
+        let args = AddShareArgs {
+            path: "a.txt".into(),
+            max_downloads: Some(2),
+            ..Default::default()
+        };
+        add_share_file(
+            Query(args),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        let url = sqlx::query!("SELECT url FROM share")
+            .fetch_one(&db)
+            .await
+            .unwrap()
+            .url
+            .unwrap();
+        let get = |download, range: &'static str| {
+            let mut headers = HeaderMap::new();
+            if !range.is_empty() {
+                headers.insert(header::RANGE, HeaderValue::from_static(range));
+            }
+            let args = QueryShareArgs {
+                url: url.clone(),
+                file_path: String::new(),
+                password: None,
+                download: Some(download),
+            };
+            get_share_file(
+                Query(args),
+                Extension(db.clone()),
+                Extension(storage.clone()),
+                ConnectInfo(addr()),
+                headers,
+            )
+        };
+        // Information of file, rendered file and later parts aren't counted
+        get(false, "").await.unwrap();
+        let args = QueryShareArgs {
+            url: url.clone(),
+            file_path: String::new(),
+            password: None,
+            download: None,
+        };
+        render_share_file(
+            Query(args),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let res = get(true, "bytes=1-").await.unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let res = get(true, "").await.unwrap();
+        assert_eq!(
+            res.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"a.txt\"; filename*=UTF-8''a.txt"
+        );
+        get(true, "bytes=0-0").await.unwrap();
+        assert!(matches!(
+            get(true, "").await,
+            Err(FileError::DownloadLimitReached)
+        ));
+        let Json(index) = get_share_index(Extension(db.clone()), claim())
+            .await
+            .unwrap();
+        assert_eq!(index[0].download_count, 2);
+        assert!(index[0].last_access_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_share_password() {
+        let db = pool().await;
+        let storage: Storage = Arc::new(MemoryStorage::new());
+        storage
+            .write(Path::new("a.txt"), &mut &b"a"[..])
+            .await
+            .unwrap();
+        let args = AddShareArgs {
+            path: "a.txt".into(),
+            password: Some("secret".into()),
+            ..Default::default()
+        };
+        add_share_file(
+            Query(args),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        let Json(index) = get_share_index(Extension(db.clone()), claim())
+            .await
+            .unwrap();
+        assert!(index[0].has_password);
+        let url = index[0].url.clone().unwrap();
+        let hash = sqlx::query!("SELECT password FROM share")
+            .fetch_one(&db)
+            .await
+            .unwrap()
+            .password
+            .unwrap();
+        assert_ne!(hash, "secret");
+        let get = |password: Option<&str>, headers| {
+            let args = QueryShareArgs {
+                url: url.clone(),
+                file_path: String::new(),
+                password: password.map(Into::into),
+                download: None,
+            };
+            get_share_file(
+                Query(args),
+                Extension(db.clone()),
+                Extension(storage.clone()),
+                ConnectInfo(addr()),
+                headers,
+            )
+        };
+        assert!(matches!(
+            get(Some("wrong"), HeaderMap::new()).await,
+            Err(FileError::WrongPassword)
+        ));
+        let res = get(Some("secret"), HeaderMap::new()).await.unwrap();
+        let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap();
+        // Session is used instead of password
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, cookie.parse().unwrap());
+        let res = get(None, headers).await.unwrap();
+        assert!(res.headers().get(header::SET_COOKIE).is_none());
+        assert!(get(None, HeaderMap::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_multiple_shares() {
+        let db = pool().await;
+        let storage: Storage = Arc::new(MemoryStorage::new());
+        storage
+            .write(Path::new("a.txt"), &mut &b"a"[..])
+            .await
+            .unwrap();
+        for label in ["x", "y"] {
+            let args = AddShareArgs {
+                path: "a.txt".into(),
+                label: Some(label.into()),
+                ..Default::default()
+            };
+            add_share_file(
+                Query(args),
+                Extension(db.clone()),
+                Extension(storage.clone()),
+                claim(),
+            )
+            .await
+            .unwrap();
+        }
+        let Json(index) = get_share_index(Extension(db.clone()), claim())
+            .await
+            .unwrap();
+        assert_eq!(index.len(), 2);
+        assert_ne!(index[0].url, index[1].url);
+        let update = UpdateShareArgs {
+            label: Some(String::new()),
+            password: Some("p".into()),
+            expires_at: None,
+            max_downloads: Some(1),
+        };
+        update_share(
+            Query(ShareIdArgs { id: index[0].id }),
+            Extension(db.clone()),
+            claim(),
+            Json(update),
+        )
+        .await
+        .unwrap();
+        delete_share(
+            Query(ShareIdArgs { id: index[1].id }),
+            Extension(db.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        // Nothing is changed if any field is rejected
+        let update = UpdateShareArgs {
+            label: Some("z".into()),
+            password: Some(String::new()),
+            expires_at: None,
+            max_downloads: Some(-1),
+        };
+        let res = update_share(
+            Query(ShareIdArgs { id: index[0].id }),
+            Extension(db.clone()),
+            claim(),
+            Json(update),
+        )
+        .await;
+        assert!(matches!(res, Err(FileError::ContentError)));
+        let Json(index) = get_share_index(Extension(db), claim()).await.unwrap();
+        assert_eq!(index.len(), 1);
+        assert!(index[0].has_password && index[0].label.is_none());
+        assert_eq!(index[0].max_downloads, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_drop_share() {
+        let db = pool().await;
+        let storage: Storage = Arc::new(MemoryStorage::new());
+        storage.mkdir(Path::new("in")).await.unwrap();
+        storage
+            .write(Path::new("in/a.txt"), &mut &b"a"[..])
+            .await
+            .unwrap();
+        let args = AddShareArgs {
+            path: "in".into(),
+            permission: Some("drop".into()),
+            max_file_size: Some(10),
+            ..Default::default()
+        };
+        add_share_file(
+            Query(args),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            claim(),
+        )
+        .await
+        .unwrap();
+        let url = sqlx::query!("SELECT url FROM share")
+            .fetch_one(&db)
+            .await
+            .unwrap()
+            .url
+            .unwrap();
+        let args = QueryShareArgs {
+            url,
+            file_path: String::new(),
+            password: None,
+            download: None,
+        };
+        let res = get_share_file(
+            Query(args),
+            Extension(db),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(res, Err(FileError::Forbidden)));
+        let (path, _guard) = free_name(storage.as_ref(), Path::new("in"), "a.txt")
+            .await
+            .unwrap();
+        assert_eq!(path, Path::new("in/a (1).txt"));
+        // The name is taken by the upload above
+        let (path, _) = free_name(storage.as_ref(), Path::new("in"), "a.txt")
+            .await
+            .unwrap();
+        assert_eq!(path, Path::new("in/a (2).txt"));
+    }
+
+    #[tokio::test]
+    async fn test_share_permissions() {
+        let db = pool().await;
+        let storage: Storage = Arc::new(MemoryStorage::new());
+        storage.mkdir(Path::new("s")).await.unwrap();
+        storage
+            .write(Path::new("s/a.txt"), &mut &b"a"[..])
+            .await
+            .unwrap();
+        storage
+            .write(Path::new("b.txt"), &mut &b"b"[..])
+            .await
+            .unwrap();
+        for permission in ["view", "edit"] {
+            let args = AddShareArgs {
+                path: "s".into(),
+                permission: Some(permission.into()),
+                ..Default::default()
+            };
+            add_share_file(
+                Query(args),
+                Extension(db.clone()),
+                Extension(storage.clone()),
+                claim(),
+            )
+            .await
+            .unwrap();
+        }
+        let urls: Vec<_> = sqlx::query!("SELECT url FROM share ORDER BY id")
+            .fetch_all(&db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.url.unwrap())
+            .collect();
+        let query = |url: &str, file_path: &str| QueryShareArgs {
+            url: url.into(),
+            file_path: file_path.into(),
+            password: None,
+            download: Some(true),
+        };
+        let res = get_share_file(
+            Query(query(&urls[0], "a.txt")),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(res, Err(FileError::Forbidden)));
+        let res = delete_share_file(
+            Query(query(&urls[0], "a.txt")),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(res, Err(FileError::Forbidden)));
+        create_share_folder(
+            Query(query(&urls[1], "c")),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let rename = |to: &str| ShareRenameArgs {
+            url: urls[1].clone(),
+            password: None,
+            file_path: "a.txt".into(),
+            to: to.into(),
+        };
+        // Files can't be moved out of share
+        let res = rename_share_file(
+            Query(rename("../a.txt")),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(res.is_err());
+        rename_share_file(
+            Query(rename("c/a.txt")),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert!(storage.exists(Path::new("s/c/a.txt")).await);
+        // Shared folder itself can't be deleted
+        let res = delete_share_file(
+            Query(query(&urls[1], "")),
+            Extension(db.clone()),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(res.is_err());
+        delete_share_file(
+            Query(query(&urls[1], "c/a.txt")),
+            Extension(db),
+            Extension(storage.clone()),
+            ConnectInfo(addr()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert!(!storage.exists(Path::new("s/c/a.txt")).await);
+        assert!(storage.exists(Path::new("b.txt")).await);
+    }
+
+    #[tokio::test]
+    async fn test_share_quota() {
+        let db = pool().await;
+        let inner: Storage = Arc::new(MemoryStorage::new());
+        inner.mkdir(Path::new("s")).await.unwrap();
+        let folders = vec![FolderQuota {
+            path: "s".into(),
+            size: 5,
+        }];
+        let storage: Storage = Arc::new(QuotaStorage::new(
+            inner.clone(),
+            db.clone(),
+            "",
+            None,
+            folders,
+        ));
+        // Files of "drop" share are written like uploads
+        for permission in ["upload", "drop"] {
+            let args = AddShareArgs {
+                path: "s".into(),
+                permission: Some(permission.into()),
+                ..Default::default()
+            };
+            add_share_file(
+                Query(args),
+                Extension(db.clone()),
+                Extension(storage.clone()),
+                claim(),
+            )
+            .await
+            .unwrap();
+            let url = sqlx::query!("SELECT url FROM share ORDER BY id DESC")
+                .fetch_one(&db)
+                .await
+                .unwrap()
+                .url
+                .unwrap();
+            let args = ShareUploadArgs {
+                url,
+                password: None,
+                file_path: String::new(),
+            };
+            let res = upload_share_file(
+                Query(args),
+                Extension(db.clone()),
+                Extension(storage.clone()),
+                ConnectInfo(addr()),
+                HeaderMap::new(),
+                multipart("b.txt", "0123456789").await,
+            )
+            .await;
+            let res = res.err().unwrap().into_response();
+            assert_eq!(res.status(), StatusCode::INSUFFICIENT_STORAGE);
+            assert!(!inner.exists(Path::new("s/b.txt")).await);
+        }
+    }
+
+    /// Multipart body with one file
+    async fn multipart(name: &str, data: &str) -> Multipart {
+        let body = format!(
+            "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n--X--\r\n",
+            name, data
+        );
+        let req = Request::builder()
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+            .body(Body::from(body))
+            .unwrap();
+        Multipart::from_request(req, &()).await.unwrap()
+    }
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:1234".parse().unwrap()
+    }
+}
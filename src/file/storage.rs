@@ -0,0 +1,785 @@
+use std::fs::{canonicalize, Metadata};
+use std::io;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::async_trait;
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::CONFIG;
+
+/// Suffix of temporary file that `LocalStorage` writes before replacing the file
+pub const WRITE_SUFFIX: &str = ".upload";
+/// Suffixes of temporary files renamed to the files they replace,
+/// made by `LocalStorage::write`, `unshare` and dedupe
+const TEMP_SUFFIXES: [&str; 3] = [WRITE_SUFFIX, ".unshare", ".dedupe"];
+
+/// Shared storage, added to router as `Extension`
+pub type Storage = Arc<dyn StorageBackend>;
+/// Content of file returned by `StorageBackend::read`
+pub type Reader = Pin<Box<dyn AsyncRead + Send>>;
+
+#[derive(Clone, Debug)]
+pub struct Stat {
+    pub is_dir: bool,
+    pub size: u64,
+    /// Last modified time in unix timestamp
+    pub modified: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub stat: Stat,
+}
+
+/// Where files are stored.
+/// All paths are relative to the root of storage, and they are checked by `check_path` before
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Check if `path` stays inside storage, e.g. it is not a link to outside
+    fn contains(&self, path: &Path) -> bool;
+    /// Path in local file system, only for the features that need a real file
+    fn local_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+    /// Check if `path` can be created, modified or deleted
+    fn writable(&self, _path: &Path) -> bool {
+        true
+    }
+    /// Storage seen by users of `role`, `None` if it is the same for all users
+    fn for_role(&self, _role: &str) -> Option<Storage> {
+        None
+    }
+    /// Get entries of folder
+    async fn list(&self, path: &Path) -> io::Result<Vec<Entry>>;
+    async fn stat(&self, path: &Path) -> io::Result<Stat>;
+    /// Read whole file, or bytes in `range`
+    async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<Reader>;
+    /// Create or replace file with `data`, return written size
+    async fn write(
+        &self,
+        path: &Path,
+        data: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<u64>;
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Copy file, return copied size
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<u64>;
+    /// Delete file or empty folder
+    async fn delete(&self, path: &Path) -> io::Result<()>;
+    async fn mkdir(&self, path: &Path) -> io::Result<()>;
+
+    /// Max size of file at `path` allowed by quota, `None` if unlimited
+    async fn max_size(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Record file written through `local_path`, `old_size` is its size before writing
+    async fn written(&self, _path: &Path, _old_size: u64) -> io::Result<()> {
+        Ok(())
+    }
+    /// Hex encoded SHA-256 of file, `None` if it isn't known
+    async fn sha256(&self, _path: &Path) -> Option<String> {
+        None
+    }
+
+    async fn exists(&self, path: &Path) -> bool {
+        self.stat(path).await.is_ok()
+    }
+
+    async fn is_dir(&self, path: &Path) -> bool {
+        matches!(self.stat(path).await, Ok(Stat { is_dir: true, .. }))
+    }
+}
+
+/// Get storage seen by users of `role`
+pub fn role_storage(storage: &Storage, role: &str) -> Storage {
+    storage.for_role(role).unwrap_or_else(|| storage.clone())
+}
+
+/// Build storage from `CONFIG`, mounts replace the single folder if configured
+pub fn from_config() -> Storage {
+    if CONFIG.mounts.is_empty() {
+        return Arc::new(LocalStorage::new(CONFIG.folder_path.clone()));
+    }
+    let mounts = CONFIG
+        .mounts
+        .iter()
+        .map(|m| MountPoint {
+            name: m.name.clone(),
+            read_only: m.read_only,
+            roles: m.roles.clone(),
+            storage: Arc::new(LocalStorage::new(m.path.clone())),
+        })
+        .collect();
+    Arc::new(MountStorage::new(mounts))
+}
+
+/// Files in local folder
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> LocalStorage {
+        LocalStorage {
+            root: canonicalize(&root).unwrap_or(root),
+        }
+    }
+
+    fn full_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path)
+    }
+}
+
+impl From<Metadata> for Stat {
+    fn from(meta: Metadata) -> Self {
+        let modified = match meta.modified() {
+            Ok(m) => match m.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(m) => m.as_secs(),
+                Err(_) => 0,
+            },
+            Err(_) => 0,
+        };
+        Stat {
+            is_dir: meta.is_dir(),
+            size: meta.len(),
+            modified,
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    fn contains(&self, path: &Path) -> bool {
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return false;
+        }
+        // Path that doesn't exist can't be resolved by `canonicalize`,
+        // so check the nearest existing ancestor instead
+        let full = self.full_path(path);
+        for p in full.ancestors() {
+            match canonicalize(p) {
+                Ok(abs) => return abs.starts_with(&self.root),
+                // Broken link
+                Err(_) if p.symlink_metadata().is_ok() => return false,
+                Err(_) => continue,
+            }
+        }
+        false
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(self.full_path(path))
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        let mut entries = vec![];
+        let mut dir = fs::read_dir(self.full_path(path)).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let (name, meta) = match (entry.file_name().into_string(), entry.metadata().await) {
+                (Ok(n), Ok(m)) => (n, m),
+                _ => continue,
+            };
+            // Follow symbolic links
+            let meta = match meta.is_symlink() {
+                true => match fs::metadata(entry.path()).await {
+                    Ok(m) => m,
+                    Err(_) => continue,
+                },
+                false => meta,
+            };
+            entries.push(Entry {
+                name,
+                stat: meta.into(),
+            });
+        }
+        Ok(entries)
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<Stat> {
+        Ok(fs::metadata(self.full_path(path)).await?.into())
+    }
+
+    async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<Reader> {
+        let mut file = fs::File::open(self.full_path(path)).await?;
+        match range {
+            Some(r) => {
+                file.seek(io::SeekFrom::Start(r.start)).await?;
+                Ok(Box::pin(file.take(r.end.saturating_sub(r.start))))
+            }
+            None => Ok(Box::pin(file)),
+        }
+    }
+
+    async fn write(
+        &self,
+        path: &Path,
+        data: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<u64> {
+        let full = self.full_path(path);
+        // Written beside the file and renamed to it after all data is written,
+        // so a failed write keeps the old content, as do other links made by dedupe
+        let mut temp = full.as_os_str().to_owned();
+        temp.push(format!(".{:08x}{}", rand::random::<u32>(), WRITE_SUFFIX));
+        let temp = PathBuf::from(temp);
+        let result = async {
+            let size = write_new(&temp, &full, data).await?;
+            fs::rename(&temp, &full).await?;
+            Ok(size)
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temp).await;
+        }
+        result
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(self.full_path(from), self.full_path(to)).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        fs::copy(self.full_path(from), self.full_path(to)).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        let path = self.full_path(path);
+        if fs::metadata(&path).await?.is_dir() {
+            fs::remove_dir(path).await
+        } else {
+            fs::remove_file(path).await
+        }
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(self.full_path(path)).await
+    }
+}
+
+/// Write `data` into new file `temp`, with the permissions of file `full` it replaces
+async fn write_new(
+    temp: &Path,
+    full: &Path,
+    data: &mut (dyn AsyncRead + Send + Unpin),
+) -> io::Result<u64> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(temp)
+        .await?;
+    if let Ok(meta) = fs::metadata(full).await {
+        file.set_permissions(meta.permissions()).await?;
+    }
+    let size = tokio::io::copy(data, &mut file).await?;
+    file.flush().await?;
+    Ok(size)
+}
+
+/// Check if `path` is a temporary file made by the server beside the file it replaces
+pub fn is_server_temp(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str());
+    name.is_some_and(|n| TEMP_SUFFIXES.iter().any(|s| n.ends_with(s)))
+}
+
+/// Check if `path` is a temporary file renamed to the file it replaces, made by
+/// the server or by editors, like "name~", ".name.swp" and ".goutputstream-XXX"
+pub fn is_temp_file(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => n,
+        None => return false,
+    };
+    is_server_temp(path) || name.starts_with('.') || name.ends_with('~') || name.ends_with(".tmp")
+}
+
+/// Check if local file has other hard links
+async fn is_shared(local: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let Ok(meta) = fs::symlink_metadata(local).await {
+            return meta.is_file() && meta.nlink() > 1;
+        }
+    }
+    false
+}
+
+/// Give local file its own copy of data before it is changed in place,
+/// so the files linked by dedupe aren't changed together
+pub async fn unshare(local: &Path) -> io::Result<()> {
+    if !is_shared(local).await {
+        return Ok(());
+    }
+    let mut temp = local.as_os_str().to_owned();
+    temp.push(".unshare");
+    fs::copy(local, &temp).await?;
+    fs::rename(&temp, local).await
+}
+
+/// Storage mounted as a top-level folder of `MountStorage`
+pub struct MountPoint {
+    pub name: String,
+    pub read_only: bool,
+    /// Roles of users who can see it, empty means all users
+    pub roles: Vec<String>,
+    pub storage: Storage,
+}
+
+/// Several named storages, shown as top-level folders.
+/// The root and the mount points themselves can't be modified
+pub struct MountStorage {
+    mounts: Arc<Vec<MountPoint>>,
+    /// Only mounts allowed for this role are visible, all of them if `None`
+    role: Option<String>,
+}
+
+impl MountStorage {
+    pub fn new(mounts: Vec<MountPoint>) -> MountStorage {
+        MountStorage {
+            mounts: Arc::new(mounts),
+            role: None,
+        }
+    }
+
+    fn visible(&self) -> impl Iterator<Item = &MountPoint> {
+        self.mounts.iter().filter(|m| match &self.role {
+            Some(r) => m.roles.is_empty() || m.roles.contains(r),
+            None => true,
+        })
+    }
+
+    /// Find mount of `path`, and path inside the mount
+    fn resolve(&self, path: &Path) -> io::Result<(&MountPoint, PathBuf)> {
+        let mut components = path.components();
+        let mount = match components.next() {
+            Some(Component::Normal(name)) => self.visible().find(|m| name == m.name.as_str()),
+            _ => None,
+        };
+        let mount = mount.ok_or(io::ErrorKind::NotFound)?;
+        Ok((mount, components.as_path().to_path_buf()))
+    }
+
+    /// Like `resolve`, but fails if the path can't be modified
+    fn resolve_writable(&self, path: &Path) -> io::Result<(&MountPoint, PathBuf)> {
+        let (mount, rest) = self.resolve(path)?;
+        if mount.read_only || rest.as_os_str().is_empty() {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        Ok((mount, rest))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MountStorage {
+    fn contains(&self, path: &Path) -> bool {
+        if path.as_os_str().is_empty() {
+            return true;
+        }
+        match self.resolve(path) {
+            Ok((mount, rest)) => mount.storage.contains(&rest),
+            Err(_) => false,
+        }
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        let (mount, rest) = self.resolve(path).ok()?;
+        mount.storage.local_path(&rest)
+    }
+
+    fn writable(&self, path: &Path) -> bool {
+        match self.resolve_writable(path) {
+            Ok((mount, rest)) => mount.storage.writable(&rest),
+            Err(_) => false,
+        }
+    }
+
+    fn for_role(&self, role: &str) -> Option<Storage> {
+        Some(Arc::new(MountStorage {
+            mounts: self.mounts.clone(),
+            role: Some(role.to_string()),
+        }))
+    }
+
+    async fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+        if !path.as_os_str().is_empty() {
+            let (mount, rest) = self.resolve(path)?;
+            return mount.storage.list(&rest).await;
+        }
+        let mut entries = vec![];
+        for mount in self.visible() {
+            // Skip missing disk instead of failing the whole list
+            if let Ok(stat) = mount.storage.stat(Path::new("")).await {
+                entries.push(Entry {
+                    name: mount.name.clone(),
+                    stat,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<Stat> {
+        if path.as_os_str().is_empty() {
+            return Ok(Stat {
+                is_dir: true,
+                size: 0,
+                modified: 0,
+            });
+        }
+        let (mount, rest) = self.resolve(path)?;
+        mount.storage.stat(&rest).await
+    }
+
+    async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<Reader> {
+        let (mount, rest) = self.resolve(path)?;
+        mount.storage.read(&rest, range).await
+    }
+
+    async fn write(
+        &self,
+        path: &Path,
+        data: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<u64> {
+        let (mount, rest) = self.resolve_writable(path)?;
+        mount.storage.write(&rest, data).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_mount, from) = self.resolve_writable(from)?;
+        let (to_mount, to) = self.resolve_writable(to)?;
+        // Mounts are usually different disks
+        if from_mount.name != to_mount.name {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can't move between mounts",
+            ));
+        }
+        from_mount.storage.rename(&from, &to).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let (from_mount, from) = self.resolve(from)?;
+        let (to_mount, to) = self.resolve_writable(to)?;
+        if from_mount.name == to_mount.name {
+            return from_mount.storage.copy(&from, &to).await;
+        }
+        let mut reader = from_mount.storage.read(&from, None).await?;
+        to_mount.storage.write(&to, &mut reader).await
+    }
+
+    async fn delete(&self, path: &Path) -> io::Result<()> {
+        let (mount, rest) = self.resolve_writable(path)?;
+        mount.storage.delete(&rest).await
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        let (mount, rest) = self.resolve_writable(path)?;
+        mount.storage.mkdir(&rest).await
+    }
+
+    async fn max_size(&self, path: &Path) -> io::Result<Option<u64>> {
+        let (mount, rest) = self.resolve_writable(path)?;
+        mount.storage.max_size(&rest).await
+    }
+
+    async fn written(&self, path: &Path, old_size: u64) -> io::Result<()> {
+        let (mount, rest) = self.resolve_writable(path)?;
+        mount.storage.written(&rest, old_size).await
+    }
+}
+
+#[cfg(test)]
+pub use memory::MemoryStorage;
+
+#[cfg(test)]
+mod memory {
+    use std::collections::BTreeMap;
+    use std::io::{self, Cursor, ErrorKind};
+    use std::ops::Range;
+    use std::path::{Component, Path, PathBuf};
+    use std::sync::Mutex;
+
+    use axum::async_trait;
+    use tokio::io::{AsyncRead, AsyncReadExt};
+
+    use super::{Entry, Reader, Stat, StorageBackend};
+    use crate::user::get_unix_timestamp;
+
+    enum Node {
+        Folder(u64),
+        File(Vec<u8>, u64),
+    }
+
+    impl Node {
+        fn stat(&self) -> Stat {
+            match self {
+                Node::Folder(modified) => Stat {
+                    is_dir: true,
+                    size: 0,
+                    modified: *modified,
+                },
+                Node::File(data, modified) => Stat {
+                    is_dir: false,
+                    size: data.len() as u64,
+                    modified: *modified,
+                },
+            }
+        }
+    }
+
+    /// Files in memory, used by handler tests
+    pub struct MemoryStorage {
+        nodes: Mutex<BTreeMap<PathBuf, Node>>,
+    }
+
+    impl MemoryStorage {
+        pub fn new() -> MemoryStorage {
+            let mut nodes = BTreeMap::new();
+            nodes.insert(PathBuf::new(), Node::Folder(get_unix_timestamp()));
+            MemoryStorage {
+                nodes: Mutex::new(nodes),
+            }
+        }
+    }
+
+    fn error(kind: ErrorKind) -> io::Error {
+        io::Error::from(kind)
+    }
+
+    /// Parent must be an existing folder
+    fn check_parent(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> io::Result<()> {
+        match path.parent().map(|p| nodes.get(p)) {
+            Some(Some(Node::Folder(_))) => Ok(()),
+            _ => Err(error(ErrorKind::NotFound)),
+        }
+    }
+
+    #[async_trait]
+    impl StorageBackend for MemoryStorage {
+        fn contains(&self, path: &Path) -> bool {
+            path.components().all(|c| matches!(c, Component::Normal(_)))
+        }
+
+        async fn list(&self, path: &Path) -> io::Result<Vec<Entry>> {
+            let nodes = self.nodes.lock().unwrap();
+            match nodes.get(path) {
+                Some(Node::Folder(_)) => (),
+                Some(_) => return Err(error(ErrorKind::NotADirectory)),
+                None => return Err(error(ErrorKind::NotFound)),
+            }
+            Ok(nodes
+                .iter()
+                .filter(|(p, _)| p.parent() == Some(path))
+                .filter_map(|(p, node)| {
+                    Some(Entry {
+                        name: p.file_name()?.to_str()?.to_string(),
+                        stat: node.stat(),
+                    })
+                })
+                .collect())
+        }
+
+        async fn stat(&self, path: &Path) -> io::Result<Stat> {
+            let nodes = self.nodes.lock().unwrap();
+            let node = nodes.get(path).ok_or(error(ErrorKind::NotFound))?;
+            Ok(node.stat())
+        }
+
+        async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<Reader> {
+            let nodes = self.nodes.lock().unwrap();
+            let data = match nodes.get(path) {
+                Some(Node::File(data, _)) => data,
+                Some(_) => return Err(error(ErrorKind::IsADirectory)),
+                None => return Err(error(ErrorKind::NotFound)),
+            };
+            let len = data.len() as u64;
+            let range = range.unwrap_or(0..len);
+            let (start, end) = (range.start.min(len), range.end.min(len));
+            let data = data[start as usize..end.max(start) as usize].to_vec();
+            Ok(Box::pin(Cursor::new(data)))
+        }
+
+        async fn write(
+            &self,
+            path: &Path,
+            data: &mut (dyn AsyncRead + Send + Unpin),
+        ) -> io::Result<u64> {
+            let mut buf = vec![];
+            data.read_to_end(&mut buf).await?;
+            let mut nodes = self.nodes.lock().unwrap();
+            check_parent(&nodes, path)?;
+            if let Some(Node::Folder(_)) = nodes.get(path) {
+                return Err(error(ErrorKind::IsADirectory));
+            }
+            let size = buf.len() as u64;
+            nodes.insert(path.into(), Node::File(buf, get_unix_timestamp()));
+            Ok(size)
+        }
+
+        async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            let mut nodes = self.nodes.lock().unwrap();
+            if !nodes.contains_key(from) {
+                return Err(error(ErrorKind::NotFound));
+            }
+            check_parent(&nodes, to)?;
+            if to.starts_with(from) || matches!(nodes.get(to), Some(Node::Folder(_))) {
+                return Err(error(ErrorKind::InvalidInput));
+            }
+            let moved: Vec<PathBuf> = nodes
+                .keys()
+                .filter(|p| p.starts_with(from))
+                .cloned()
+                .collect();
+            for p in moved {
+                let node = nodes.remove(&p).unwrap();
+                let new_path = to.join(p.strip_prefix(from).unwrap());
+                nodes.insert(new_path, node);
+            }
+            Ok(())
+        }
+
+        async fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+            let mut nodes = self.nodes.lock().unwrap();
+            let data = match nodes.get(from) {
+                Some(Node::File(data, _)) => data.clone(),
+                Some(_) => return Err(error(ErrorKind::InvalidInput)),
+                None => return Err(error(ErrorKind::NotFound)),
+            };
+            check_parent(&nodes, to)?;
+            let size = data.len() as u64;
+            nodes.insert(to.into(), Node::File(data, get_unix_timestamp()));
+            Ok(size)
+        }
+
+        async fn delete(&self, path: &Path) -> io::Result<()> {
+            let mut nodes = self.nodes.lock().unwrap();
+            if path.parent().is_none() {
+                return Err(error(ErrorKind::PermissionDenied));
+            }
+            if nodes.keys().any(|p| p.parent() == Some(path)) {
+                return Err(error(ErrorKind::DirectoryNotEmpty));
+            }
+            nodes
+                .remove(path)
+                .map(|_| ())
+                .ok_or(error(ErrorKind::NotFound))
+        }
+
+        async fn mkdir(&self, path: &Path) -> io::Result<()> {
+            let mut nodes = self.nodes.lock().unwrap();
+            check_parent(&nodes, path)?;
+            if nodes.contains_key(path) {
+                return Err(error(ErrorKind::AlreadyExists));
+            }
+            nodes.insert(path.into(), Node::Folder(get_unix_timestamp()));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::fixture::temp_folder;
+
+    #[test]
+    fn test_contains() {
+        let storage = LocalStorage::new("files".into());
+        assert!(storage.contains(Path::new("test_file")));
+        assert!(storage.contains(Path::new("not_exist/not_exist")));
+        assert!(!storage.contains(Path::new("../src")));
+        assert!(!storage.contains(Path::new("/etc/passwd")));
+    }
+
+    #[tokio::test]
+    async fn test_local_write() {
+        use axum::body::Bytes;
+        use futures_util::stream;
+        use tokio_util::io::StreamReader;
+
+        let root = temp_folder();
+        let storage = LocalStorage::new(root.clone());
+        let path = Path::new("a.txt");
+        storage.write(path, &mut &b"old"[..]).await.unwrap();
+        // Body fails in the middle
+        let mut body = StreamReader::new(stream::iter([
+            Ok(Bytes::from("new")),
+            Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+        ]));
+        assert!(storage.write(path, &mut body).await.is_err());
+        assert_eq!(std::fs::read(root.join(path)).unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+        storage.write(path, &mut &b"new"[..]).await.unwrap();
+        assert_eq!(std::fs::read(root.join(path)).unwrap(), b"new");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        storage.mkdir(Path::new("a")).await.unwrap();
+        storage
+            .write(Path::new("a/b.txt"), &mut &b"hello"[..])
+            .await
+            .unwrap();
+        assert!(storage
+            .write(Path::new("c/d"), &mut &b""[..])
+            .await
+            .is_err());
+        let mut content = String::new();
+        storage
+            .read(Path::new("a/b.txt"), Some(1..3))
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, "el");
+        assert!(storage.delete(Path::new("a")).await.is_err());
+        storage
+            .rename(Path::new("a"), Path::new("c"))
+            .await
+            .unwrap();
+        assert_eq!(storage.list(Path::new("c")).await.unwrap()[0].name, "b.txt");
+        assert_eq!(storage.stat(Path::new("c/b.txt")).await.unwrap().size, 5);
+    }
+
+    #[tokio::test]
+    async fn test_mount_storage() {
+        let mount = |name: &str, read_only, roles: &[&str]| MountPoint {
+            name: name.into(),
+            read_only,
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            storage: Arc::new(MemoryStorage::new()),
+        };
+        let storage: Storage = Arc::new(MountStorage::new(vec![
+            mount("media", false, &[]),
+            mount("archive", true, &[]),
+            mount("admin", false, &["admin"]),
+        ]));
+        storage.mkdir(Path::new("media/a")).await.unwrap();
+        assert!(storage.mkdir(Path::new("archive/a")).await.is_err());
+        assert!(storage.mkdir(Path::new("other")).await.is_err());
+        assert!(storage.delete(Path::new("media")).await.is_err());
+        assert!(storage.contains(Path::new("media/a/b")));
+        assert!(!storage.contains(Path::new("other/a")));
+        storage
+            .write(Path::new("media/a/b"), &mut &b"hi"[..])
+            .await
+            .unwrap();
+        assert!(storage
+            .copy(Path::new("media/a/b"), Path::new("admin/b"))
+            .await
+            .is_ok());
+        assert_eq!(storage.list(Path::new("")).await.unwrap().len(), 3);
+        let user = role_storage(&storage, "user");
+        assert_eq!(user.list(Path::new("")).await.unwrap().len(), 2);
+        assert!(!user.contains(Path::new("admin/b")));
+        assert!(user.stat(Path::new("admin/b")).await.is_err());
+    }
+}
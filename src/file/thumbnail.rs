@@ -0,0 +1,244 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use axum::{
+    extract::{Extension, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use image::{
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    DynamicImage, ImageDecoder, ImageReader,
+};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use tokio::{fs, io::AsyncReadExt, sync::broadcast::error::RecvError, sync::Semaphore};
+
+use crate::{
+    event::subscribe,
+    file::{
+        storage::{Stat, Storage, StorageBackend},
+        CheckedPath, FileError,
+    },
+    user::Claim,
+    CONFIG,
+};
+
+/// Requested size is rounded up to one of them, so the cache stays small
+const SIZES: [u32; 4] = [128, 256, 512, 1024];
+const DEFAULT_SIZE: u32 = 256;
+const JPEG_QUALITY: u8 = 80;
+const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff"];
+
+lazy_static! {
+    /// Thumbnails generated at the same time, decoding is heavy on CPU
+    static ref WORKERS: Semaphore = Semaphore::new(CONFIG.thumbnail_workers.max(1));
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailArgs {
+    /// Max width and height
+    size: Option<u32>,
+    /// "jpeg" (default) or "webp"
+    format: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Jpeg,
+    WebP,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::WebP => "webp",
+        }
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            Format::WebP => "image/webp",
+        }
+    }
+}
+
+/// Get resized image, generated on first request then read from cache
+pub async fn get_thumbnail(
+    CheckedPath(path): CheckedPath,
+    Query(args): Query<ThumbnailArgs>,
+    Extension(storage): Extension<Storage>,
+    headers: HeaderMap,
+    _: Claim,
+) -> Result<Response, FileError> {
+    if !is_image(&path) {
+        return Err(FileError::ContentError);
+    }
+    let format = match args.format.as_deref() {
+        None | Some("jpeg") | Some("jpg") => Format::Jpeg,
+        Some("webp") => Format::WebP,
+        Some(_) => return Err(FileError::ContentError),
+    };
+    let size = args.size.unwrap_or(DEFAULT_SIZE);
+    let size = SIZES.into_iter().find(|s| *s >= size).unwrap_or(SIZES[3]);
+    let stat = storage.stat(&path).await?;
+    if stat.is_dir {
+        return Err(FileError::PathError);
+    }
+    let etag = format!(
+        "\"{}-{}-{}.{}\"",
+        stat.modified,
+        stat.size,
+        size,
+        format.extension()
+    );
+    let none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    if none_match == Some(etag.as_str()) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    let cache = cache_path(&path, size, &stat, format);
+    let data = match fs::read(&cache).await {
+        Ok(data) => data,
+        Err(_) => {
+            let data = generate(storage.as_ref(), &path, size, format).await?;
+            if let Err(e) = save(&cache, &stat, &data).await {
+                tracing::warn!("failed to cache thumbnail: {}", e);
+            }
+            data
+        }
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.mime().to_string()),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+            (header::ETAG, etag),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+/// Remove cached thumbnails of changed files, until the server stops
+pub async fn clean_thumbnails() {
+    let mut receiver = subscribe();
+    loop {
+        let event = match receiver.recv().await {
+            Ok(e) => e,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        for path in event.paths() {
+            // Folder of the file, or the folder containing all files inside
+            let _ = fs::remove_dir_all(CONFIG.thumbnail_path.join(path)).await;
+        }
+    }
+}
+
+pub fn is_image(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// Thumbnails of a file are stored in a folder named by its path,
+/// and named by its modified time and size, so changed files aren't matched
+fn cache_path(path: &Path, size: u32, stat: &Stat, format: Format) -> PathBuf {
+    let name = format!(
+        "{}-{}-{}.{}",
+        size,
+        stat.modified,
+        stat.size,
+        format.extension()
+    );
+    CONFIG.thumbnail_path.join(path).join(name)
+}
+
+/// Write thumbnail and remove the ones of old content
+async fn save(cache: &Path, stat: &Stat, data: &[u8]) -> std::io::Result<()> {
+    let (folder, name) = match (cache.parent(), cache.file_name()) {
+        (Some(f), Some(n)) => (f, n.to_string_lossy()),
+        _ => return Ok(()),
+    };
+    fs::create_dir_all(folder).await?;
+    let version = format!("-{}-{}.", stat.modified, stat.size);
+    let mut entries = fs::read_dir(folder).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_name().to_string_lossy().contains(&version) {
+            let _ = fs::remove_file(entry.path()).await;
+        }
+    }
+    // Other requests never read half written file
+    let temp = folder.join(format!(".{}", name));
+    fs::write(&temp, data).await?;
+    fs::rename(temp, cache).await
+}
+
+async fn generate(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    size: u32,
+    format: Format,
+) -> Result<Vec<u8>, FileError> {
+    let _permit = WORKERS
+        .acquire()
+        .await
+        .map_err(|_| FileError::ServerError)?;
+    let mut data = vec![];
+    storage
+        .read(path, None)
+        .await?
+        .read_to_end(&mut data)
+        .await?;
+    tokio::task::spawn_blocking(move || render(&data, size, format))
+        .await
+        .map_err(|_| FileError::ServerError)?
+        .map_err(|_| FileError::ContentError)
+}
+
+/// Resize image to fit in `size` x `size`, photos are rotated by their EXIF orientation
+fn render(data: &[u8], size: u32, format: Format) -> image::ImageResult<Vec<u8>> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    let image = image.thumbnail(size, size);
+    let mut out = vec![];
+    match format {
+        Format::Jpeg => image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?,
+        Format::WebP => image
+            .to_rgba8()
+            .write_with_encoder(WebPEncoder::new_lossless(&mut out))?,
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use image::{ImageFormat, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut png = vec![];
+        RgbImage::new(400, 200)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        for format in [Format::Jpeg, Format::WebP] {
+            let thumbnail = render(&png, 128, format).unwrap();
+            let image = image::load_from_memory(&thumbnail).unwrap();
+            assert_eq!((image.width(), image.height()), (128, 64));
+        }
+        assert!(is_image(Path::new("a/b.JPG")));
+        assert!(!is_image(Path::new("a/b.txt")));
+    }
+}
@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use axum::{extract::Extension, Json};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    event::{subscribe, EventType, FileEvent},
+    file::{
+        metadata::prefix_range,
+        storage::{is_temp_file, Storage, StorageBackend},
+        FileError,
+    },
+    user::{get_unix_timestamp, Claim},
+};
+
+/// Broken shares are relinked to a file of the same identity created within this time,
+/// like a move seen as delete and create
+const RELINK_WINDOW: i64 = 10;
+/// Max files checked in a created folder for files of broken shares
+const MAX_RELINK_FILES: usize = 10000;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrokenShare {
+    id: i64,
+    url: Option<String>,
+    label: Option<String>,
+    /// Where the file was
+    path: String,
+    broken_at: i64,
+}
+
+/// Identity of file that is kept after rename and move, `None` if it isn't known
+#[cfg(unix)]
+pub fn file_identity(storage: &dyn StorageBackend, path: &Path) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::metadata(storage.local_path(path)?).ok()?;
+    // Creation time tells a new file from a removed file whose inode it reuses
+    let created = meta
+        .created()
+        .ok()
+        .and_then(|c| c.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |c| c.as_nanos());
+    Some(format!("{}:{}:{}", meta.dev(), meta.ino(), created))
+}
+
+#[cfg(not(unix))]
+pub fn file_identity(_storage: &dyn StorageBackend, _path: &Path) -> Option<String> {
+    None
+}
+
+/// Check shares at start, then keep paths of shares updated by file events
+/// until the server stops
+pub async fn track_shares(pool: SqlitePool, storage: Storage) {
+    // Subscribe before the check, so files changed during it aren't missed
+    let mut receiver = subscribe();
+    if let Err(e) = check_shares(&pool, storage.as_ref()).await {
+        tracing::warn!("failed to check shares: {}", e);
+    }
+    loop {
+        let result = match receiver.recv().await {
+            Ok(event) => update(&pool, storage.as_ref(), &event).await,
+            // Missed events may be removes, so all shares are checked again
+            Err(RecvError::Lagged(_)) => check_shares(&pool, storage.as_ref()).await,
+            Err(RecvError::Closed) => return,
+        };
+        if let Err(e) = result {
+            tracing::warn!("failed to update shares: {}", e);
+        }
+    }
+}
+
+/// Get shares whose files are removed or replaced, newest first.
+/// Shares are checked again, for changes made when folder isn't watched
+pub async fn get_broken_shares(
+    Extension(db): Extension<SqlitePool>,
+    Extension(storage): Extension<Storage>,
+    _: Claim,
+) -> Result<Json<Vec<BrokenShare>>, FileError> {
+    check_shares(&db, storage.as_ref()).await?;
+    let shares = sqlx::query_as!(
+        BrokenShare,
+        r#"SELECT id AS "id!", url, label, path AS "path!", broken_at AS "broken_at!"
+        FROM share WHERE broken_at IS NOT NULL ORDER BY broken_at DESC, id"#
+    )
+    .fetch_all(&db)
+    .await?;
+    Ok(Json(shares))
+}
+
+/// Record identity of shared file after it's written, writing may replace the file
+pub async fn refresh_identity(
+    db: &SqlitePool,
+    storage: &dyn StorageBackend,
+    path: &Path,
+) -> Result<(), FileError> {
+    let id = match file_identity(storage, path) {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    let p = path.to_str().ok_or(FileError::PathError)?;
+    sqlx::query!(
+        "UPDATE share SET file_id = ? WHERE path = ? AND broken_at IS NULL",
+        id,
+        p
+    )
+    .execute(db)
+    .await?;
+    sqlx::query!(
+        "UPDATE album_item SET file_id = ? WHERE path = ? AND broken_at IS NULL",
+        id,
+        p
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Shares and items of shared albums are linked to files the same way
+#[derive(Clone, Copy)]
+enum Kind {
+    Share,
+    AlbumItem,
+}
+
+/// Share or album item linked to a file
+struct Tracked {
+    kind: Kind,
+    id: i64,
+    path: String,
+    file_id: Option<String>,
+}
+
+/// Shares and album items which aren't broken, at `path` or inside it.
+/// All of them if `path` is `None`
+async fn tracked(db: &SqlitePool, path: Option<&Path>) -> Result<Vec<Tracked>, FileError> {
+    let all = path.is_none();
+    let (p, low, high) = prefix_range(path.unwrap_or(Path::new("")))?;
+    let shares = sqlx::query!(
+        r#"SELECT id AS "id!", path AS "path!", file_id FROM share
+        WHERE path IS NOT NULL AND broken_at IS NULL
+        AND (? OR path = ? OR (path >= ? AND path < ?))"#,
+        all,
+        p,
+        low,
+        high
+    )
+    .fetch_all(db)
+    .await?;
+    let items = sqlx::query!(
+        r#"SELECT rowid AS "id!: i64", path, file_id FROM album_item
+        WHERE broken_at IS NULL AND (? OR path = ? OR (path >= ? AND path < ?))"#,
+        all,
+        p,
+        low,
+        high
+    )
+    .fetch_all(db)
+    .await?;
+    let shares = shares.into_iter().map(|r| Tracked {
+        kind: Kind::Share,
+        id: r.id,
+        path: r.path,
+        file_id: r.file_id,
+    });
+    let items = items.into_iter().map(|r| Tracked {
+        kind: Kind::AlbumItem,
+        id: r.id,
+        path: r.path,
+        file_id: r.file_id,
+    });
+    Ok(shares.chain(items).collect())
+}
+
+/// Flag shares and album items whose files are removed or replaced by other files.
+/// Identities of the ones without it are recorded
+async fn check_shares(db: &SqlitePool, storage: &dyn StorageBackend) -> Result<(), FileError> {
+    for row in tracked(db, None).await? {
+        let path = Path::new(&row.path);
+        // Files in hidden mounts aren't seen
+        if !storage.contains(path) {
+            continue;
+        }
+        if !storage.exists(path).await {
+            flag(db, &row).await?;
+            continue;
+        }
+        match (&row.file_id, file_identity(storage, path)) {
+            (Some(old), Some(new)) if *old != new => flag(db, &row).await?,
+            (None, Some(new)) => match row.kind {
+                Kind::Share => {
+                    sqlx::query!("UPDATE share SET file_id = ? WHERE id = ?", new, row.id)
+                        .execute(db)
+                        .await?;
+                }
+                Kind::AlbumItem => {
+                    sqlx::query!(
+                        "UPDATE album_item SET file_id = ? WHERE rowid = ?",
+                        new,
+                        row.id
+                    )
+                    .execute(db)
+                    .await?;
+                }
+            },
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+async fn update(
+    db: &SqlitePool,
+    storage: &dyn StorageBackend,
+    event: &FileEvent,
+) -> Result<(), FileError> {
+    let path = Path::new(event.path());
+    match (event.event_type(), event.to()) {
+        (EventType::Rename, Some(to)) => rename(db, storage, path, Path::new(to)).await,
+        (EventType::Delete, _) => remove(db, storage, path).await,
+        (EventType::Create, _) => relink(db, storage, path).await,
+        (EventType::Update, _) => refresh_identity(db, storage, path).await,
+        _ => Ok(()),
+    }
+}
+
+/// Shares and album items of renamed file or files in renamed folder follow them
+async fn rename(
+    db: &SqlitePool,
+    storage: &dyn StorageBackend,
+    from: &Path,
+    to: &Path,
+) -> Result<(), FileError> {
+    if from == to {
+        return Ok(());
+    }
+    // File saved by renaming a temporary file beside it keeps its shares
+    if is_temp_file(from) && from.parent() == to.parent() {
+        return refresh_identity(db, storage, to).await;
+    }
+    for row in tracked(db, Some(to)).await? {
+        // Files replaced by the renamed one aren't shared anymore
+        let id = file_identity(storage, Path::new(&row.path));
+        if row.file_id.is_none() || id != row.file_id {
+            flag(db, &row).await?;
+        }
+    }
+    let t = to.to_str().ok_or(FileError::PathError)?;
+    let (f, low, high) = prefix_range(from)?;
+    sqlx::query!(
+        "UPDATE share SET path = ? || substr(path, length(?) + 1)
+        WHERE broken_at IS NULL AND (path = ? OR (path >= ? AND path < ?))",
+        t,
+        f,
+        f,
+        low,
+        high
+    )
+    .execute(db)
+    .await?;
+    // Broken items of replaced files are replaced in their albums
+    sqlx::query!(
+        "UPDATE OR REPLACE album_item SET path = ? || substr(path, length(?) + 1)
+        WHERE broken_at IS NULL AND (path = ? OR (path >= ? AND path < ?))",
+        t,
+        f,
+        f,
+        low,
+        high
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Flag shares and album items of removed file or files in removed folder.
+/// A new file at the same path isn't shared by them
+async fn remove(
+    db: &SqlitePool,
+    storage: &dyn StorageBackend,
+    path: &Path,
+) -> Result<(), FileError> {
+    for row in tracked(db, Some(path)).await? {
+        let path = Path::new(&row.path);
+        // It may be created again before the event is handled
+        let replaced = match (&row.file_id, storage.exists(path).await) {
+            (_, false) => true,
+            (Some(old), true) => file_identity(storage, path).is_some_and(|new| new != *old),
+            (None, true) => false,
+        };
+        if replaced {
+            flag(db, &row).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Relink shares and album items broken just now to their files moved into created `path`,
+/// moves out of a watched folder are seen as delete and create
+async fn relink(
+    db: &SqlitePool,
+    storage: &dyn StorageBackend,
+    path: &Path,
+) -> Result<(), FileError> {
+    let since = get_unix_timestamp() as i64 - RELINK_WINDOW;
+    let shares = sqlx::query!(
+        r#"SELECT id AS "id!", file_id AS "file_id!" FROM share
+        WHERE file_id IS NOT NULL AND broken_at >= ?"#,
+        since
+    )
+    .fetch_all(db)
+    .await?;
+    let items = sqlx::query!(
+        r#"SELECT rowid AS "id!: i64", file_id AS "file_id!" FROM album_item
+        WHERE file_id IS NOT NULL AND broken_at >= ?"#,
+        since
+    )
+    .fetch_all(db)
+    .await?;
+    let mut broken: HashMap<String, Vec<(Kind, i64)>> = HashMap::new();
+    for row in shares {
+        broken
+            .entry(row.file_id)
+            .or_default()
+            .push((Kind::Share, row.id));
+    }
+    for row in items {
+        broken
+            .entry(row.file_id)
+            .or_default()
+            .push((Kind::AlbumItem, row.id));
+    }
+    let mut pending = vec![path.to_path_buf()];
+    let mut checked = 0;
+    while let Some(p) = pending.pop() {
+        if broken.is_empty() || checked >= MAX_RELINK_FILES {
+            break;
+        }
+        checked += 1;
+        if let Some(ids) = file_identity(storage, &p).and_then(|id| broken.remove(&id)) {
+            let s = p.to_str().ok_or(FileError::PathError)?;
+            for (kind, id) in ids {
+                match kind {
+                    Kind::Share => {
+                        sqlx::query!(
+                            "UPDATE share SET path = ?, broken_at = NULL WHERE id = ?",
+                            s,
+                            id
+                        )
+                        .execute(db)
+                        .await?;
+                        tracing::info!("share {} is relinked to {}", id, s);
+                    }
+                    // Album which has the file already keeps its item
+                    Kind::AlbumItem => {
+                        sqlx::query!(
+                            "UPDATE OR IGNORE album_item SET path = ?, broken_at = NULL
+                            WHERE rowid = ?",
+                            s,
+                            id
+                        )
+                        .execute(db)
+                        .await?;
+                    }
+                }
+            }
+        }
+        if storage.is_dir(&p).await {
+            let entries = storage.list(&p).await.unwrap_or_default();
+            pending.extend(entries.into_iter().map(|e| p.join(e.name)));
+        }
+    }
+    Ok(())
+}
+
+async fn flag(db: &SqlitePool, row: &Tracked) -> Result<(), FileError> {
+    let now = get_unix_timestamp() as i64;
+    match row.kind {
+        Kind::Share => {
+            sqlx::query!(
+                "UPDATE share SET broken_at = ? WHERE id = ? AND broken_at IS NULL",
+                now,
+                row.id
+            )
+            .execute(db)
+            .await?;
+            tracing::info!(
+                "share {} is broken, its file is removed or replaced",
+                row.id
+            );
+        }
+        Kind::AlbumItem => {
+            sqlx::query!(
+                "UPDATE album_item SET broken_at = ? WHERE rowid = ? AND broken_at IS NULL",
+                now,
+                row.id
+            )
+            .execute(db)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::file::{
+        album::album_paths,
+        fixture::{claim, pool, temp_folder},
+        remove_path, rename_path,
+        storage::LocalStorage,
+    };
+
+    #[tokio::test]
+    async fn test_track_shares() {
+        let pool = pool().await;
+        let root = temp_folder();
+        std::fs::create_dir(root.join("a")).unwrap();
+        let storage: Storage = Arc::new(LocalStorage::new(root.clone()));
+        for (id, path) in [(1, "a/b.txt"), (2, "c.txt")] {
+            storage
+                .write(Path::new(path), &mut &b"x"[..])
+                .await
+                .unwrap();
+            let file_id = file_identity(storage.as_ref(), Path::new(path));
+            sqlx::query!(
+                "INSERT INTO share (id, path, url, file_id) VALUES (?, ?, ?, ?)",
+                id,
+                path,
+                path,
+                file_id
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        // Album shared with the file in it
+        let file_id = file_identity(storage.as_ref(), Path::new("a/b.txt"));
+        sqlx::query!(
+            "INSERT INTO album (id, username, name, created_at) VALUES (1, 'test', 'a', 0);
+            INSERT INTO share (id, album_id, url) VALUES (3, 1, 'album');
+            INSERT INTO album_item (album_id, path, position, file_id)
+            VALUES (1, 'a/b.txt', 0, ?)",
+            file_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let album = || album_paths(&pool, 1);
+        let share = |id: i64| {
+            let pool = pool.clone();
+            async move {
+                let r = sqlx::query!("SELECT path, broken_at FROM share WHERE id = ?", id)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+                (r.path.unwrap(), r.broken_at.is_some())
+            }
+        };
+        let handle = |event: Option<FileEvent>| {
+            let (pool, storage) = (pool.clone(), storage.clone());
+            async move {
+                update(&pool, storage.as_ref(), &event.unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+        rename_path(storage.as_ref(), Path::new("a"), Path::new("d"))
+            .await
+            .unwrap();
+        handle(FileEvent::rename(Path::new("a"), Path::new("d"))).await;
+        assert_eq!(share(1).await, ("d/b.txt".into(), false));
+        assert_eq!(album().await.unwrap(), [Path::new("d/b.txt")]);
+        // Saved by renaming temporary file of the server or editor
+        for temp in ["d/b.txt.1a2b3c4d.upload", "d/.b.txt.swp"] {
+            std::fs::write(root.join(temp), "saved").unwrap();
+            std::fs::rename(root.join(temp), root.join("d/b.txt")).unwrap();
+            handle(FileEvent::rename(Path::new(temp), Path::new("d/b.txt"))).await;
+            assert_eq!(share(1).await, ("d/b.txt".into(), false));
+        }
+        // A new file at the old path isn't shared
+        remove_path(storage.as_ref(), Path::new("c.txt"))
+            .await
+            .unwrap();
+        handle(FileEvent::new(EventType::Delete, Path::new("c.txt"))).await;
+        storage
+            .write(Path::new("c.txt"), &mut &b"y"[..])
+            .await
+            .unwrap();
+        handle(FileEvent::new(EventType::Create, Path::new("c.txt"))).await;
+        assert_eq!(share(2).await, ("c.txt".into(), true));
+        // Move seen as delete and create
+        std::fs::rename(root.join("d/b.txt"), root.join("e.txt")).unwrap();
+        handle(FileEvent::new(EventType::Delete, Path::new("d/b.txt"))).await;
+        assert_eq!(share(1).await, ("d/b.txt".into(), true));
+        handle(FileEvent::new(EventType::Create, Path::new("e.txt"))).await;
+        assert_eq!(share(1).await, ("e.txt".into(), false));
+        assert_eq!(album().await.unwrap(), [Path::new("e.txt")]);
+        // Replaced when events are missed
+        std::fs::remove_file(root.join("e.txt")).unwrap();
+        std::fs::write(root.join("f.txt"), "keep the inode used").unwrap();
+        std::fs::write(root.join("e.txt"), "z").unwrap();
+        let Json(broken) = get_broken_shares(Extension(pool.clone()), Extension(storage), claim())
+            .await
+            .unwrap();
+        assert_eq!(broken.len(), 2);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
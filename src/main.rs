@@ -1,8 +1,8 @@
 use std::{env, fs::write, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use axum::{
-    middleware::from_extractor,
-    routing::{get, get_service, patch, post},
+    middleware,
+    routing::{any, get, patch, post},
     Extension, Router,
 };
 use lazy_static::lazy_static;
@@ -11,37 +11,59 @@ use tokio::signal;
 use tower_http::{
     compression::CompressionLayer,
     cors::{Any, CorsLayer},
-    services::ServeDir,
     trace::TraceLayer,
 };
 
 mod config;
 mod dist;
+mod event;
 mod file;
+mod throttle;
 mod user;
 
 use config::Config;
 use dist::static_handler;
+use event::{events, watch_folder};
 use file::{
-    file::{delete_file, rename_file, search_file, upload_file},
+    access::get_share_access,
+    album::{add_album, delete_album, get_album, get_albums, update_album},
+    checksum::{get_mismatches, scrub_loop, HashStorage},
+    dav::{dav, DAV_PREFIX},
+    dedupe::{dedupe, get_duplicates},
+    du::{get_disk_usage, refresh_loop},
+    file::{delete_file, download_file, rename_file, search_file, upload_file, write_file},
     folder::{create_folder, get_folder},
-    share::{add_share_file, delete_share, get_share_file, get_share_index},
+    gallery::{get_gallery, get_on_this_day},
+    metadata::index_media,
+    quota::{self, get_usage, scan_loop},
+    render::render_file,
+    s3::{add_s3_key, clean_staging, delete_s3_key, get_s3_keys, s3},
+    sftp::{add_ssh_key, delete_ssh_key, get_ssh_keys, serve_sftp},
+    share::{
+        add_share_file, add_share_session, delete_share, get_share_file, get_share_index,
+        create_share_folder, delete_share_file, hash_share_passwords, purge_shares,
+        rename_share_file, render_share_file, update_share, upload_share_file,
+    },
+    storage::{self, Storage},
+    thumbnail::{clean_thumbnails, get_thumbnail},
+    tracking::{get_broken_shares, track_shares},
+    user_storage,
 };
-use user::{authorize, register, reset_password, Claim};
+use user::{authorize, register, reset_password};
 
 lazy_static! {
     pub static ref CONFIG: Arc<Config> = Arc::new(Config::from_env());
 }
 
-/// Perform migration if database is not exist
+/// Create database if it is not exist, then perform migration
 pub async fn migrate(db_url: &str) {
     if !PathBuf::from(db_url).exists() {
         write(db_url, "").unwrap();
-        let pool = SqlitePool::connect(&format!("sqlite://{}", db_url))
-            .await
-            .unwrap();
-        migrate!().run(&pool).await.unwrap();
     }
+    let pool = SqlitePool::connect(&format!("sqlite://{}", db_url))
+        .await
+        .unwrap();
+    migrate!().run(&pool).await.unwrap();
 }
 
 /// Shutdown signal handler, stop the loop
@@ -71,10 +93,6 @@ async fn shutdown_signal() {
     println!("signal received, starting graceful shutdown");
 }
 
-async fn handle_file_error(_: std::io::Error) {
-    ()
-}
-
 #[tokio::main]
 async fn main() {
     migrate(&CONFIG.database_path).await;
@@ -86,6 +104,18 @@ async fn main() {
         env::set_var("RUST_LOG", "file-station=debug,tower_http=debug")
     }
     tracing_subscriber::fmt::init();
+    if let Err(e) = hash_share_passwords(&pool).await {
+        tracing::warn!("failed to hash share passwords: {}", e);
+    }
+    // Keep the watcher alive until server stops
+    let _watcher = if CONFIG.watch_folder {
+        watch_folder()
+            .map_err(|e| tracing::warn!("failed to watch folder: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    let storage: Storage = Arc::new(HashStorage::new(storage::from_config(), pool.clone()));
     let app = Router::new()
         .nest(
             "/api/v1",
@@ -93,25 +123,58 @@ async fn main() {
                 .route("/auth", post(authorize))
                 .route("/users", post(register))
                 .route("/user", patch(reset_password))
-                .nest_service(
-                    "/file/",
-                    get_service(ServeDir::new(CONFIG.folder_path.clone()))
-                        .handle_error(handle_file_error)
-                        .layer(from_extractor::<Claim>())
+                .route(
+                    "/file/*path",
+                    get(download_file)
                         .delete(delete_file)
                         .patch(rename_file)
-                        .post(upload_file),
+                        .post(upload_file)
+                        .put(write_file),
                 )
+                .route("/file/", post(upload_file))
                 .route("/files/*path", get(get_folder).post(create_folder))
                 .route("/files/", get(get_folder).post(create_folder))
                 .route("/search", get(search_file))
+                .route("/thumbnail/*path", get(get_thumbnail))
+                .route("/render/*path", get(render_file))
+                .route("/gallery", get(get_gallery))
+                .route("/gallery/on-this-day", get(get_on_this_day))
+                .route(
+                    "/albums",
+                    get(get_albums).post(add_album).delete(delete_album),
+                )
+                .route("/album", get(get_album).patch(update_album))
                 .route(
                     "/share",
                     get(get_share_file)
                         .post(add_share_file)
+                        .patch(update_share)
                         .delete(delete_share),
                 )
-                .route("/shares", get(get_share_index)),
+                .route("/share/render", get(render_share_file))
+                .route("/share/session", post(add_share_session))
+                .route("/share/upload", post(upload_share_file))
+                .route("/share/folder", post(create_share_folder))
+                .route(
+                    "/share/file",
+                    patch(rename_share_file).delete(delete_share_file),
+                )
+                .route("/shares", get(get_share_index))
+                .route("/shares/broken", get(get_broken_shares))
+                .route("/shares/access", get(get_share_access))
+                .route("/events", get(events))
+                .route("/usage", get(get_usage))
+                .route("/disk-usage", get(get_disk_usage))
+                .route("/integrity", get(get_mismatches))
+                .route("/duplicates", get(get_duplicates).post(dedupe))
+                .route(
+                    "/s3/keys",
+                    get(get_s3_keys).post(add_s3_key).delete(delete_s3_key),
+                )
+                .route(
+                    "/ssh/keys",
+                    get(get_ssh_keys).post(add_ssh_key).delete(delete_ssh_key),
+                ),
         )
         .route("/assets/", get(static_handler))
         .fallback(static_handler)
@@ -121,15 +184,52 @@ async fn main() {
                 .allow_headers(Any)
                 .allow_origin(Any),
         )
+        // CORS layer answers all OPTIONS requests, so WebDAV is added after it
+        .nest_service(DAV_PREFIX, any(dav))
         .layer(CompressionLayer::new().gzip(true).deflate(true).br(true))
-        .layer(Extension(pool))
+        .layer(middleware::from_fn(user_storage))
+        .layer(Extension(pool.clone()))
+        .layer(Extension(storage.clone()))
         .layer(TraceLayer::new_for_http().on_request(()));
+    // Usage of quota folders is recounted in background
+    if quota::enabled() {
+        tokio::spawn(scan_loop(pool.clone(), storage.clone()));
+    }
+    tokio::spawn(refresh_loop(storage.clone()));
+    tokio::spawn(clean_thumbnails());
+    tokio::spawn(index_media(pool.clone(), storage.clone()));
+    tokio::spawn(purge_shares(pool.clone()));
+    tokio::spawn(track_shares(pool.clone(), storage.clone()));
+    if CONFIG.scrub_interval > 0 {
+        tokio::spawn(scrub_loop(pool.clone(), storage.clone()));
+    }
+    // SFTP server listens on its own port
+    if let Some(sftp_listen) = &CONFIG.sftp_listen {
+        let addr: SocketAddr = sftp_listen.parse().unwrap();
+        let (pool, storage) = (pool.clone(), storage.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve_sftp(pool, storage, addr).await {
+                tracing::error!("SFTP server stopped: {}", e);
+            }
+        });
+    }
+    // S3-compatible API listens on its own port
+    if let Some(s3_listen) = &CONFIG.s3_listen {
+        tokio::spawn(clean_staging(pool.clone()));
+        let s3_app = Router::new()
+            .fallback(s3)
+            .layer(Extension(pool))
+            .layer(Extension(storage))
+            .layer(TraceLayer::new_for_http().on_request(()));
+        let addr: SocketAddr = s3_listen.parse().unwrap();
+        tokio::spawn(axum::Server::bind(&addr).serve(s3_app.into_make_service()));
+    }
     let addr: SocketAddr = env::var("FS_LISTEN")
         .unwrap_or("127.0.0.1:5000".to_string())
         .parse()
         .unwrap();
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+/// Failed attempts allowed without delay
+const FREE_ATTEMPTS: u32 = 3;
+/// Failed attempts before lockout, delay doubles on each attempt before it
+const LOCKOUT_ATTEMPTS: u32 = 10;
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Failures are forgotten after this time without new failure
+const FORGET: Duration = Duration::from_secs(60 * 60);
+/// Old records are removed when there are more records than this
+const MAX_RECORDS: usize = 10000;
+
+lazy_static! {
+    static ref FAILURES: Mutex<Throttle> = Mutex::new(Throttle::default());
+}
+
+/// Key of attempts from an IP
+pub fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Key of attempts to login as a user
+pub fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+/// Key of attempts to open a share
+pub fn share_key(url: &str) -> String {
+    format!("share:{}", url)
+}
+
+/// Check none of `keys` is blocked, otherwise return seconds to wait
+pub fn check(keys: &[String]) -> Result<(), u64> {
+    FAILURES.lock().unwrap().check(keys, Instant::now())
+}
+
+/// Record a failed attempt of `keys`
+pub fn fail(keys: &[String]) {
+    FAILURES.lock().unwrap().fail(keys, Instant::now())
+}
+
+/// Forget failed attempts of `key` after a successful attempt
+pub fn succeed(key: &str) {
+    FAILURES.lock().unwrap().records.remove(key);
+}
+
+struct Record {
+    count: u32,
+    last: Instant,
+    /// Attempts are rejected until then
+    until: Instant,
+}
+
+#[derive(Default)]
+struct Throttle {
+    records: HashMap<String, Record>,
+}
+
+impl Throttle {
+    fn check(&self, keys: &[String], now: Instant) -> Result<(), u64> {
+        let wait = keys
+            .iter()
+            .filter_map(|k| self.records.get(k))
+            .filter(|r| r.until > now)
+            .map(|r| r.until - now)
+            .max();
+        match wait {
+            // Round up, so clients retrying after it are accepted
+            Some(wait) => Err(wait.as_secs() + 1),
+            None => Ok(()),
+        }
+    }
+
+    fn fail(&mut self, keys: &[String], now: Instant) {
+        if self.records.len() > MAX_RECORDS {
+            self.records
+                .retain(|_, r| now.duration_since(r.last) < FORGET || r.until > now);
+        }
+        for key in keys {
+            let record = self.records.entry(key.clone()).or_insert(Record {
+                count: 0,
+                last: now,
+                until: now,
+            });
+            if now.duration_since(record.last) >= FORGET && record.until <= now {
+                record.count = 0;
+            }
+            record.count += 1;
+            record.last = now;
+            let delay = match record.count {
+                c if c <= FREE_ATTEMPTS => Duration::ZERO,
+                c if c < LOCKOUT_ATTEMPTS => Duration::from_secs(1 << (c - FREE_ATTEMPTS)),
+                _ => LOCKOUT,
+            };
+            record.until = now + delay;
+            if record.count == LOCKOUT_ATTEMPTS {
+                tracing::warn!(
+                    "audit: {} locked out for {} seconds after {} failed attempts",
+                    key,
+                    LOCKOUT.as_secs(),
+                    record.count
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = Throttle::default();
+        let keys = [ip_key("127.0.0.1".parse().unwrap()), user_key("a")];
+        let now = Instant::now();
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(throttle.check(&keys, now).is_ok());
+            throttle.fail(&keys, now);
+        }
+        assert!(throttle.check(&keys, now).is_ok());
+        throttle.fail(&keys, now);
+        assert_eq!(throttle.check(&keys, now), Err(3));
+        // Other keys aren't affected
+        assert!(throttle.check(&[user_key("b")], now).is_ok());
+        let now = now + Duration::from_secs(2);
+        assert!(throttle.check(&keys, now).is_ok());
+        for _ in FREE_ATTEMPTS + 1..LOCKOUT_ATTEMPTS {
+            throttle.fail(&keys, now);
+        }
+        assert_eq!(throttle.check(&keys, now), Err(LOCKOUT.as_secs() + 1));
+        let now = now + LOCKOUT + FORGET;
+        assert!(throttle.check(&keys, now).is_ok());
+        // Failures are forgotten
+        throttle.fail(&keys, now);
+        assert!(throttle.check(&keys, now).is_ok());
+    }
+}
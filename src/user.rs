@@ -1,3 +1,4 @@
+use std::net::{IpAddr, SocketAddr};
 use std::time::SystemTime;
 
 use argon2::{
@@ -6,7 +7,7 @@ use argon2::{
 };
 use axum::{
     async_trait,
-    extract::{Extension, FromRequestParts, TypedHeader},
+    extract::{ConnectInfo, Extension, FromRequestParts, TypedHeader},
     headers::Cookie,
     headers::{authorization::Bearer, Authorization},
     http::{header, request::Parts, StatusCode},
@@ -15,11 +16,11 @@ use axum::{
 };
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use lazy_static::lazy_static;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
 use sqlx::{FromRow, SqlitePool};
 
-use crate::CONFIG;
+use crate::{throttle, CONFIG};
 
 lazy_static! {
     static ref KEY: String = (0..32).map(|_| rand::random::<char>()).collect();
@@ -50,7 +51,10 @@ pub struct ResetPassword {
 #[derive(Deserialize, Serialize)]
 pub struct Claim {
     sub: String,
-    username: String,
+    pub username: String,
+    /// Role of user when the token is created
+    #[serde(default)]
+    pub role: String,
     exp: u64,
 }
 
@@ -61,9 +65,11 @@ pub enum AuthError {
     TokenCreation,
     InvalidToken,
     DatabaseError,
+    /// Seconds to wait before next attempt
+    TooManyAttempts(u64),
 }
 
-fn get_unix_timestamp() -> u64 {
+pub fn get_unix_timestamp() -> u64 {
     match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
         Ok(n) => n.as_secs(),
         Err(_) => 0,
@@ -71,7 +77,7 @@ fn get_unix_timestamp() -> u64 {
 }
 
 /// Check password and hash
-fn check_hash(password: &String, hash: &String) -> bool {
+pub fn check_hash(password: &String, hash: &String) -> bool {
     let hash_db = match PasswordHash::new(&hash) {
         Ok(h) => h,
         _ => return false,
@@ -83,7 +89,7 @@ fn check_hash(password: &String, hash: &String) -> bool {
 }
 
 /// Generate hash
-fn gen_hash(password: &String) -> Option<String> {
+pub fn gen_hash(password: &String) -> Option<String> {
     Some(
         Argon2::default()
             .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
@@ -92,34 +98,98 @@ fn gen_hash(password: &String) -> Option<String> {
     )
 }
 
+/// Sign claims with the same key as login tokens
+pub fn sign_token<T: Serialize>(claims: &T) -> Option<String> {
+    encode(&DEFAULT_HEADER, claims, &ENCRYPT_KEY).ok()
+}
+
+/// Decode token signed by `sign_token`, `None` if it's invalid or expired
+pub fn verify_token<T: DeserializeOwned>(token: &str) -> Option<T> {
+    decode::<T>(token, &DECRYPT_KEY, &VALIDATION)
+        .ok()
+        .map(|t| t.claims)
+}
+
+/// Check `username` and `password` with the hash in database, return role of user.
+/// Failed attempts of a user are throttled
+pub async fn verify_user(
+    pool: &SqlitePool,
+    username: &str,
+    password: &str,
+) -> Result<String, AuthError> {
+    let key = throttle::user_key(username);
+    throttle::check(std::slice::from_ref(&key)).map_err(AuthError::TooManyAttempts)?;
+    // Get password hash in database
+    let result = sqlx::query!(
+        "SELECT password, role FROM user WHERE username = ?",
+        username
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|_| AuthError::DatabaseError);
+    // Verify password hash
+    match result {
+        Ok(r) if r.password.as_ref().is_some_and(|p| check_hash(&password.to_string(), p)) => {
+            throttle::succeed(&key);
+            Ok(r.role)
+        }
+        Ok(_) => {
+            throttle::fail(&[key]);
+            Err(AuthError::WrongCredentials)
+        }
+        Err(e) => {
+            throttle::fail(&[key]);
+            Err(e)
+        }
+    }
+}
+
+/// Check password login like `verify_user`.
+/// Failed attempts from `ip` are throttled too, whichever user they try
+pub async fn verify_login(
+    pool: &SqlitePool,
+    ip: IpAddr,
+    username: &str,
+    password: &str,
+) -> Result<String, AuthError> {
+    let ip = [throttle::ip_key(ip)];
+    throttle::check(&ip).map_err(AuthError::TooManyAttempts)?;
+    let result = verify_user(pool, username, password).await;
+    if let Err(e) = &result {
+        if !matches!(e, AuthError::TooManyAttempts(_)) {
+            throttle::fail(&ip);
+        }
+    }
+    result
+}
+
+/// Get role of user, for the logins without password
+pub async fn get_role(pool: &SqlitePool, username: &str) -> Result<String, AuthError> {
+    let result = sqlx::query!("SELECT role FROM user WHERE username = ?", username)
+        .fetch_one(pool)
+        .await
+        .map_err(|_| AuthError::DatabaseError)?;
+    Ok(result.role)
+}
+
 /// Login authorization
 pub async fn authorize(
     Extension(pool): Extension<SqlitePool>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Json(payload): Json<QueryUser>,
 ) -> Result<Response, AuthError> {
     // Check if the user sent the credentials
     if payload.username.is_empty() || payload.password.is_empty() {
         return Err(AuthError::MissingCredentials);
     }
-    // Get password hash in database
-    let result = sqlx::query!(
-        "SELECT password FROM user WHERE username = ?",
-        payload.username
-    )
-    .fetch_one(&pool)
-    .await
-    .map_err(|_| AuthError::DatabaseError)?;
-    // Verify password hash
-    match result.password {
-        Some(p) if check_hash(&payload.password, &p) => (),
-        _ => return Err(AuthError::WrongCredentials),
-    }
+    let role = verify_login(&pool, addr.ip(), &payload.username, &payload.password).await?;
     let expire_age = 60 * 60 * 24; // Token/Cookies expire age
 
     // Create the authorization token
     let claims = Claim {
         sub: "file".to_owned(),
         username: payload.username,
+        role,
         exp: expire_age + get_unix_timestamp(),
     };
     let token =
@@ -188,12 +258,23 @@ pub async fn reset_password(
 
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
+        if let AuthError::TooManyAttempts(wait) = self {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, wait.to_string())],
+                Json(json!({
+                    "error": "Too many attempts",
+                })),
+            )
+                .into_response();
+        }
         let (status, error_message) = match self {
             AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "Wrong credentials"),
             AuthError::MissingCredentials => (StatusCode::BAD_REQUEST, "Missing credentials"),
             AuthError::TokenCreation => (StatusCode::INTERNAL_SERVER_ERROR, "Token creation error"),
             AuthError::InvalidToken => (StatusCode::BAD_REQUEST, "Invalid token"),
             AuthError::DatabaseError => (StatusCode::INTERNAL_SERVER_ERROR, "Database query error"),
+            AuthError::TooManyAttempts(_) => (StatusCode::TOO_MANY_REQUESTS, "Too many attempts"),
         };
         let body = Json(json!({
             "error": error_message,
@@ -241,6 +322,7 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::file::fixture::pool;
 
     #[test]
     fn test_hash() {
@@ -249,4 +331,16 @@ mod test {
         let password_hash = Argon2::default().hash_password(password, &salt);
         assert!(password_hash.is_ok(), "{:#?}", password_hash);
     }
+
+    #[tokio::test]
+    async fn test_login_throttle() {
+        let pool = pool().await;
+        let ip = IpAddr::from(rand::random::<[u8; 16]>());
+        // Each user is tried once, but the IP is throttled
+        for username in ["a", "b", "c", "d"] {
+            assert!(verify_login(&pool, ip, username, "x").await.is_err());
+        }
+        let result = verify_login(&pool, ip, "e", "x").await;
+        assert!(matches!(result, Err(AuthError::TooManyAttempts(_))));
+    }
 }